@@ -0,0 +1,217 @@
+use colored::Colorize;
+
+use crate::uzpfile::{UzpLayout, UzpShapeEncoding};
+use crate::uzpgen::UzpWriteOptions;
+
+/* AUGMENT COST MODELS
+ *
+ * Augmenting dimensionality finds groups of pieces of a metapattern repeated n times along a stride. Promoting a group
+ * makes every piece a single origin of a new higher order shape, instead of n origins of the shape of the metapattern.
+ * Cost models tell what a group costs either way, and groups are only promoted if that lowers the cost:
+ *
+ *   bytes    Bytes written by UZPGen::write_uzp for the origins of the group and the definition of the new shape.
+ *            Values take the same bytes either way
+ *   spmv     Estimated words loaded by the SpMV walk of uzpspmv: every origin, the axis walk of every loop of
+ *            vertex_rec shapes (or the points of the rest) and the value, x and y of every point. The new shape is
+ *            loaded once
+ *
+ * Single nonzeros are not origins but uninc nonzeros, taken as stored in CSR: a column index each, in bytes or words.
+ * COO takes two, and the row pointers are there either way.
+ *
+ * Shapes of metapatterns left without origins are not written, but that is not counted, so savings are a lower bound.
+ */
+
+/// Origin shape, row, col and data offset
+const ORIGIN_WORDS: u64 = 4;
+/// Length, start and step of an axis
+const AXIS_WALK_WORDS: u64 = 5;
+/// Value, x and y
+const POINT_WORDS: u64 = 3;
+/// Row and col of a point of a shape walked as a list of points
+const SHAPE_POINT_WORDS: u64 = 2;
+/// Col of an uninc nonzero
+const UNINC_INDEX_WORDS: u64 = 1;
+
+/// A group of pieces of the same metapattern, repeated along the same stride, that augmenting dimensionality may promote
+/// to a higher order shape
+#[derive(Clone, Debug)]
+pub struct AugmentPromotion {
+    /// Id of the higher order metapattern
+    pub id: i32,
+    /// Id of the metapattern of the pieces, -1 for single nonzeros
+    pub sub_id: i32,
+    /// Repetitions of the pieces and their stride, (n,i,j)
+    pub repetition: (i32, i32, i32),
+    /// Length of every axis of the pieces, outermost first. Empty for single nonzeros
+    pub sub_lengths: Vec<usize>,
+    /// Number of pieces, every one of them an origin of the higher order shape once promoted
+    pub pieces: usize,
+}
+
+impl AugmentPromotion {
+    /// Whether the pieces are single nonzeros, promoted to a lattice shape of their own
+    fn of_single_nonzeros(&self) -> bool {
+        self.sub_lengths.is_empty()
+    }
+
+    /// Dimensionality of the higher order shape
+    pub fn dim(&self) -> usize {
+        self.sub_lengths.len() + 1
+    }
+
+    /// Length of every axis of the higher order shape, outermost first
+    fn lengths(&self) -> Vec<usize> {
+        std::iter::once(self.repetition.0 as usize).chain(self.sub_lengths.iter().copied()).collect()
+    }
+}
+
+/// Cost of the pieces of a promotion, in the units of a cost model
+#[derive(Clone, Copy, Debug)]
+pub struct AugmentCost {
+    /// As origins of the shape of their metapattern
+    pub kept: u64,
+    /// As origins of the higher order shape, along with its definition
+    pub promoted: u64,
+}
+
+impl AugmentCost {
+    pub fn saving(&self) -> i64 {
+        self.kept as i64 - self.promoted as i64
+    }
+}
+
+/// Decides which groups of pieces are promoted to higher order shapes when augmenting dimensionality
+pub trait AugmentCostModel {
+    fn cost(&self, promotion: &AugmentPromotion) -> AugmentCost;
+
+    /// Promotions are only made if they lower the cost
+    fn promotes(&self, promotion: &AugmentPromotion) -> bool {
+        self.cost(promotion).saving() > 0
+    }
+}
+
+/// Bytes written by `UZPGen::write_uzp`
+#[derive(Clone, Copy, Debug)]
+pub struct UzpBytesCostModel {
+    pub layout: UzpLayout,
+    pub shape_encoding: UzpShapeEncoding,
+    pub hierarchical_shapes: bool,
+}
+
+impl UzpBytesCostModel {
+    /// Bytes of a UZP file of a matrix of `nrows` x `ncols` with `nnz` stored nonzeros, written with `options`. Files
+    /// with too many shapes for narrow layouts are written wide, which is not known beforehand
+    pub fn new(options: &UzpWriteOptions, nrows: usize, ncols: usize, nnz: usize) -> Self {
+        let max_count = nnz.max(nrows + 1).max(ncols + 1);
+        UzpBytesCostModel {
+            layout: UzpLayout::for_write(options.legacy_layout, options.wide_layout, max_count, 0),
+            shape_encoding: options.shape_encoding,
+            hierarchical_shapes: options.hierarchical_shapes,
+        }
+    }
+
+    /// Shape id, row, col and data offset
+    fn origin_bytes(&self) -> u64 {
+        self.layout.id_bytes() + 3 * self.layout.count_bytes()
+    }
+
+    /// Bytes of the definition of the higher order shape of `promotion`
+    pub fn shape_bytes(&self, promotion: &AugmentPromotion) -> u64 {
+        // Ids of the shape and of its sub-pattern shape, dimension of the repetition, min point, max point, stride and lattice.
+        // Lattices of single nonzeros have no sub-pattern, so they are written as any other shape
+        if self.hierarchical_shapes && !promotion.of_single_nonzeros() {
+            return 2 * self.layout.id_bytes() + 2 + 3 * 4 + 2 * 4;
+        }
+
+        let dim = promotion.dim() as u64;
+        let encoding_bytes = match self.shape_encoding {
+            UzpShapeEncoding::VertexRec => 4 * dim,
+            UzpShapeEncoding::VertexGen => {
+                let corners = 1u64 << promotion.lengths().iter().filter(|len| **len > 1).count();
+                4 + 4 * dim * corners
+            },
+            UzpShapeEncoding::Ineqs => 4 + 4 * (2 * dim) * dim + 4 * (2 * dim),
+        };
+        // Shape id, encoding, dimension, min point, max point, encoding and lattice
+        self.layout.id_bytes() + 2 + 2 + 4 * dim + 4 * dim + encoding_bytes + 4 * 2 * dim
+    }
+}
+
+impl AugmentCostModel for UzpBytesCostModel {
+    fn cost(&self, promotion: &AugmentPromotion) -> AugmentCost {
+        let origins_bytes = promotion.pieces as u64 * self.origin_bytes();
+        let kept_bytes = if promotion.of_single_nonzeros() { promotion.pieces as u64 * self.layout.count_bytes() } else { origins_bytes };
+        AugmentCost {
+            kept: promotion.repetition.0 as u64 * kept_bytes,
+            promoted: origins_bytes + self.shape_bytes(promotion),
+        }
+    }
+}
+
+/// Words loaded by `uzp_spmv`. Hierarchical shapes are walked as the shape they expand to
+#[derive(Clone, Copy, Debug)]
+pub struct SpmvCostModel {
+    pub shape_encoding: UzpShapeEncoding,
+}
+
+impl SpmvCostModel {
+    pub fn new(options: &UzpWriteOptions) -> Self {
+        SpmvCostModel { shape_encoding: options.shape_encoding }
+    }
+
+    /// Words loaded walking an origin of a shape with axes of `lengths`, or an uninc nonzero without any
+    fn origin_words(&self, lengths: &[usize]) -> u64 {
+        if lengths.is_empty() {
+            return UNINC_INDEX_WORDS + POINT_WORDS;
+        }
+
+        let points: u64 = lengths.iter().map(|len| *len as u64).product();
+        let walk_words = match self.shape_encoding {
+            UzpShapeEncoding::VertexRec => {
+                // Every axis is looped over once for every point of the axes outside it
+                let loops: u64 = (0..lengths.len()).map(|axis| lengths[..axis].iter().map(|len| *len as u64).product::<u64>()).sum();
+                AXIS_WALK_WORDS * loops
+            },
+            _ => SHAPE_POINT_WORDS * points,
+        };
+        ORIGIN_WORDS + walk_words + POINT_WORDS * points
+    }
+}
+
+impl AugmentCostModel for SpmvCostModel {
+    fn cost(&self, promotion: &AugmentPromotion) -> AugmentCost {
+        let lengths = promotion.lengths();
+        let shape_words = match self.shape_encoding {
+            UzpShapeEncoding::VertexRec => AXIS_WALK_WORDS * lengths.len() as u64,
+            _ => SHAPE_POINT_WORDS * lengths.iter().map(|len| *len as u64).product::<u64>(),
+        };
+        AugmentCost {
+            kept: promotion.repetition.0 as u64 * promotion.pieces as u64 * self.origin_words(&promotion.sub_lengths),
+            promoted: promotion.pieces as u64 * self.origin_words(&lengths) + shape_words,
+        }
+    }
+}
+
+/// Prints the promotions found augmenting dimensionality as a tab separated table, with what they save in both models
+/// and whether they were made, and then the bytes saved by those made
+pub fn print_promotions(promotions: &[(AugmentPromotion, bool)], bytes_model: &UzpBytesCostModel, spmv_model: &SpmvCostModel) {
+    let made: Vec<&AugmentPromotion> = promotions.iter().filter(|(_, promoted)| *promoted).map(|(promotion, _)| promotion).collect();
+    let bytes_saved: i64 = made.iter().map(|promotion| bytes_model.cost(promotion).saving()).sum();
+
+    if promotions.is_empty() {
+        eprintln!("{} No groups of pieces to promote to higher order shapes", "[INFO]".cyan().bold());
+        return;
+    }
+
+    println!("Dim\tShape\tSub-shape\tRepetition\tPieces\tBytes kept\tBytes promoted\tBytes saved\tSpMV words saved\tPromoted");
+    promotions.iter().for_each(|(promotion, promoted)| {
+        let bytes = bytes_model.cost(promotion);
+        let (n, i, j) = promotion.repetition;
+        println!(
+            "{}\t{}\t{}\t({},{},{})\t{}\t{}\t{}\t{}\t{}\t{}",
+            promotion.dim(), promotion.id, promotion.sub_id, n, i, j, promotion.pieces,
+            bytes.kept, bytes.promoted, bytes.saving(), spmv_model.cost(promotion).saving(), promoted
+        );
+    });
+    eprintln!("{} {} of {} groups of pieces promoted to higher order shapes, saving {} bytes", "[INFO]".cyan().bold(), made.len(), promotions.len(), bytes_saved);
+}
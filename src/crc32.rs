@@ -0,0 +1,76 @@
+/* CRC-32
+ *
+ * The IEEE 802.3 polynomial (reflected, 0xEDB88320), as used by zlib, PNG and `cksum -a crc32b`.
+ * Table driven, one byte at a time.
+ */
+
+const POLYNOMIAL: u32 = 0xEDB88320;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+};
+
+/// Running CRC-32 over several chunks of bytes
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.state = bytes.iter().fold(self.state, |crc, byte| TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8));
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(bytes);
+        crc.finish()
+    }
+
+    #[test]
+    fn check_value() {
+        // Check value of the CRC-32/ISO-HDLC catalogue entry
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414FA339);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn chunks_match_whole() {
+        let bytes: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let mut crc = Crc32::default();
+        bytes.chunks(7).for_each(|chunk| crc.update(chunk));
+        assert_eq!(crc.finish(), crc32(&bytes));
+    }
+}
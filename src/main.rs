@@ -1,5 +1,4 @@
 extern crate sprs;
-extern crate text_io;
 
 use std::io::Write;
 use std::process::exit;
@@ -13,7 +12,10 @@ use crate::spsearch::{SpSearchMatrix,SpSearchPatternsFlags};
 
 mod spaugment;
 #[allow(unused_imports)]
-use crate::spaugment::SpAugment;
+use crate::spaugment::{SpAugment, AugmentDirection, AugmentStrideBounds};
+
+mod augmentcost;
+use crate::augmentcost::{AugmentCostModel, SpmvCostModel, UzpBytesCostModel};
 
 mod uzpgen;
 #[allow(unused_imports)]
@@ -22,24 +24,69 @@ use crate::uzpgen::UZPGen;
 #[macro_use(c)]
 extern crate cute;
 
+#[cfg(test)]
+mod testutil;
+
 mod utils;
 
+mod patfile;
+
+mod mmio;
+use crate::mmio::MmSymmetry;
+
+mod rbio;
+
+mod occupancy;
+
+mod crc32;
+
+mod uzpfile;
+
+mod uzpverify;
+
+mod uzpinfo;
+
+mod uzpspmv;
+
+mod uzpcodegen;
+
 mod flags {
     use std::path::PathBuf;
 
     xflags::xflags! {
         cmd z_polyhedrator {
-            /// Search for (meta)patterns in a matrixmarket file. Optionally augment dimensionality and write to UZP file.
+            /// Search for (meta)patterns in a matrixmarket or rutherford-boeing file. Optionally augment dimensionality and write to UZP file.
             cmd search {
-                /// File containing pattern list
+                /// File containing pattern list, or `auto` to discover patterns from the matrix stride histogram (unless a file named auto exists)
                 required patterns_file_path: PathBuf
 
-                /// Input MatrixMarket file
+                /// Input MatrixMarket or Rutherford-Boeing file
                 required matrixmarket_file_path: PathBuf
 
+                /// Input file format. Valid options: {[auto], mtx, rb} where [] = default. auto picks rb for .rb, .hb and matrix type (.rua, .rsa, .psa...) extensions
+                optional --input-format input_format: String
+
                 /// Print patterns parsed from pattern list
                 optional --print-pattern-list
 
+                /// Comma separated list of pattern groups (`[name]` sections of the patterns file) to search for. Default = all
+                optional --pattern-groups pattern_groups: String
+
+                /// [auto patterns] Max row and column distance between nonzeros for the stride histogram. Default = 8
+                optional -dsmax, --discover-max-stride discover_max_stride: usize
+
+                /// [auto patterns] Max length of discovered patterns. Default = 8
+                optional -dlmax, --discover-max-length discover_max_length: usize
+
+                /// [auto patterns] Max number of discovered patterns. Default = 64
+                optional -dnmax, --discover-max-patterns discover_max_patterns: usize
+
+                /// [auto patterns] Write discovered patterns to a patterns file
+                optional --write-discovered-patterns discovered_patterns_file_path: PathBuf
+
+                /// [auto patterns] Print the stride histogram used to discover patterns
+                optional --print-stride-histogram
+
                 /// Print 1D piece list (AST list) before any dimensionality augmentation
                 optional --print-ast-list
 
@@ -52,12 +99,42 @@ mod flags {
                 /// Transpose matrix at output
                 optional -to, --transpose-output
 
-                /// [2D SEARCH] Search Flags. Valid options: {[PatternFirst], CellFirst} where [] = default.
+                /// Search and write only the lower triangle of symmetric, skew-symmetric and hermitian matrices. The symmetry is recorded in the UZP file
+                optional --symmetric
+
+                /// [2D SEARCH] Search Flags. Valid options: {[PatternFirst], CellFirst, Coverage} where [] = default.
                 optional --search-flags search_flags: String
 
+                /// Report pieces and covered nonzeros of every search strategy before searching
+                optional --compare-strategies
+
+                /// Number of threads used to search for patterns. The result does not depend on it. Default = 1
+                optional -t, --threads threads: usize
+
                 /// Write to custom UZP file. Writes to <output_uzp_file_path>.<N>d.uzp
                 optional -w,--write-uzp output_uzp_file_path: PathBuf
 
+                /// Encoding of the shapes in the UZP file. Valid options: {[vertex_rec], vertex_gen, ineqs} where [] = default
+                optional --shape-encoding shape_encoding: String
+
+                /// Write augmented metapatterns as hierarchical shapes repeating the shape of their sub-pattern, so shared sub-patterns are written once
+                optional --hierarchical-shapes
+
+                /// Write the gcd of the lattice of every axis as its stride, leaving shapes with gaps between their index points. Only for vertex_rec and hierarchical shapes
+                optional --normalize-lattice
+
+                /// Write the legacy UZP layout (no magic number, 32-bit counters) for older readers
+                optional --legacy-uzp
+
+                /// Write 64-bit counters, offsets and indices even if the matrix fits in 32-bit ones. They are used anyway when it does not
+                optional --wide-uzp
+
+                /// Write the metapatterns and their origins as ISL sets and relations (iscc script) to <output_isl_file_path>.<N>d.isl
+                optional --write-isl output_isl_file_path: PathBuf
+
+                /// Also generate a C SpMV kernel for the written UZP file (see the codegen subcommand). Requires --write-uzp
+                optional --write-c output_c_file_path: PathBuf
+
                 /// Augment dimensionality
                 optional -a, --augment-dimensionality augment_dimensionality: usize
 
@@ -70,6 +147,30 @@ mod flags {
                 /// Max stride for augment dimensionality search
                 optional -psmax, --augment-dimensionality-piece-stride-max augment_dimensionality_piece_stride_max: usize
 
+                /// Min absolute row stride for augment dimensionality search. Default = -psmin
+                optional -prsmin, --augment-dimensionality-piece-row-stride-min augment_dimensionality_piece_row_stride_min: usize
+
+                /// Max absolute row stride for augment dimensionality search. Default = -psmax
+                optional -prsmax, --augment-dimensionality-piece-row-stride-max augment_dimensionality_piece_row_stride_max: usize
+
+                /// Min absolute column stride for augment dimensionality search. Default = -psmin
+                optional -pcsmin, --augment-dimensionality-piece-col-stride-min augment_dimensionality_piece_col_stride_min: usize
+
+                /// Max absolute column stride for augment dimensionality search. Default = -psmax
+                optional -pcsmax, --augment-dimensionality-piece-col-stride-max augment_dimensionality_piece_col_stride_max: usize
+
+                /// Strides allowed for augment dimensionality search. Valid options: {[any], row, col} where [] = default. row only allows strides between rows, (i, 0), and col between columns, (0, j)
+                optional --augment-direction augment_direction: String
+
+                /// Only promote groups of pieces to higher order shapes if that lowers their cost. Valid options: {[none], bytes, spmv} where [] = default, or bytes with --augment-singletons. bytes counts the bytes written to the UZP file, and spmv estimates the words loaded by SpMV
+                optional --augment-cost-model augment_cost_model: String
+
+                /// Also augment single nonzeros regularly spaced along a stride into 1D lattice shapes, before augmenting dimensionality
+                optional --augment-singletons
+
+                /// Print every dimension, metapattern and piece found while augmenting dimensionality
+                optional -v, --verbose
+
                 /// Write not included single-points as 1-length patterns
                 optional --write-uninc-as-patterns
 
@@ -77,6 +178,66 @@ mod flags {
                 optional --experimental
             }
 
+            /// Augment the dimensionality of the shapes of a UZP file further, without searching the matrix again, and write the result to another UZP file
+            cmd augment {
+                /// Input UZP file
+                required input_uzp_file_path: PathBuf
+
+                /// Output UZP file. Writes to <output_uzp_file_path>.<N>d.uzp
+                required output_uzp_file_path: PathBuf
+
+                /// Target dimensionality
+                required -a, --augment-dimensionality augment_dimensionality: usize
+
+                /// Minimum piece length for dimensionality augmentation
+                optional -pl, --augment-dimensionality-piece-cutoff augment_dimensionality_piece_cutoff: usize
+
+                /// Min stride for augment dimensionality search
+                optional -psmin, --augment-dimensionality-piece-stride-min augment_dimensionality_piece_stride_min: usize
+
+                /// Max stride for augment dimensionality search
+                optional -psmax, --augment-dimensionality-piece-stride-max augment_dimensionality_piece_stride_max: usize
+
+                /// Min absolute row stride for augment dimensionality search. Default = -psmin
+                optional -prsmin, --augment-dimensionality-piece-row-stride-min augment_dimensionality_piece_row_stride_min: usize
+
+                /// Max absolute row stride for augment dimensionality search. Default = -psmax
+                optional -prsmax, --augment-dimensionality-piece-row-stride-max augment_dimensionality_piece_row_stride_max: usize
+
+                /// Min absolute column stride for augment dimensionality search. Default = -psmin
+                optional -pcsmin, --augment-dimensionality-piece-col-stride-min augment_dimensionality_piece_col_stride_min: usize
+
+                /// Max absolute column stride for augment dimensionality search. Default = -psmax
+                optional -pcsmax, --augment-dimensionality-piece-col-stride-max augment_dimensionality_piece_col_stride_max: usize
+
+                /// Strides allowed for augment dimensionality search. Valid options: {[any], row, col} where [] = default. row only allows strides between rows, (i, 0), and col between columns, (0, j)
+                optional --augment-direction augment_direction: String
+
+                /// Only promote groups of pieces to higher order shapes if that lowers their cost. Valid options: {[none], bytes, spmv} where [] = default, or bytes with --augment-singletons. bytes counts the bytes written to the UZP file, and spmv estimates the words loaded by SpMV
+                optional --augment-cost-model augment_cost_model: String
+
+                /// Also augment single nonzeros regularly spaced along a stride into 1D lattice shapes, before augmenting dimensionality
+                optional --augment-singletons
+
+                /// Print every dimension, metapattern and piece found while augmenting dimensionality
+                optional -v, --verbose
+
+                /// Encoding of the shapes in the UZP file. Valid options: {[vertex_rec], vertex_gen, ineqs} where [] = default
+                optional --shape-encoding shape_encoding: String
+
+                /// Write augmented metapatterns as hierarchical shapes repeating the shape of their sub-pattern, so shared sub-patterns are written once
+                optional --hierarchical-shapes
+
+                /// Write the gcd of the lattice of every axis as its stride, leaving shapes with gaps between their index points. Only for vertex_rec and hierarchical shapes
+                optional --normalize-lattice
+
+                /// Write the legacy UZP layout (no magic number, 32-bit counters) for older readers
+                optional --legacy-uzp
+
+                /// Write 64-bit counters, offsets and indices even if the matrix fits in 32-bit ones. They are used anyway when it does not
+                optional --wide-uzp
+            }
+
             /// Convert UZP file to MTX file, in either CSC or CSR format
             cmd convert {
                 /// Input UZP file
@@ -93,6 +254,9 @@ mod flags {
 
                 /// Print 1D piece list (AST list). It only works in 1D shapes. Useful for utils/plot_ast_2d.py
                 optional --print-ast-list
+
+                /// Write symmetric UZP files with a symmetric MTX header instead of mirroring the stored triangle
+                optional --keep-symmetry
             }
 
             /// Convert UZP file to MTX file, in either CSC or CSR format. Modified into a overall slower version for timing purposes (CPU and Disk operations separated in time)
@@ -108,6 +272,63 @@ mod flags {
 
                 /// Output in csr format
                 optional --csr
+
+                /// Write symmetric UZP files with a symmetric MTX header instead of mirroring the stored triangle
+                optional --keep-symmetry
+            }
+
+            /// Check that a UZP file decodes back to the matrix it was generated from. Exits with a non-zero code on any discrepancy
+            cmd verify {
+                /// Input UZP file
+                required input_uzp_file_path: PathBuf
+
+                /// Source MatrixMarket or Rutherford-Boeing file
+                required matrixmarket_file_path: PathBuf
+
+                /// Source file format. Valid options: {[auto], mtx, rb} where [] = default
+                optional --input-format input_format: String
+
+                /// The UZP file was written with --transpose-input
+                optional -ti, --transpose-input
+
+                /// The UZP file was written with --transpose-output
+                optional -to, --transpose-output
+
+                /// Max absolute difference between values. Default = 0 (exact)
+                optional --tolerance tolerance: f64
+
+                /// Max number of entries listed per kind of discrepancy. Default = 20
+                optional --max-reported max_reported: usize
+            }
+
+            /// Print the header, shapes, coverage and size of a UZP file, compared with CSR and COO encodings of the same matrix
+            cmd info {
+                /// Input UZP file
+                required input_uzp_file_path: PathBuf
+
+                /// Print as JSON
+                optional --json
+            }
+
+            /// Compute y = A·x straight from a UZP file, check it against a CSR SpMV and report the timings of both
+            cmd spmv {
+                /// Input UZP file
+                required input_uzp_file_path: PathBuf
+
+                /// Number of timed SpMVs of each kind. Default = 10
+                optional -r, --repetitions repetitions: usize
+
+                /// Max difference between both results, relative to the CSR result (absolute below 1). Default = 1e-9
+                optional --tolerance tolerance: f64
+            }
+
+            /// Generate a C file with an SpMV kernel specialized for the matrix of a UZP file, plus a driver main (left out with -DUZP_NO_MAIN)
+            cmd codegen {
+                /// Input UZP file
+                required input_uzp_file_path: PathBuf
+
+                /// Output C file
+                required output_c_file_path: PathBuf
             }
         }
     }
@@ -146,6 +367,18 @@ fn main() {
                     }
                     /****************************************/
 
+                    if flags.legacy_uzp && flags.wide_uzp {
+                        eprintln!("{} {} and {} are mutually exclusive.", "[ERROR]".red().bold(), "--legacy-uzp".yellow().bold(), "--wide-uzp".yellow().bold());
+                        exit(-1);
+                    }
+
+                    if flags.write_c.is_some() && flags.write_uzp.is_none() {
+                        eprintln!("{} {} requires {}.", "[ERROR]".red().bold(), "--write-c".yellow().bold(), "--write-uzp".yellow().bold());
+                        exit(-1);
+                    }
+
+                    let shape_encoding = parse_shape_encoding(flags.shape_encoding.as_deref());
+
                     let output_uzp_file_path: (bool, String);
                     output_uzp_file_path = {
                         if flags.write_uzp.as_ref().is_some() {
@@ -155,6 +388,8 @@ fn main() {
                         }
                     };
 
+                    let input_format = parse_input_format(flags.input_format.as_deref());
+
                     let mut search_flags_str: String = "[default]".to_string();
 
                     let search_flags = {
@@ -165,8 +400,9 @@ fn main() {
                             match search_flags_str.as_str() {
                                 "PatternFirst" => l_search_flags |= spsearch::SpSearchPatternsFlags::PatternFirst,
                                 "CellFirst" => l_search_flags |= spsearch::SpSearchPatternsFlags::CellFirst,
+                                "Coverage" => l_search_flags |= spsearch::SpSearchPatternsFlags::Coverage,
                                 def => {
-                                    eprintln!("invalid value `{}` for `--search-flags`. Valid options: {{[PatternFirst], CellFirst}} where [] = default.", def);
+                                    eprintln!("invalid value `{}` for `--search-flags`. Valid options: {{[PatternFirst], CellFirst, Coverage}} where [] = default.", def);
                                     exit(-1);
                                 }
                             }
@@ -177,38 +413,103 @@ fn main() {
                         l_search_flags
                     };
 
+                    let threads = match flags.threads {
+                        Some(0) => {
+                            eprintln!("invalid value `0` for `--threads`. At least one thread is required.");
+                            exit(-1);
+                        },
+                        threads => threads.unwrap_or(1),
+                    };
+
                     /* -------- PARSE -------- */
-                    eprintln!("{} Opening matrixmarket file: {}", "[INFO]".cyan().bold(), matrixmarket_file_path);
+                    eprintln!("{} Opening matrix file: {}", "[INFO]".cyan().bold(), matrixmarket_file_path);
                     std::io::stderr().flush().unwrap();
                     let now = Instant::now();
 
-                    let mut base_matrix: SpSearchMatrix = SpSearchMatrix::from_file(matrixmarket_file_path, flags.transpose_input);
+                    let mut base_matrix: SpSearchMatrix = SpSearchMatrix::from_file(matrixmarket_file_path, flags.transpose_input, input_format, flags.symmetric);
 
                     let elapsed = now.elapsed();
-                    println!("{} Opening matrixmarket file: {} took: {}.{:03} seconds", "[TIME]".green().bold(), matrixmarket_file_path, elapsed.as_secs(), elapsed.subsec_millis());
+                    println!("{} Opening matrix file: {} took: {}.{:03} seconds", "[TIME]".green().bold(), matrixmarket_file_path, elapsed.as_secs(), elapsed.subsec_millis());
                     std::io::stdout().flush().unwrap();
 
-                    eprintln!("{} Opening patterns file: {}", "[INFO]".cyan().bold(), patterns_file_path);
-                    std::io::stderr().flush().unwrap();
-                    let now = Instant::now();
+                    if flags.symmetric {
+                        if base_matrix.symmetry == MmSymmetry::General {
+                            eprintln!("{} Matrix is not declared symmetric. {} has no effect.", "[WARNING]".yellow().bold(), "--symmetric".yellow().bold());
+                        } else {
+                            eprintln!("{} Searching the lower triangle of a {:?} matrix ({} stored nonzeros)", "[INFO]".cyan().bold(), base_matrix.symmetry, base_matrix.nonzeros);
+                        }
+                    }
 
-                    base_matrix.load_patterns(patterns_file_path);
+                    // An existing file named auto is read as any other patterns file
+                    if patterns_file_path == "auto" && !std::path::Path::new(patterns_file_path).exists() {
+                        eprintln!("{} Discovering patterns from stride histogram", "[INFO]".cyan().bold());
+                        std::io::stderr().flush().unwrap();
+                        let now = Instant::now();
+
+                        let histogram = base_matrix.discover_patterns(
+                            flags.discover_max_stride.unwrap_or(8),
+                            flags.discover_max_length.unwrap_or(8),
+                            flags.discover_max_patterns.unwrap_or(64)
+                        );
+
+                        let elapsed = now.elapsed();
+                        println!("{} Discovering patterns took: {}.{:03} seconds", "[TIME]".green().bold(), elapsed.as_secs(), elapsed.subsec_millis());
+                        std::io::stdout().flush().unwrap();
+
+                        if flags.print_stride_histogram {
+                            eprintln!("--- Stride histogram ---");
+                            println!("I\tJ\tCount");
+                            histogram.iter().for_each(|((i, j), count)| {
+                                println!("{}\t{}\t{}", i, j, count);
+                            });
+                        }
 
-                    let elapsed = now.elapsed();
-                    println!("{} Opening patterns file: {} took: {}.{:03} seconds", "[TIME]".green().bold(), patterns_file_path, elapsed.as_secs(), elapsed.subsec_millis());
-                    std::io::stdout().flush().unwrap();
+                        if let Some(discovered_patterns_file_path) = flags.write_discovered_patterns.as_ref() {
+                            eprintln!("{} Writing discovered patterns to: {}", "[INFO]".cyan().bold(), discovered_patterns_file_path.to_str().unwrap());
+                            base_matrix.write_patterns(discovered_patterns_file_path.to_str().unwrap());
+                        }
+                    } else {
+                        eprintln!("{} Opening patterns file: {}", "[INFO]".cyan().bold(), patterns_file_path);
+                        std::io::stderr().flush().unwrap();
+                        let now = Instant::now();
+
+                        let pattern_groups = flags.pattern_groups.as_ref().map(|groups| groups.split(',').map(|g| g.trim().to_string()).collect::<Vec<String>>());
+                        if let Err(e) = base_matrix.load_patterns(patterns_file_path, pattern_groups.as_deref()) {
+                            eprintln!("{} Invalid patterns file. {}", "[ERROR]".red().bold(), e);
+                            exit(-1);
+                        }
+
+                        let elapsed = now.elapsed();
+                        println!("{} Opening patterns file: {} took: {}.{:03} seconds", "[TIME]".green().bold(), patterns_file_path, elapsed.as_secs(), elapsed.subsec_millis());
+                        std::io::stdout().flush().unwrap();
+                    }
 
                     if flags.print_pattern_list {
                         eprintln!("--- Pattern list ---");
                         base_matrix.print_patterns();
                     }
 
+                    if flags.compare_strategies {
+                        eprintln!("--- Search strategies ---");
+                        println!("Strategy\tPieces\tCovered\tCoverage\tTime");
+                        for (name, strategy) in [("PatternFirst", SpSearchPatternsFlags::PatternFirst), ("CellFirst", SpSearchPatternsFlags::CellFirst), ("Coverage", SpSearchPatternsFlags::Coverage)] {
+                            let mut matrix = base_matrix.clone();
+                            let now = Instant::now();
+                            matrix.search_patterns(strategy, threads);
+                            let elapsed = now.elapsed();
+
+                            let (pieces, covered) = matrix.coverage_stats();
+                            println!("{}\t{}\t{}\t{:.2}%\t{}.{:03}", name, pieces, covered, 100.0 * covered as f64 / matrix.nonzeros.max(1) as f64, elapsed.as_secs(), elapsed.subsec_millis());
+                        }
+                        std::io::stdout().flush().unwrap();
+                    }
+
                     /* -------- SEARCH -------- */
                     eprintln!("{} Searching for patterns with flags {}... ", "[INFO]".cyan().bold(), search_flags_str);
                     std::io::stderr().flush().unwrap();
                     let now = Instant::now();
 
-                    base_matrix.search_patterns(search_flags);
+                    base_matrix.search_patterns(search_flags, threads);
 
                     let elapsed = now.elapsed();
                     println!("{} Searching for patterns with flags {} took: {}.{:09} seconds", "[TIME]".green().bold(), search_flags_str, elapsed.as_secs(), elapsed.subsec_nanos());
@@ -222,29 +523,55 @@ fn main() {
                     /* -------- AUGMENT DIMENSIONALITY AND WRITE UZP FILE IF REQUIRED -------- */
                     let augment_dimensionality: usize = flags.augment_dimensionality.unwrap_or(1);
                     let augment_dimensionality_piece_cutoff: usize = flags.augment_dimensionality_piece_cutoff.unwrap_or(2);
-                    let augment_dimensionality_piece_stride_max: usize = flags.augment_dimensionality_piece_stride_max.unwrap_or(std::usize::MAX);
-                    let augment_dimensionality_piece_stride_min: usize = flags.augment_dimensionality_piece_stride_min.unwrap_or(0);
+                    let augment_stride_bounds = parse_augment_stride_bounds(
+                        (flags.augment_dimensionality_piece_stride_min, flags.augment_dimensionality_piece_stride_max),
+                        (flags.augment_dimensionality_piece_row_stride_min, flags.augment_dimensionality_piece_row_stride_max),
+                        (flags.augment_dimensionality_piece_col_stride_min, flags.augment_dimensionality_piece_col_stride_max),
+                        flags.augment_direction.as_deref()
+                    );
+
+                    let write_options = uzpgen::UzpWriteOptions {
+                        transpose_input: flags.transpose_input,
+                        transpose_output: flags.transpose_output,
+                        uninc_as_patterns: flags.write_uninc_as_patterns,
+                        symmetric: flags.symmetric,
+                        shape_encoding,
+                        hierarchical_shapes: flags.hierarchical_shapes,
+                        normalize_lattice: flags.normalize_lattice,
+                        legacy_layout: flags.legacy_uzp,
+                        wide_layout: flags.wide_uzp,
+                    };
+                    let matrix_size = (base_matrix.numrows, base_matrix.numcols, base_matrix.nonzeros);
+                    // Lattices of a few single nonzeros rarely make up for their shape definitions, so they are only made if they save bytes unless told otherwise
+                    let augment_cost_model = parse_augment_cost_model(flags.augment_cost_model.as_deref().or(flags.augment_singletons.then_some("bytes")), &write_options, matrix_size);
 
-                    if flags.print_uwc_list || output_uzp_file_path.0 || augment_dimensionality > 1 {
+                    if flags.print_uwc_list || output_uzp_file_path.0 || flags.write_isl.is_some() || augment_dimensionality > 1 || flags.augment_singletons {
                         let mut uzpgen = UZPGen::from_piece_list(base_matrix.get_piece_list(), base_matrix.numrows, base_matrix.numcols, base_matrix.nonzeros);
 
                         let mut spaugment;
-                        if augment_dimensionality > 1 {
+                        if augment_dimensionality > 1 || flags.augment_singletons {
                             // Augment dimensionality
                             spaugment = SpAugment::from_1d_origin_uwc_list(uzpgen.get_orig_uwc_list(), uzpgen.nrows, uzpgen.ncols, uzpgen.nnz);
+                            spaugment.verbose = flags.verbose;
 
                             eprintln!("{} Augmenting dimensionality... ", "[INFO]".cyan().bold());
                             std::io::stderr().flush().unwrap();
                             let now = Instant::now();
 
-                            spaugment.augment_dimensionality(augment_dimensionality, augment_dimensionality_piece_cutoff, augment_dimensionality_piece_stride_min, augment_dimensionality_piece_stride_max);
+                            if flags.augment_singletons {
+                                spaugment.augment_singletons(augment_dimensionality_piece_cutoff, &augment_stride_bounds, augment_cost_model.as_deref());
+                            }
+                            spaugment.augment_dimensionality(augment_dimensionality, augment_dimensionality_piece_cutoff, augment_stride_bounds, augment_cost_model.as_deref());
 
                             let elapsed = now.elapsed();
                             println!("{} Augmenting dimensionality took: {}.{:03} seconds", "[TIME]".green().bold(), elapsed.as_secs(), elapsed.subsec_millis());
                             std::io::stdout().flush().unwrap();
 
+                            augmentcost::print_promotions(spaugment.get_promotions(), &UzpBytesCostModel::new(&write_options, matrix_size.0, matrix_size.1, matrix_size.2), &SpmvCostModel::new(&write_options));
+                            std::io::stdout().flush().unwrap();
+
                             // And update uzpgen accordingly
-                            uzpgen = UZPGen::from_metapatterns_list(spaugment.get_metapatterns(), spaugment.get_metapattern_pieces(), uzpgen.nrows, uzpgen.ncols, uzpgen.nnz, uzpgen.inc_nnz);
+                            uzpgen = UZPGen::from_metapatterns_list(spaugment.get_metapatterns(), spaugment.get_metapattern_pieces(), uzpgen.nrows, uzpgen.ncols, uzpgen.nnz, spaugment.inc_nnz());
                         }
 
                         if flags.print_uwc_list {
@@ -252,21 +579,136 @@ fn main() {
                             uzpgen.print_distinct_uwc_list(true);
                         }
 
+                        if let Some(output_isl_file_path) = flags.write_isl.as_ref() {
+                            let isl_file_path = format!("{}.{}d.isl", output_isl_file_path.to_str().unwrap(), augment_dimensionality);
+                            eprintln!("{} Writing ISL file {}... ", "[INFO]".cyan().bold(), isl_file_path.bright_blue());
+                            std::io::stderr().flush().unwrap();
+
+                            if let Err(e) = uzpgen.write_isl(&isl_file_path, flags.transpose_output) {
+                                eprintln!("{} Unable to write ISL file {}. ERROR: {}", "[ERROR]".red().bold(), isl_file_path.bright_blue(), e.to_string().bold().red());
+                                exit(1);
+                            }
+                        }
+
                         if output_uzp_file_path.0 {
                             eprintln!("{} Writing UZP file... ", "[INFO]".cyan().bold());
                             std::io::stderr().flush().unwrap();
                             let now = Instant::now();
 
-                            uzpgen.write_uzp(&matrixmarket_file_path, &format!("{}.{}d.uzp", &output_uzp_file_path.1, augment_dimensionality), flags.transpose_input, flags.transpose_output, flags.write_uninc_as_patterns);
+                            let uzp_file_path = format!("{}.{}d.uzp", &output_uzp_file_path.1, augment_dimensionality);
+                            uzpgen.write_uzp(matrixmarket_file_path, input_format, &uzp_file_path, &write_options);
 
                             let elapsed = now.elapsed();
                             println!("{} Writing UZP file took: {}.{:03} seconds", "[TIME]".green().bold(), elapsed.as_secs(), elapsed.subsec_millis());
                             std::io::stdout().flush().unwrap();
+
+                            if let Some(output_c_file_path) = flags.write_c.as_ref() {
+                                eprintln!("{} Generating C SpMV kernel... ", "[INFO]".cyan().bold());
+                                std::io::stderr().flush().unwrap();
+                                let now = Instant::now();
+
+                                uzpcodegen::codegen_uzp(&uzp_file_path, output_c_file_path.to_str().unwrap());
+
+                                let elapsed = now.elapsed();
+                                println!("{} Generating C file took: {}.{:03} seconds", "[TIME]".green().bold(), elapsed.as_secs(), elapsed.subsec_millis());
+                                std::io::stdout().flush().unwrap();
+                            }
                         }
 
                     }
                 }
 
+                flags::Z_polyhedratorCmd::Augment(flags) => {
+                    let input_uzp_file_path = flags.input_uzp_file_path.to_str().unwrap();
+                    let output_uzp_file_path = flags.output_uzp_file_path.to_str().unwrap();
+
+                    if flags.legacy_uzp && flags.wide_uzp {
+                        eprintln!("{} {} and {} are mutually exclusive.", "[ERROR]".red().bold(), "--legacy-uzp".yellow().bold(), "--wide-uzp".yellow().bold());
+                        exit(-1);
+                    }
+
+                    let shape_encoding = parse_shape_encoding(flags.shape_encoding.as_deref());
+
+                    let augment_dimensionality: usize = flags.augment_dimensionality;
+                    let augment_dimensionality_piece_cutoff: usize = flags.augment_dimensionality_piece_cutoff.unwrap_or(2);
+                    let augment_stride_bounds = parse_augment_stride_bounds(
+                        (flags.augment_dimensionality_piece_stride_min, flags.augment_dimensionality_piece_stride_max),
+                        (flags.augment_dimensionality_piece_row_stride_min, flags.augment_dimensionality_piece_row_stride_max),
+                        (flags.augment_dimensionality_piece_col_stride_min, flags.augment_dimensionality_piece_col_stride_max),
+                        flags.augment_direction.as_deref()
+                    );
+
+                    /* -------- READ -------- */
+                    eprintln!("{} Reading UZP file: {}... ", "[INFO]".cyan().bold(), input_uzp_file_path);
+                    std::io::stderr().flush().unwrap();
+                    let now = Instant::now();
+
+                    let uzp_file = uzpgen::read_uzp_file(input_uzp_file_path);
+                    let uzpgen = match UZPGen::from_uzp_file(&uzp_file) {
+                        Ok(uzpgen) => uzpgen,
+                        Err(e) => {
+                            eprintln!("{} Unable to augment UZP file {}. ERROR: {}", "[ERROR]".red().bold(), input_uzp_file_path.bright_blue(), e.bold().red());
+                            exit(1);
+                        }
+                    };
+
+                    let elapsed = now.elapsed();
+                    println!("{} Reading UZP file: {} took: {}.{:03} seconds", "[TIME]".green().bold(), input_uzp_file_path, elapsed.as_secs(), elapsed.subsec_millis());
+                    std::io::stdout().flush().unwrap();
+
+                    /* -------- AUGMENT DIMENSIONALITY -------- */
+                    let write_options = uzpgen::UzpWriteOptions {
+                        transpose_input: false,
+                        transpose_output: false,
+                        uninc_as_patterns: false,
+                        symmetric: uzp_file.symmetry != MmSymmetry::General,
+                        shape_encoding,
+                        hierarchical_shapes: flags.hierarchical_shapes,
+                        normalize_lattice: flags.normalize_lattice,
+                        legacy_layout: flags.legacy_uzp,
+                        wide_layout: flags.wide_uzp,
+                    };
+                    let matrix_size = (uzpgen.nrows, uzpgen.ncols, uzpgen.nnz);
+                    // Lattices of a few single nonzeros rarely make up for their shape definitions, so they are only made if they save bytes unless told otherwise
+                    let augment_cost_model = parse_augment_cost_model(flags.augment_cost_model.as_deref().or(flags.augment_singletons.then_some("bytes")), &write_options, matrix_size);
+
+                    let mut spaugment = SpAugment::from_metapatterns(uzpgen.get_metapatterns(), uzpgen.get_metapattern_pieces(), uzpgen.nrows, uzpgen.ncols, uzpgen.nnz);
+                    spaugment.verbose = flags.verbose;
+
+                    eprintln!("{} Augmenting dimensionality... ", "[INFO]".cyan().bold());
+                    std::io::stderr().flush().unwrap();
+                    let now = Instant::now();
+
+                    if flags.augment_singletons {
+                        spaugment.augment_singletons(augment_dimensionality_piece_cutoff, &augment_stride_bounds, augment_cost_model.as_deref());
+                    }
+                    spaugment.augment_dimensionality(augment_dimensionality, augment_dimensionality_piece_cutoff, augment_stride_bounds, augment_cost_model.as_deref());
+
+                    let elapsed = now.elapsed();
+                    println!("{} Augmenting dimensionality took: {}.{:03} seconds", "[TIME]".green().bold(), elapsed.as_secs(), elapsed.subsec_millis());
+                    std::io::stdout().flush().unwrap();
+
+                    augmentcost::print_promotions(spaugment.get_promotions(), &UzpBytesCostModel::new(&write_options, matrix_size.0, matrix_size.1, matrix_size.2), &SpmvCostModel::new(&write_options));
+                    std::io::stdout().flush().unwrap();
+
+                    let uzpgen = UZPGen::from_metapatterns_list(spaugment.get_metapatterns(), spaugment.get_metapattern_pieces(), uzpgen.nrows, uzpgen.ncols, uzpgen.nnz, spaugment.inc_nnz());
+
+                    /* -------- WRITE -------- */
+                    eprintln!("{} Writing UZP file... ", "[INFO]".cyan().bold());
+                    std::io::stderr().flush().unwrap();
+                    let now = Instant::now();
+
+                    // Values come from the input file, already stored the way they were searched
+                    let f64_value_matrix = uzp_file.to_trimat(false).to_csr::<usize>();
+
+                    let uzp_file_path = format!("{}.{}d.uzp", output_uzp_file_path, augment_dimensionality);
+                    uzpgen.write_uzp_with_values(&f64_value_matrix, uzp_file.symmetry, &uzp_file_path, &write_options);
+
+                    let elapsed = now.elapsed();
+                    println!("{} Writing UZP file took: {}.{:03} seconds", "[TIME]".green().bold(), elapsed.as_secs(), elapsed.subsec_millis());
+                    std::io::stdout().flush().unwrap();
+                }
+
                 flags::Z_polyhedratorCmd::Convert(flags) => {
                     let input_uzp_file_path = flags.input_uzp_file_path.to_str().unwrap();
                     let output_mtx_file_path = flags.output_mtx_file_path.to_str().unwrap();
@@ -275,7 +717,7 @@ fn main() {
                     std::io::stderr().flush().unwrap();
                     let now = Instant::now();
 
-                    uzpgen::convert_uzp(input_uzp_file_path, output_mtx_file_path, flags.csr && !flags.csc, flags.print_ast_list);
+                    uzpgen::convert_uzp(input_uzp_file_path, output_mtx_file_path, flags.csr && !flags.csc, flags.print_ast_list, flags.keep_symmetry);
 
                     let elapsed = now.elapsed();
                     if !flags.print_ast_list {
@@ -292,7 +734,132 @@ fn main() {
                     std::io::stderr().flush().unwrap();
 
                     // Conversion time is measured inside the function
-                    uzpgen::convert_uzp_for_timing(input_uzp_file_path, output_mtx_file_path, flags.csr && !flags.csc);
+                    uzpgen::convert_uzp_for_timing(input_uzp_file_path, output_mtx_file_path, flags.csr && !flags.csc, flags.keep_symmetry);
+                }
+
+                flags::Z_polyhedratorCmd::Verify(flags) => {
+                    let input_uzp_file_path = flags.input_uzp_file_path.to_str().unwrap();
+                    let matrixmarket_file_path = flags.matrixmarket_file_path.to_str().unwrap();
+                    let input_format = parse_input_format(flags.input_format.as_deref());
+
+                    let tolerance = flags.tolerance.unwrap_or(0.0);
+                    if tolerance.is_nan() || tolerance < 0.0 {
+                        eprintln!("{} Tolerance must be a non-negative number.", "[ERROR]".red().bold());
+                        exit(-1);
+                    }
+
+                    eprintln!("{} Verifying UZP file: {} against {}... ", "[INFO]".cyan().bold(), input_uzp_file_path, matrixmarket_file_path);
+                    std::io::stderr().flush().unwrap();
+                    let now = Instant::now();
+
+                    let uzp_file = uzpgen::read_uzp_file(input_uzp_file_path);
+
+                    // Symmetric UZP files are mirrored when decoded, so the full source matrix is compared
+                    let (mut source_matrix, _) = utils::read_matrix_csr::<f64>(matrixmarket_file_path, flags.transpose_input, input_format, false);
+                    if flags.transpose_output {
+                        source_matrix = source_matrix.transpose_view().to_csr();
+                    }
+
+                    let report = uzpverify::verify_uzp(&uzp_file, &source_matrix, tolerance);
+                    uzpverify::print_report(&report, flags.max_reported.unwrap_or(20));
+
+                    let elapsed = now.elapsed();
+                    println!("{} Verifying UZP file: {} took: {}.{:03} seconds", "[TIME]".green().bold(), input_uzp_file_path, elapsed.as_secs(), elapsed.subsec_millis());
+                    std::io::stdout().flush().unwrap();
+
+                    if !report.is_ok() {
+                        eprintln!("{} UZP file {} does not match {}", "[ERROR]".red().bold(), input_uzp_file_path, matrixmarket_file_path);
+                        exit(1);
+                    }
+                    eprintln!("{} UZP file matches the source matrix", "[INFO]".cyan().bold());
+                }
+
+                flags::Z_polyhedratorCmd::Info(flags) => {
+                    let input_uzp_file_path = flags.input_uzp_file_path.to_str().unwrap();
+
+                    eprintln!("{} Reading UZP file: {}... ", "[INFO]".cyan().bold(), input_uzp_file_path);
+                    std::io::stderr().flush().unwrap();
+
+                    let uzp_file = uzpgen::read_uzp_file(input_uzp_file_path);
+                    let file_bytes = std::fs::metadata(input_uzp_file_path).map(|metadata| metadata.len()).unwrap_or(0);
+
+                    let info = uzpinfo::uzp_info(&uzp_file, file_bytes);
+                    if flags.json {
+                        uzpinfo::print_info_json(&info, input_uzp_file_path);
+                    } else {
+                        uzpinfo::print_info(&info);
+                    }
+                    std::io::stdout().flush().unwrap();
+                }
+
+                flags::Z_polyhedratorCmd::Spmv(flags) => {
+                    let input_uzp_file_path = flags.input_uzp_file_path.to_str().unwrap();
+
+                    let repetitions = flags.repetitions.unwrap_or(10);
+                    if repetitions == 0 {
+                        eprintln!("{} Repetitions must be greater than 0.", "[ERROR]".red().bold());
+                        exit(-1);
+                    }
+                    let tolerance = flags.tolerance.unwrap_or(1e-9);
+                    if tolerance.is_nan() || tolerance < 0.0 {
+                        eprintln!("{} Tolerance must be a non-negative number.", "[ERROR]".red().bold());
+                        exit(-1);
+                    }
+
+                    eprintln!("{} Running SpMV on UZP file: {}... ", "[INFO]".cyan().bold(), input_uzp_file_path);
+                    std::io::stderr().flush().unwrap();
+
+                    let uzp_file = uzpgen::read_uzp_file(input_uzp_file_path);
+                    let csr_matrix = uzp_file.to_trimat(true).to_csr::<usize>();
+                    let (nrows, ncols) = (uzp_file.header.nrows, uzp_file.header.ncols);
+
+                    let x: Vec<f64> = uzpspmv::input_vector(ncols);
+
+                    let mut uzp_y: Vec<f64> = vec![0.0; nrows];
+                    let now = Instant::now();
+                    for _ in 0..repetitions {
+                        uzp_y.iter_mut().for_each(|val| *val = 0.0);
+                        uzpspmv::uzp_spmv(&uzp_file, &x, &mut uzp_y);
+                    }
+                    let uzp_elapsed = now.elapsed();
+
+                    let mut csr_y: Vec<f64> = vec![0.0; nrows];
+                    let now = Instant::now();
+                    for _ in 0..repetitions {
+                        csr_y.iter_mut().for_each(|val| *val = 0.0);
+                        uzpspmv::csr_spmv(&csr_matrix, &x, &mut csr_y);
+                    }
+                    let csr_elapsed = now.elapsed();
+
+                    println!("{} UZP SpMV x{} took: {}.{:09} seconds ({}.{:09} seconds per SpMV)", "[TIME]".green().bold(), repetitions, uzp_elapsed.as_secs(), uzp_elapsed.subsec_nanos(), (uzp_elapsed / repetitions as u32).as_secs(), (uzp_elapsed / repetitions as u32).subsec_nanos());
+                    println!("{} CSR SpMV x{} took: {}.{:09} seconds ({}.{:09} seconds per SpMV)", "[TIME]".green().bold(), repetitions, csr_elapsed.as_secs(), csr_elapsed.subsec_nanos(), (csr_elapsed / repetitions as u32).as_secs(), (csr_elapsed / repetitions as u32).subsec_nanos());
+                    eprintln!("{} UZP/CSR time ratio: {:.3}", "[INFO]".cyan().bold(), uzp_elapsed.as_secs_f64() / csr_elapsed.as_secs_f64());
+
+                    let mismatched: Vec<usize> = (0..nrows).filter(|row| (uzp_y[*row] - csr_y[*row]).abs() > tolerance * csr_y[*row].abs().max(1.0)).collect();
+                    let max_diff = uzp_y.iter().zip(csr_y.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max);
+                    std::io::stdout().flush().unwrap();
+
+                    if !mismatched.is_empty() {
+                        eprintln!("{} {} rows of y differ between UZP and CSR SpMV (max difference {:e}):", "[ERROR]".red().bold(), mismatched.len(), max_diff);
+                        mismatched.iter().take(20).for_each(|row| eprintln!("    y[{}]: UZP = {:e}, CSR = {:e}", row+1, uzp_y[*row], csr_y[*row]));
+                        exit(1);
+                    }
+                    eprintln!("{} UZP SpMV matches CSR SpMV (max difference {:e})", "[INFO]".cyan().bold(), max_diff);
+                }
+
+                flags::Z_polyhedratorCmd::Codegen(flags) => {
+                    let input_uzp_file_path = flags.input_uzp_file_path.to_str().unwrap();
+                    let output_c_file_path = flags.output_c_file_path.to_str().unwrap();
+
+                    eprintln!("{} Generating C SpMV kernel for UZP file: {}... ", "[INFO]".cyan().bold(), input_uzp_file_path);
+                    std::io::stderr().flush().unwrap();
+                    let now = Instant::now();
+
+                    uzpcodegen::codegen_uzp(input_uzp_file_path, output_c_file_path);
+
+                    let elapsed = now.elapsed();
+                    println!("{} Generating C file: {} took: {}.{:03} seconds", "[TIME]".green().bold(), output_c_file_path, elapsed.as_secs(), elapsed.subsec_millis());
+                    std::io::stdout().flush().unwrap();
                 }
             }
         }
@@ -302,3 +869,70 @@ fn main() {
         }
     }
 }
+
+fn parse_shape_encoding(shape_encoding: Option<&str>) -> uzpfile::UzpShapeEncoding {
+    match shape_encoding {
+        None | Some("vertex_rec") => uzpfile::UzpShapeEncoding::VertexRec,
+        Some("vertex_gen") => uzpfile::UzpShapeEncoding::VertexGen,
+        Some("ineqs") => uzpfile::UzpShapeEncoding::Ineqs,
+        Some(def) => {
+            eprintln!("invalid value `{}` for `--shape-encoding`. Valid options: {{[vertex_rec], vertex_gen, ineqs}} where [] = default.", def);
+            exit(-1);
+        }
+    }
+}
+
+/// Row and column stride bounds default to the bounds of both, (-psmin, -psmax)
+fn parse_augment_stride_bounds(stride: (Option<usize>, Option<usize>), row_stride: (Option<usize>, Option<usize>), col_stride: (Option<usize>, Option<usize>), direction: Option<&str>) -> AugmentStrideBounds {
+    let (min_stride, max_stride) = (stride.0.unwrap_or(0), stride.1.unwrap_or(usize::MAX));
+
+    let direction = match direction {
+        None | Some("any") => AugmentDirection::Any,
+        Some("row") => AugmentDirection::RowWise,
+        Some("col") => AugmentDirection::ColWise,
+        Some(def) => {
+            eprintln!("invalid value `{}` for `--augment-direction`. Valid options: {{[any], row, col}} where [] = default.", def);
+            exit(-1);
+        }
+    };
+
+    let stride_bounds = AugmentStrideBounds {
+        min_row_stride: row_stride.0.unwrap_or(min_stride),
+        max_row_stride: row_stride.1.unwrap_or(max_stride),
+        min_col_stride: col_stride.0.unwrap_or(min_stride),
+        max_col_stride: col_stride.1.unwrap_or(max_stride),
+        direction,
+    };
+
+    if stride_bounds.min_row_stride > stride_bounds.max_row_stride || stride_bounds.min_col_stride > stride_bounds.max_col_stride {
+        eprintln!("{} Min strides for augment dimensionality search must not be greater than max strides.", "[ERROR]".red().bold());
+        exit(-1);
+    }
+
+    stride_bounds
+}
+
+/// Without a cost model every group of pieces found is promoted
+fn parse_augment_cost_model(cost_model: Option<&str>, write_options: &uzpgen::UzpWriteOptions, (nrows, ncols, nnz): (usize, usize, usize)) -> Option<Box<dyn AugmentCostModel>> {
+    match cost_model {
+        None | Some("none") => None,
+        Some("bytes") => Some(Box::new(UzpBytesCostModel::new(write_options, nrows, ncols, nnz))),
+        Some("spmv") => Some(Box::new(SpmvCostModel::new(write_options))),
+        Some(def) => {
+            eprintln!("invalid value `{}` for `--augment-cost-model`. Valid options: {{[none], bytes, spmv}} where [] = default.", def);
+            exit(-1);
+        }
+    }
+}
+
+fn parse_input_format(input_format: Option<&str>) -> utils::InputFormat {
+    match input_format {
+        None | Some("auto") => utils::InputFormat::Auto,
+        Some("mtx") => utils::InputFormat::MatrixMarket,
+        Some("rb") => utils::InputFormat::RutherfordBoeing,
+        Some(def) => {
+            eprintln!("invalid value `{}` for `--input-format`. Valid options: {{[auto], mtx, rb}} where [] = default.", def);
+            exit(-1);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use sprs::TriMat;
+
+/* MATRIXMARKET HEADER */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmFormat {
+    Coordinate,
+    Array,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmField {
+    Real,
+    Integer,
+    Complex,
+    Pattern,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MmSymmetry {
+    General,
+    Symmetric,
+    SkewSymmetric,
+    Hermitian,
+}
+
+impl MmSymmetry {
+    /// Name of the symmetry in MatrixMarket headers
+    pub fn name(&self) -> &'static str {
+        match self {
+            MmSymmetry::General => "general",
+            MmSymmetry::Symmetric => "symmetric",
+            MmSymmetry::SkewSymmetric => "skew-symmetric",
+            MmSymmetry::Hermitian => "hermitian",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MmHeader {
+    pub format: MmFormat,
+    pub field: MmField,
+    pub symmetry: MmSymmetry,
+}
+
+/// Reads a MatrixMarket file into a f64 triplet matrix, expanding any symmetric storage
+/// so the result always holds the full matrix.
+///
+/// Every header variant is accepted: coordinate and array formats, real/integer/complex/pattern
+/// fields and general/symmetric/skew-symmetric/hermitian symmetries. Pattern entries are read as
+/// 1.0 and complex entries keep their real part only (A == A^T for the real part of a hermitian
+/// matrix). Array matrices are dense, so their explicit zeros are not stored.
+pub fn read_matrix_market_triplets(path: &str) -> Result<(TriMat<f64>, MmHeader), String> {
+    let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+    read_matrix_market(BufReader::new(file))
+}
+
+/// Same as `read_matrix_market_triplets`, from any reader
+fn read_matrix_market(reader: impl BufRead) -> Result<(TriMat<f64>, MmHeader), String> {
+    let mut lines = reader.lines().enumerate();
+
+    // Banner line. Tags are case insensitive
+    let header = match lines.next() {
+        Some((_, line)) => {
+            let line = line.map_err(|e| format!("line 1: {}", e))?;
+            parse_banner(&line.to_lowercase()).map_err(|e| format!("line 1: {}", e))?
+        },
+        None => return Err("file is empty".to_string()),
+    };
+
+    // Skip comments and blank lines until the size line
+    let (size_line_no, size_line) = loop {
+        match lines.next() {
+            Some((idx, line)) => {
+                let line = line.map_err(|e| format!("line {}: {}", idx+1, e))?;
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('%') { continue; }
+                break (idx+1, line);
+            },
+            None => return Err("missing size line".to_string()),
+        }
+    };
+
+    let sizes = size_line
+        .split_whitespace()
+        .map(|s| s.parse::<usize>())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|e| format!("line {}: invalid size line `{}`: {}", size_line_no, size_line.trim(), e))?;
+
+    let (nrows, ncols, entries) = match (header.format, sizes.as_slice()) {
+        (MmFormat::Coordinate, &[nrows, ncols, entries]) => (nrows, ncols, entries),
+        (MmFormat::Array, &[nrows, ncols]) => {
+            let entries = match header.symmetry {
+                MmSymmetry::General => nrows * ncols,
+                MmSymmetry::SkewSymmetric => nrows * (nrows.saturating_sub(1)) / 2,
+                _ => nrows * (nrows + 1) / 2,
+            };
+            (nrows, ncols, entries)
+        },
+        _ => return Err(format!("line {}: invalid size line `{}` for {:?} format", size_line_no, size_line.trim(), header.format)),
+    };
+
+    if header.symmetry != MmSymmetry::General && nrows != ncols {
+        return Err(format!("line {}: {:?} matrices must be square, found {}x{}", size_line_no, header.symmetry, nrows, ncols));
+    }
+
+    let capacity = if header.symmetry == MmSymmetry::General { entries } else { 2 * entries };
+    let mut triplets: TriMat<f64> = TriMat::with_capacity((nrows, ncols), capacity);
+
+    // Position of the next value for array format (column-major, only the stored triangle for symmetric ones)
+    let (mut arr_row, mut arr_col) = (if header.symmetry == MmSymmetry::SkewSymmetric { 1 } else { 0 }, 0usize);
+
+    let mut read_entries = 0usize;
+    while read_entries < entries {
+        let (line_no, line) = match lines.next() {
+            Some((idx, line)) => (idx+1, line.map_err(|e| format!("line {}: {}", idx+1, e))?),
+            None => return Err(format!("expected {} entries, found only {}", entries, read_entries)),
+        };
+
+        let mut tokens = line.split_whitespace();
+        // Blank lines may appear between entries
+        let first = match tokens.next() {
+            Some(tok) => tok,
+            None => continue,
+        };
+
+        let (row, col, value) = match header.format {
+            MmFormat::Coordinate => {
+                let row = parse_index(first, line_no, nrows)?;
+                let col = parse_index(tokens.next().ok_or(format!("line {}: missing column index", line_no))?, line_no, ncols)?;
+                let value = parse_value(&mut tokens, header.field, line_no)?;
+                (row, col, value)
+            },
+            MmFormat::Array => {
+                let mut values = std::iter::once(first).chain(tokens.by_ref());
+                let value = parse_value(&mut values, header.field, line_no)?;
+                let pos = (arr_row, arr_col);
+
+                // Advance to next stored position
+                arr_row += 1;
+                if arr_row == nrows {
+                    arr_col += 1;
+                    arr_row = match header.symmetry {
+                        MmSymmetry::General => 0,
+                        MmSymmetry::SkewSymmetric => arr_col + 1,
+                        _ => arr_col,
+                    };
+                }
+
+                (pos.0, pos.1, value)
+            },
+        };
+
+        if tokens.next().is_some() {
+            return Err(format!("line {}: unexpected trailing data `{}`", line_no, line.trim()));
+        }
+
+        read_entries += 1;
+
+        if header.format == MmFormat::Array && value == 0.0 {
+            continue;
+        }
+
+        if header.symmetry == MmSymmetry::SkewSymmetric && row == col {
+            return Err(format!("line {}: skew-symmetric matrices cannot store diagonal entries", line_no));
+        }
+
+        triplets.add_triplet(row, col, value);
+
+        if header.symmetry != MmSymmetry::General && row != col {
+            match header.symmetry {
+                MmSymmetry::SkewSymmetric => triplets.add_triplet(col, row, -value),
+                // Conjugation only affects the imaginary part, which is already dropped
+                _ => triplets.add_triplet(col, row, value),
+            }
+        }
+    }
+
+    Ok((triplets, header))
+}
+
+fn parse_banner(banner: &str) -> Result<MmHeader, String> {
+    let tokens = banner.split_whitespace().collect::<Vec<&str>>();
+
+    if tokens.len() != 5 || tokens[0] != "%%matrixmarket" {
+        return Err(format!("invalid MatrixMarket banner `{}`", banner.trim()));
+    }
+
+    if tokens[1] != "matrix" {
+        return Err(format!("unsupported object `{}`, only `matrix` is supported", tokens[1]));
+    }
+
+    let format = match tokens[2] {
+        "coordinate" => MmFormat::Coordinate,
+        "array" => MmFormat::Array,
+        other => return Err(format!("unsupported format `{}`", other)),
+    };
+
+    let field = match tokens[3] {
+        "real" | "double" => MmField::Real,
+        "integer" => MmField::Integer,
+        "complex" => MmField::Complex,
+        "pattern" => MmField::Pattern,
+        other => return Err(format!("unsupported field `{}`", other)),
+    };
+
+    let symmetry = match tokens[4] {
+        "general" => MmSymmetry::General,
+        "symmetric" => MmSymmetry::Symmetric,
+        "skew-symmetric" => MmSymmetry::SkewSymmetric,
+        "hermitian" => MmSymmetry::Hermitian,
+        other => return Err(format!("unsupported symmetry `{}`", other)),
+    };
+
+    if format == MmFormat::Array && field == MmField::Pattern {
+        return Err("pattern field is not allowed with array format".to_string());
+    }
+
+    Ok(MmHeader { format, field, symmetry })
+}
+
+#[inline(always)]
+fn parse_index(token: &str, line_no: usize, bound: usize) -> Result<usize, String> {
+    // MatrixMarket indices are 1-based
+    match token.parse::<usize>() {
+        Ok(idx) if idx >= 1 && idx <= bound => Ok(idx - 1),
+        Ok(idx) => Err(format!("line {}: index {} out of bounds [1, {}]", line_no, idx, bound)),
+        Err(e) => Err(format!("line {}: invalid index `{}`: {}", line_no, token, e)),
+    }
+}
+
+#[inline(always)]
+fn parse_value<'a>(tokens: &mut impl Iterator<Item = &'a str>, field: MmField, line_no: usize) -> Result<f64, String> {
+    let mut next_f64 = || -> Result<f64, String> {
+        let tok = tokens.next().ok_or(format!("line {}: missing value", line_no))?;
+        tok.parse::<f64>().map_err(|e| format!("line {}: invalid value `{}`: {}", line_no, tok, e))
+    };
+
+    match field {
+        MmField::Pattern => Ok(1.0),
+        MmField::Real | MmField::Integer => next_f64(),
+        MmField::Complex => {
+            let re = next_f64()?;
+            // Imaginary part is validated but dropped
+            next_f64()?;
+            Ok(re)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sprs::CsMat;
+
+    use crate::testutil::entries;
+
+    fn read(contents: &str) -> Result<(TriMat<f64>, MmHeader), String> {
+        read_matrix_market(contents.as_bytes())
+    }
+
+    #[test]
+    fn round_trip_through_sprs_writer() {
+        let mut triplets: TriMat<f64> = TriMat::new((3, 4));
+        [(0, 0, 1.5), (0, 3, -2.0), (1, 1, 0.25), (2, 0, 8.0), (2, 2, 1e-3)].iter().for_each(|(row, col, val)| triplets.add_triplet(*row, *col, *val));
+        let matrix: CsMat<f64> = triplets.to_csr();
+
+        let mut contents: Vec<u8> = vec![];
+        sprs::io::write_matrix_market_to_bufwrite(&mut contents, &matrix).unwrap();
+        let (read, header) = read_matrix_market(contents.as_slice()).unwrap();
+
+        assert_eq!((header.format, header.field, header.symmetry), (MmFormat::Coordinate, MmField::Real, MmSymmetry::General));
+        assert_eq!(read.to_csr::<usize>(), matrix);
+    }
+
+    #[test]
+    fn header_and_size_line() {
+        let (triplets, header) = read("%%MatrixMarket MATRIX Coordinate Integer Symmetric\n% comment\n\n  3 3 2  \n2 1 7\n3 3 -1\n").unwrap();
+
+        assert_eq!((header.format, header.field, header.symmetry), (MmFormat::Coordinate, MmField::Integer, MmSymmetry::Symmetric));
+        assert_eq!(triplets.shape(), (3, 3));
+        // Symmetric storage is expanded
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 1, 7.0), (1, 0, 7.0), (2, 2, -1.0)]);
+    }
+
+    #[test]
+    fn array_pattern_and_complex_fields() {
+        let (triplets, _) = read("%%MatrixMarket matrix array real general\n2 2\n1\n0\n3\n4\n").unwrap();
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 0, 1.0), (0, 1, 3.0), (1, 1, 4.0)]);
+
+        let (triplets, _) = read("%%MatrixMarket matrix array real skew-symmetric\n3 3\n2\n0\n5\n").unwrap();
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 1, -2.0), (1, 0, 2.0), (1, 2, -5.0), (2, 1, 5.0)]);
+
+        let (triplets, _) = read("%%MatrixMarket matrix coordinate pattern general\n2 3 2\n1 3\n2 1\n").unwrap();
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 2, 1.0), (1, 0, 1.0)]);
+
+        // Complex entries keep their real part, mirrored as is in hermitian matrices
+        let (triplets, header) = read("%%MatrixMarket matrix coordinate complex hermitian\n2 2 2\n1 1 1.5 0\n2 1 -2.0 3.0\n").unwrap();
+        assert_eq!(header.field, MmField::Complex);
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 0, 1.5), (0, 1, -2.0), (1, 0, -2.0)]);
+    }
+
+    #[test]
+    fn malformed_input() {
+        let error = |contents: &str| read(contents).unwrap_err();
+
+        assert_eq!(error(""), "file is empty");
+        assert!(error("%%MatrixMarket matrix coordinate real\n1 1 0\n").starts_with("line 1: invalid MatrixMarket banner"));
+        assert!(error("%%MatrixMarket matrix coordinate complex general\n1 1 1\n1 1 1.0\n").starts_with("line 3: missing value"));
+        assert_eq!(error("%%MatrixMarket matrix coordinate real general\n% only comments\n"), "missing size line");
+        assert!(error("%%MatrixMarket matrix coordinate real general\n2 2\n").starts_with("line 2: invalid size line"));
+        assert!(error("%%MatrixMarket matrix coordinate real symmetric\n2 3 0\n").contains("must be square"));
+        assert_eq!(error("%%MatrixMarket matrix coordinate real general\n2 2 1\n3 1 1.0\n"), "line 3: index 3 out of bounds [1, 2]");
+        assert!(error("%%MatrixMarket matrix coordinate real general\n2 2 1\n1 1 x\n").starts_with("line 3: invalid value `x`"));
+        assert!(error("%%MatrixMarket matrix coordinate real general\n2 2 1\n1 1 1.0 2.0\n").starts_with("line 3: unexpected trailing data"));
+        assert_eq!(error("%%MatrixMarket matrix coordinate real general\n2 2 2\n1 1 1.0\n"), "expected 2 entries, found only 1");
+        assert!(error("%%MatrixMarket matrix coordinate real skew-symmetric\n2 2 1\n1 1 1.0\n").contains("cannot store diagonal entries"));
+    }
+}
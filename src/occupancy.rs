@@ -0,0 +1,143 @@
+use sprs::CsMat;
+
+use crate::utils::Piece;
+
+/* OCCUPANCY INDEX
+ *
+ * Search state of the nonzeros of a sparse matrix. Nonzeros are identified by their index in CSR
+ * order, so the covered state is a bitset over those indices and positions are looked up row by row:
+ *
+ *   indptr, indices    CSR structure, to find the index of a (row, col) position
+ *   rows               Row of every nonzero, so positions of an index are known without searching
+ *   covered            Bitset, one bit per nonzero already part of a piece
+ *   uncovered          Indices of the nonzeros not covered yet, in row-major order. It is compacted
+ *                      on demand, so it may still hold nonzeros covered since the last compaction
+ */
+#[derive(Clone)]
+pub struct Occupancy {
+    nrows: usize,
+    ncols: usize,
+    indptr: Vec<usize>,
+    indices: Vec<usize>,
+    rows: Vec<usize>,
+    covered: Vec<u64>,
+    uncovered: Vec<usize>,
+}
+
+impl Occupancy {
+    /// Builds the index from the structure of a CSR matrix, every nonzero being uncovered.
+    pub fn from_csmat<T>(csmat: &CsMat<T>) -> Occupancy {
+        let indptr = csmat.indptr().to_proper().to_vec();
+        let indices = csmat.indices().to_vec();
+        let nnz = indices.len();
+
+        let mut rows: Vec<usize> = Vec::with_capacity(nnz);
+        indptr.windows(2).enumerate().for_each(|(row, ptrs)| rows.extend(std::iter::repeat_n(row, ptrs[1] - ptrs[0])));
+
+        Occupancy {
+            nrows: csmat.rows(),
+            ncols: csmat.cols(),
+            indptr,
+            indices,
+            rows,
+            covered: vec![0; nnz.div_ceil(64)],
+            uncovered: (0..nnz).collect(),
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.nrows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.ncols
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Column indices of the nonzeros in `row`
+    #[inline(always)]
+    pub fn row_indices(&self, row: usize) -> &[usize] {
+        &self.indices[self.indptr[row]..self.indptr[row+1]]
+    }
+
+    /// (row, col) position of the nonzero `idx`
+    #[inline(always)]
+    pub fn position(&self, idx: usize) -> (usize, usize) {
+        (self.rows[idx], self.indices[idx])
+    }
+
+    /// Index of the nonzero at (row, col), if there is one. Out-of-bounds positions have none.
+    #[inline(always)]
+    pub fn index(&self, row: i64, col: i64) -> Option<usize> {
+        if row < 0 || col < 0 || row >= self.nrows as i64 || col >= self.ncols as i64 {
+            return None;
+        }
+
+        let start = self.indptr[row as usize];
+        self.row_indices(row as usize).binary_search(&(col as usize)).ok().map(|pos| start + pos)
+    }
+
+    /// Index of the nonzero found `(i, j)` away from the nonzero `from`, if there is one.
+    ///
+    /// Strides within a row are walked from `from` (at most |j| nonzeros away), any other
+    /// stride is a binary search on the destination row.
+    #[inline(always)]
+    pub fn step(&self, from: usize, (i, j): (i32, i32)) -> Option<usize> {
+        let (row, col) = self.position(from);
+        if i != 0 {
+            return self.index(row as i64 + i as i64, col as i64 + j as i64);
+        }
+
+        let target = col as i64 + j as i64;
+        let (row_start, row_end) = (self.indptr[row], self.indptr[row+1]);
+        let mut idx = from;
+        if j > 0 {
+            while idx + 1 < row_end && (self.indices[idx+1] as i64) <= target {
+                idx += 1;
+            }
+        } else {
+            while idx > row_start && (self.indices[idx-1] as i64) >= target {
+                idx -= 1;
+            }
+        }
+
+        if self.indices[idx] as i64 == target { Some(idx) } else { None }
+    }
+
+    #[inline(always)]
+    pub fn is_covered(&self, idx: usize) -> bool {
+        self.covered[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    #[inline(always)]
+    pub fn cover(&mut self, idx: usize) {
+        self.covered[idx / 64] |= 1 << (idx % 64);
+    }
+
+    /// Indices of the uncovered nonzeros as of the last `compact`, in row-major order
+    pub fn uncovered(&self) -> &[usize] {
+        &self.uncovered
+    }
+
+    /// Drops the nonzeros covered since the last call from the uncovered list.
+    pub fn compact(&mut self) {
+        let covered = &self.covered;
+        self.uncovered.retain(|idx| covered[idx / 64] & (1 << (idx % 64)) == 0);
+    }
+
+    /// Indices of the nonzeros of a piece. The piece must lie on nonzeros.
+    pub fn piece_indices(&self, piece: &Piece) -> Vec<usize> {
+        let &(x, y, (n, i, j)) = piece;
+        let mut indices: Vec<usize> = Vec::with_capacity(n as usize);
+        let mut idx = self.index(x as i64, y as i64).unwrap();
+        indices.push(idx);
+        for _ in 1..n {
+            idx = self.step(idx, (i, j)).unwrap();
+            indices.push(idx);
+        }
+        indices
+    }
+}
@@ -0,0 +1,323 @@
+use std::fmt;
+
+use colored::Colorize;
+use itertools::Itertools;
+use linked_hash_map::LinkedHashMap;
+use linked_hash_set::LinkedHashSet;
+
+use crate::utils::SearchPattern;
+
+/* PATTERN FILE GRAMMAR
+ *
+ *   # comment                   Anything after a `#` is ignored. Blank lines are ignored too
+ *   (N, I, J)                   A single pattern. Spaces are allowed anywhere
+ *   (8..2, -3..3, 0..3)         Ranges are inclusive and may go downwards. They expand to the
+ *                               cartesian product, N being the outermost loop and J the innermost
+ *   (4.., 1, 1)                 Maximal pattern. N is only the minimum length, pieces extend as far
+ *                               as the run of uncovered nonzeros goes. Only allowed on N
+ *   [name]                      Starts a named group. Following patterns belong to it
+ *   @name                       Includes the patterns of a previously defined group
+ *
+ * Patterns before the first group header belong to an unnamed group.
+ */
+
+#[derive(Debug)]
+pub struct PatternParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PatternParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for PatternParseError {}
+
+/// Parses a patterns file into its groups, in file order.
+///
+/// Patterns outside any group are stored under the empty name.
+pub fn parse_patterns(source: &str) -> Result<LinkedHashMap<String, Vec<SearchPattern>>, PatternParseError> {
+    let mut groups: LinkedHashMap<String, Vec<SearchPattern>> = LinkedHashMap::new();
+    let mut curr_group = String::new();
+    groups.insert(curr_group.clone(), vec![]);
+
+    for (idx, raw_line) in source.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = match raw_line.find('#') {
+            Some(pos) => &raw_line[..pos],
+            None => raw_line,
+        };
+
+        let mut parser = LineParser { chars: line.chars().collect(), pos: 0, line: line_no };
+        parser.skip_spaces();
+
+        match parser.peek() {
+            None => continue,
+            Some('[') => {
+                parser.bump();
+                let name = parser.identifier("group name")?;
+                parser.expect(']')?;
+                parser.expect_end()?;
+
+                if groups.contains_key(&name) {
+                    return Err(parser.error_at(1, format!("group `{}` is already defined", name)));
+                }
+                groups.insert(name.clone(), vec![]);
+                curr_group = name;
+            },
+            Some('@') => {
+                parser.bump();
+                let name_col = parser.column();
+                let name = parser.identifier("group name")?;
+                parser.expect_end()?;
+
+                let included = match groups.get(&name) {
+                    Some(patterns) if name != curr_group => patterns.clone(),
+                    Some(_) => return Err(PatternParseError { line: line_no, column: name_col, message: format!("group `{}` cannot include itself", name) }),
+                    None => return Err(PatternParseError { line: line_no, column: name_col, message: format!("unknown group `{}`", name) }),
+                };
+                groups.get_mut(&curr_group).unwrap().extend(included);
+            },
+            Some('(') => {
+                let patterns = parser.pattern()?;
+                parser.expect_end()?;
+                groups.get_mut(&curr_group).unwrap().extend(patterns);
+            },
+            Some(c) => return Err(parser.error(format!("unexpected `{}`, expected `(`, `[`, `@` or a comment", c))),
+        }
+    }
+
+    Ok(groups)
+}
+
+/// Flattens the selected groups (all of them if `selected_groups` is None) into a pattern list,
+/// dropping repeated patterns but keeping the first occurrence order.
+pub fn select_patterns(groups: &LinkedHashMap<String, Vec<SearchPattern>>, selected_groups: Option<&[String]>) -> Result<Vec<SearchPattern>, String> {
+    let selected: Vec<&Vec<SearchPattern>> = match selected_groups {
+        None => groups.values().collect(),
+        Some(names) => names
+            .iter()
+            .map(|name| groups.get(name).ok_or(format!("unknown group `{}`. Defined groups: {}", name, groups.keys().filter(|k| !k.is_empty()).join(", "))))
+            .collect::<Result<_, _>>()?,
+    };
+
+    // Plain insert would move repeated patterns to the back
+    let mut patterns: LinkedHashSet<SearchPattern> = LinkedHashSet::new();
+    selected.into_iter().flatten().for_each(|pattern| { patterns.insert_if_absent(*pattern); });
+
+    Ok(patterns.into_iter().collect())
+}
+
+/// One value or inclusive range inside a pattern tuple
+#[derive(Clone, Copy, Debug)]
+enum Component {
+    Value(i32),
+    Range(i32, i32),
+    // Open range, N.. on maximal patterns
+    From(i32),
+}
+
+impl Component {
+    fn values(&self) -> Vec<i32> {
+        match *self {
+            Component::Value(v) => vec![v],
+            Component::Range(a, b) if a <= b => (a..=b).collect(),
+            Component::Range(a, b) => (b..=a).rev().collect(),
+            Component::From(a) => vec![a],
+        }
+    }
+}
+
+struct LineParser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl LineParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) {
+        self.pos += 1;
+    }
+
+    /// 1-based column of the current position
+    fn column(&self) -> usize {
+        self.pos + 1
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.bump();
+        }
+    }
+
+    fn error(&self, message: String) -> PatternParseError {
+        PatternParseError { line: self.line, column: self.column(), message }
+    }
+
+    fn error_at(&self, column: usize, message: String) -> PatternParseError {
+        PatternParseError { line: self.line, column, message }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), PatternParseError> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(c) if c == expected => { self.bump(); Ok(()) },
+            Some(c) => Err(self.error(format!("expected `{}`, found `{}`", expected, c))),
+            None => Err(self.error(format!("expected `{}`, found end of line", expected))),
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), PatternParseError> {
+        self.skip_spaces();
+        match self.peek() {
+            None => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected `{}` after end of statement", c))),
+        }
+    }
+
+    fn identifier(&mut self, what: &str) -> Result<String, PatternParseError> {
+        self.skip_spaces();
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_alphanumeric() || c == '_' || c == '-') {
+            self.bump();
+        }
+
+        if start == self.pos {
+            return Err(self.error(format!("expected {}", what)));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn integer(&mut self) -> Result<i32, PatternParseError> {
+        self.skip_spaces();
+        let start = self.pos;
+        if matches!(self.peek(), Some('-') | Some('+')) {
+            self.bump();
+        }
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.bump();
+        }
+
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse::<i32>().map_err(|_| {
+            if text.is_empty() || text == "-" || text == "+" {
+                match self.peek() {
+                    Some(c) => self.error_at(start + 1, format!("expected an integer, found `{}`", c)),
+                    None => self.error_at(start + 1, "expected an integer, found end of line".to_string()),
+                }
+            } else {
+                self.error_at(start + 1, format!("invalid integer `{}`", text))
+            }
+        })
+    }
+
+    fn component(&mut self) -> Result<(Component, usize), PatternParseError> {
+        self.skip_spaces();
+        let column = self.column();
+        let first = self.integer()?;
+
+        self.skip_spaces();
+        if self.peek() == Some('.') {
+            self.bump();
+            if self.peek() != Some('.') {
+                return Err(self.error("expected `..` in range".to_string()));
+            }
+            self.bump();
+            self.skip_spaces();
+            if matches!(self.peek(), Some(',') | Some(')')) {
+                return Ok((Component::From(first), column));
+            }
+            let last = self.integer()?;
+            return Ok((Component::Range(first, last), column));
+        }
+
+        Ok((Component::Value(first), column))
+    }
+
+    fn pattern(&mut self) -> Result<Vec<SearchPattern>, PatternParseError> {
+        self.expect('(')?;
+        let (n, n_col) = self.component()?;
+        self.expect(',')?;
+        let (i, i_col) = self.component()?;
+        self.expect(',')?;
+        let (j, ij_col) = self.component()?;
+        self.expect(')')?;
+
+        for (component, col) in [(i, i_col), (j, ij_col)] {
+            if let Component::From(_) = component {
+                return Err(self.error_at(col, "open ranges are only allowed on the pattern length".to_string()));
+            }
+        }
+        let maximal = matches!(n, Component::From(_));
+
+        let n_values = n.values();
+        if let Some(bad_n) = n_values.iter().find(|n| **n < 2) {
+            return Err(self.error_at(n_col, format!(
+                "pattern length {} found! Pattern lengths must be greater than 1.{}",
+                bad_n,
+                if *bad_n == 1 { format!(" Perhaps you want to use the {} flag {}", "experimental".red().bold(), "--write-uninc-as-patterns".yellow().bold()) } else { "".to_string() }
+            )));
+        }
+
+        let mut patterns: Vec<SearchPattern> = vec![];
+        for n in n_values.iter() {
+            for i in i.values() {
+                for j in j.values() {
+                    // Ranges may go through the zero stride, which is just skipped
+                    if i == 0 && j == 0 {
+                        continue;
+                    }
+                    patterns.push(if maximal { SearchPattern::Maximal((*n, i, j)) } else { SearchPattern::Fixed((*n, i, j)) });
+                }
+            }
+        }
+
+        if patterns.is_empty() {
+            return Err(self.error_at(ij_col, "stride (0,0) does not describe a pattern".to_string()));
+        }
+
+        Ok(patterns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_ranges_and_includes() {
+        let source = "(2, 1, 0)  # ungrouped\n\n[rows]\n(3..2, 0, 1..2)\n[all]\n(4.., 1, 1)\n@rows\n";
+        let groups = parse_patterns(source).unwrap();
+
+        assert_eq!(groups.keys().cloned().collect::<Vec<_>>(), vec!["", "rows", "all"]);
+        assert_eq!(groups[""], vec![SearchPattern::Fixed((2, 1, 0))]);
+        assert_eq!(groups["rows"], vec![
+            SearchPattern::Fixed((3, 0, 1)), SearchPattern::Fixed((3, 0, 2)),
+            SearchPattern::Fixed((2, 0, 1)), SearchPattern::Fixed((2, 0, 2)),
+        ]);
+        assert_eq!(groups["all"][0], SearchPattern::Maximal((4, 1, 1)));
+        assert_eq!(groups["all"].len(), 5);
+
+        let selected = select_patterns(&groups, Some(&["all".to_string(), "rows".to_string()])).unwrap();
+        assert_eq!(selected.len(), 5);
+        assert!(select_patterns(&groups, Some(&["cols".to_string()])).is_err());
+    }
+
+    #[test]
+    fn errors_name_line_and_column() {
+        let error = parse_patterns("(2, 1, 0)\n  (2, 1 0)\n").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert!(error.column > 1);
+
+        assert_eq!(parse_patterns("@rows\n").unwrap_err().line, 1);
+        assert_eq!(parse_patterns("[rows]\n[rows]\n").unwrap_err().line, 2);
+        assert!(parse_patterns("(2, 1.., 0)\n").is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use sprs::TriMat;
+
+use crate::mmio::MmSymmetry;
+
+/* RUTHERFORD-BOEING HEADER */
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub struct RbHeader {
+    pub title: String,
+    pub key: String,
+    /// Three-letter matrix type, e.g. `rua`, `rsa` or `psa`
+    pub mxtype: String,
+    pub symmetry: MmSymmetry,
+}
+
+/// Reads an assembled Rutherford-Boeing (or Harwell-Boeing) file into a f64 triplet matrix,
+/// expanding any symmetric storage so the result always holds the full matrix.
+///
+/// Value types `r`, `i`, `c`, `p` and `q` are accepted (complex values keep their real part,
+/// pattern matrices are read as 1.0) along with `s`, `u`, `h`, `z` and `r` symmetries.
+/// Elemental (`e`) matrices are rejected.
+pub fn read_rutherford_boeing_triplets(path: &str) -> Result<(TriMat<f64>, RbHeader), String> {
+    let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+    read_rutherford_boeing(BufReader::new(file))
+}
+
+/// Same as `read_rutherford_boeing_triplets`, from any reader
+fn read_rutherford_boeing(reader: impl BufRead) -> Result<(TriMat<f64>, RbHeader), String> {
+    let mut lines = reader.lines().enumerate().map(|(idx, line)| (idx+1, line));
+
+    let mut next_line = |what: &str| -> Result<(usize, String), String> {
+        match lines.next() {
+            Some((line_no, Ok(line))) => Ok((line_no, line)),
+            Some((line_no, Err(e))) => Err(format!("line {}: {}", line_no, e)),
+            None => Err(format!("unexpected end of file while reading {}", what)),
+        }
+    };
+
+    // Line 1: title (A72) and key (A8)
+    let (_, line) = next_line("title")?;
+    let title = line.chars().take(72).collect::<String>().trim().to_string();
+    let key = line.chars().skip(72).collect::<String>().trim().to_string();
+
+    // Line 2: totcrd ptrcrd indcrd valcrd [rhscrd], the last one only in Harwell-Boeing files
+    let (line_no, line) = next_line("card counts")?;
+    let cards = parse_ints(&line, line_no)?;
+    if cards.len() < 4 {
+        return Err(format!("line {}: expected at least 4 card counts, found {}", line_no, cards.len()));
+    }
+    let rhscrd = cards.get(4).copied().unwrap_or(0);
+
+    // Line 3: mxtype (A3) nrow ncol nnzero neltvl
+    let (line_no, line) = next_line("matrix type")?;
+    let mxtype = line.chars().take(3).collect::<String>().to_ascii_lowercase();
+    let (value_type, symmetry_type, storage_type) = match mxtype.chars().collect::<Vec<char>>()[..] {
+        [value_type, symmetry_type, storage_type] if mxtype.is_ascii() => (value_type, symmetry_type, storage_type),
+        _ => return Err(format!("line {}: invalid matrix type `{}`, expected three letters", line_no, mxtype)),
+    };
+
+    let sizes = parse_ints(&line[3..], line_no)?;
+    if sizes.len() < 3 {
+        return Err(format!("line {}: expected nrow, ncol and nnzero, found `{}`", line_no, line.trim()));
+    }
+    let (nrows, ncols, nnzero) = (sizes[0], sizes[1], sizes[2]);
+
+    let symmetry = match symmetry_type {
+        'u' | 'r' => MmSymmetry::General,
+        's' => MmSymmetry::Symmetric,
+        'z' => MmSymmetry::SkewSymmetric,
+        'h' => MmSymmetry::Hermitian,
+        other => return Err(format!("line {}: unsupported symmetry `{}` in matrix type `{}`", line_no, other, mxtype)),
+    };
+
+    if storage_type != 'a' {
+        return Err(format!("line {}: only assembled matrices are supported, found matrix type `{}`", line_no, mxtype));
+    }
+
+    let values_per_entry = match value_type {
+        'r' | 'i' => 1,
+        'c' => 2,
+        // Values of `q` matrices are supplied separately, so they are read as patterns too
+        'p' | 'q' => 0,
+        other => return Err(format!("line {}: unsupported value type `{}` in matrix type `{}`", line_no, other, mxtype)),
+    };
+
+    if symmetry != MmSymmetry::General && nrows != ncols {
+        return Err(format!("line {}: {:?} matrices must be square, found {}x{}", line_no, symmetry, nrows, ncols));
+    }
+
+    // Line 4: ptrfmt (A16) indfmt (A16) valfmt (A20)
+    let (line_no, line) = next_line("formats")?;
+    let ptr_fmt = parse_fortran_format(&line.chars().take(16).collect::<String>(), line_no)?;
+    let ind_fmt = parse_fortran_format(&line.chars().skip(16).take(16).collect::<String>(), line_no)?;
+    let val_fmt = if values_per_entry > 0 {
+        Some(parse_fortran_format(&line.chars().skip(32).collect::<String>(), line_no)?)
+    } else {
+        None
+    };
+
+    // Line 5 only exists on Harwell-Boeing files carrying right hand sides
+    if rhscrd > 0 {
+        next_line("right hand side format")?;
+    }
+
+    // Column pointers, row indices and values, all of them 1-based
+    let colptr = read_fixed_width(&mut next_line, ncols + 1, &ptr_fmt, "column pointers")?
+        .iter()
+        .map(|tok| tok.parse::<usize>().map_err(|e| format!("invalid column pointer `{}`: {}", tok, e)))
+        .collect::<Result<Vec<usize>, String>>()?;
+    let rowind = read_fixed_width(&mut next_line, nnzero, &ind_fmt, "row indices")?
+        .iter()
+        .map(|tok| tok.parse::<usize>().map_err(|e| format!("invalid row index `{}`: {}", tok, e)))
+        .collect::<Result<Vec<usize>, String>>()?;
+    let values = match &val_fmt {
+        Some(fmt) => read_fixed_width(&mut next_line, nnzero * values_per_entry, fmt, "values")?
+            .iter()
+            .map(|tok| parse_fortran_real(tok))
+            .collect::<Result<Vec<f64>, String>>()?,
+        None => vec![],
+    };
+
+    if colptr[0] != 1 || colptr[ncols] != nnzero + 1 || colptr.windows(2).any(|w| w[0] > w[1]) {
+        return Err(format!("column pointers are not a valid 1-based CSC pointer array for {} entries", nnzero));
+    }
+
+    let capacity = if symmetry == MmSymmetry::General { nnzero } else { 2 * nnzero };
+    let mut triplets: TriMat<f64> = TriMat::with_capacity((nrows, ncols), capacity);
+
+    for col in 0..ncols {
+        for idx in (colptr[col]-1)..(colptr[col+1]-1) {
+            let row = match rowind[idx] {
+                r if r >= 1 && r <= nrows => r - 1,
+                r => return Err(format!("row index {} of entry {} out of bounds [1, {}]", r, idx+1, nrows)),
+            };
+            // Complex values keep their real part only
+            let value = if values_per_entry > 0 { values[idx * values_per_entry] } else { 1.0 };
+
+            triplets.add_triplet(row, col, value);
+
+            if symmetry != MmSymmetry::General && row != col {
+                match symmetry {
+                    MmSymmetry::SkewSymmetric => triplets.add_triplet(col, row, -value),
+                    _ => triplets.add_triplet(col, row, value),
+                }
+            }
+        }
+    }
+
+    Ok((triplets, RbHeader { title, key, mxtype, symmetry }))
+}
+
+/// Returns true for file extensions used by Rutherford-Boeing and Harwell-Boeing files.
+pub fn is_rutherford_boeing_path(path: &str) -> bool {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    // Matrix type codes are commonly used as extension (rua, rsa, psa...)
+    matches!(extension.as_str(), "rb" | "hb")
+        || (extension.len() == 3 && {
+            let mut c = extension.chars();
+            matches!(c.next(), Some('r' | 'c' | 'i' | 'p' | 'q'))
+                && matches!(c.next(), Some('u' | 's' | 'h' | 'z' | 'r'))
+                && matches!(c.next(), Some('a'))
+        })
+}
+
+/// Fortran edit descriptor: number of fields per line and width of each field.
+struct FortranFormat {
+    per_line: usize,
+    width: usize,
+}
+
+fn parse_fortran_format(fmt: &str, line_no: usize) -> Result<FortranFormat, String> {
+    // Accepts things like (10I8), (3E26.18), (1P,4D20.12) or (1P5E16.8)
+    let cleaned = fmt.trim().to_lowercase().replace([' ', '(', ')'], "");
+
+    // Drop the scale factor, it does not change values that carry an exponent
+    let descriptor = match cleaned.find('p') {
+        Some(pos) => cleaned[pos+1..].trim_start_matches(','),
+        None => cleaned.as_str(),
+    };
+
+    let letter_pos = descriptor.find(|c: char| c.is_ascii_alphabetic())
+        .ok_or(format!("line {}: invalid Fortran format `{}`", line_no, fmt.trim()))?;
+    let per_line = match &descriptor[..letter_pos] {
+        "" => 1,
+        count => count.parse::<usize>().map_err(|_| format!("line {}: invalid repeat count in Fortran format `{}`", line_no, fmt.trim()))?,
+    };
+
+    if !matches!(&descriptor[letter_pos..letter_pos+1], "i" | "e" | "d" | "f" | "g") {
+        return Err(format!("line {}: unsupported edit descriptor in Fortran format `{}`", line_no, fmt.trim()));
+    }
+
+    let width = descriptor[letter_pos+1..]
+        .split('.')
+        .next()
+        .and_then(|w| w.parse::<usize>().ok())
+        .filter(|w| *w > 0)
+        .ok_or(format!("line {}: invalid field width in Fortran format `{}`", line_no, fmt.trim()))?;
+
+    Ok(FortranFormat { per_line, width })
+}
+
+/// Reads `count` fixed-width fields laid out as `fmt` says, returning them trimmed.
+fn read_fixed_width(next_line: &mut impl FnMut(&str) -> Result<(usize, String), String>, count: usize, fmt: &FortranFormat, what: &str) -> Result<Vec<String>, String> {
+    let mut fields: Vec<String> = Vec::with_capacity(count);
+
+    while fields.len() < count {
+        let (_, line) = next_line(what)?;
+        let chars = line.trim_end().chars().collect::<Vec<char>>();
+
+        for chunk in chars.chunks(fmt.width).take(fmt.per_line) {
+            let field = chunk.iter().collect::<String>();
+            let field = field.trim();
+            if field.is_empty() { continue; }
+
+            fields.push(field.to_string());
+            if fields.len() == count { break; }
+        }
+    }
+
+    Ok(fields)
+}
+
+#[inline(always)]
+fn parse_fortran_real(token: &str) -> Result<f64, String> {
+    let normalized = token.replace(['d', 'D'], "e");
+
+    match normalized.parse::<f64>() {
+        Ok(val) => Ok(val),
+        Err(_) => {
+            // Fortran may drop the exponent letter when the exponent has three digits, e.g. 1.0-100
+            match normalized.rfind(['+', '-']) {
+                Some(pos) if pos > 0 && !normalized[..pos].ends_with(['e', 'E']) => {
+                    format!("{}e{}", &normalized[..pos], &normalized[pos..]).parse::<f64>()
+                        .map_err(|e| format!("invalid value `{}`: {}", token, e))
+                },
+                _ => Err(format!("invalid value `{}`", token)),
+            }
+        },
+    }
+}
+
+#[inline(always)]
+fn parse_ints(line: &str, line_no: usize) -> Result<Vec<usize>, String> {
+    line.split_whitespace()
+        .map(|tok| tok.parse::<usize>().map_err(|e| format!("line {}: invalid integer `{}`: {}", line_no, tok, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::entries;
+
+    /// Rutherford-Boeing file of an `nrows` x `ncols` matrix of type `mxtype`, with four fields per line
+    fn rb_file(mxtype: &str, (nrows, ncols): (usize, usize), colptr: &[usize], rowind: &[usize], values: &[&str]) -> String {
+        let card = |fields: Vec<String>| fields.chunks(4).map(|chunk| chunk.concat()).collect::<Vec<String>>();
+        let ptr_cards = card(colptr.iter().map(|v| format!("{:4}", v)).collect());
+        let ind_cards = card(rowind.iter().map(|v| format!("{:4}", v)).collect());
+        let val_cards = card(values.iter().map(|v| format!("{:>12}", v)).collect());
+
+        let mut lines = vec![
+            format!("{:<72}{:<8}", "Test matrix", "TESTKEY"),
+            format!("{:14}{:14}{:14}{:14}", ptr_cards.len() + ind_cards.len() + val_cards.len(), ptr_cards.len(), ind_cards.len(), val_cards.len()),
+            format!("{:<3}{:>25}{:14}{:14}{:14}", mxtype, nrows, ncols, rowind.len(), 0),
+            format!("{:<16}{:<16}{:<20}", "(4I4)", "(4I4)", "(4E12.4)"),
+        ];
+        lines.extend(ptr_cards);
+        lines.extend(ind_cards);
+        lines.extend(val_cards);
+        lines.join("\n") + "\n"
+    }
+
+    fn read(contents: &str) -> Result<(TriMat<f64>, RbHeader), String> {
+        read_rutherford_boeing(contents.as_bytes())
+    }
+
+    #[test]
+    fn header_and_sizes() {
+        let contents = rb_file("rua", (3, 4), &[1, 3, 4, 4, 5], &[1, 3, 2, 3], &["1.0000E+00", "2.5000D-01", "-3.0000E+00", "4.0000+001"]);
+        let (triplets, header) = read(&contents).unwrap();
+
+        assert_eq!((header.title.as_str(), header.key.as_str(), header.mxtype.as_str(), header.symmetry), ("Test matrix", "TESTKEY", "rua", MmSymmetry::General));
+        assert_eq!(triplets.shape(), (3, 4));
+        // Fortran exponents: D and a dropped exponent letter
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 0, 1.0), (1, 1, -3.0), (2, 0, 0.25), (2, 3, 40.0)]);
+    }
+
+    #[test]
+    fn symmetric_pattern_and_complex_types() {
+        let contents = rb_file("rsa", (2, 2), &[1, 3, 4], &[1, 2, 2], &["1.0000E+00", "2.0000E+00", "3.0000E+00"]);
+        let (triplets, header) = read(&contents).unwrap();
+        assert_eq!(header.symmetry, MmSymmetry::Symmetric);
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 0, 1.0), (0, 1, 2.0), (1, 0, 2.0), (1, 1, 3.0)]);
+
+        let contents = rb_file("pza", (2, 2), &[1, 2, 2], &[2], &[]);
+        let (triplets, _) = read(&contents).unwrap();
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 1, -1.0), (1, 0, 1.0)]);
+
+        // Complex values keep their real part
+        let contents = rb_file("cua", (2, 2), &[1, 2, 3], &[1, 2], &["1.5", "9.0", "-2.0", "9.0"]);
+        let (triplets, _) = read(&contents).unwrap();
+        assert_eq!(entries(&triplets.to_csr()), vec![(0, 0, 1.5), (1, 1, -2.0)]);
+    }
+
+    #[test]
+    fn malformed_input() {
+        let error = |contents: &str| read(contents).unwrap_err();
+
+        assert!(error(&rb_file("rue", (1, 1), &[1, 2], &[1], &["1.0"])).contains("only assembled matrices are supported"));
+        assert!(error(&rb_file("éua", (1, 1), &[1, 2], &[1], &["1.0"])).starts_with("line 3: invalid matrix type `éua`"));
+        assert_eq!(error("Title\n1 1 1 1\nr\n"), "line 3: invalid matrix type `r`, expected three letters");
+        assert!(error(&rb_file("rsa", (2, 3), &[1, 2, 2, 2], &[1], &["1.0"])).contains("must be square"));
+        assert!(error(&rb_file("rua", (2, 2), &[1, 3, 2], &[1], &["1.0"])).starts_with("column pointers are not a valid"));
+        assert_eq!(error(&rb_file("rua", (2, 2), &[1, 2, 2], &[3], &["1.0"])), "row index 3 of entry 1 out of bounds [1, 2]");
+        assert!(error(&rb_file("rua", (2, 2), &[1, 2, 2], &[1], &["x"])).starts_with("invalid value `x`"));
+
+        let truncated = rb_file("rua", (2, 2), &[1, 2, 3], &[1, 2], &["1.0", "2.0"]);
+        let truncated = truncated.lines().take(5).collect::<Vec<_>>().join("\n");
+        assert_eq!(error(&truncated), "unexpected end of file while reading row indices");
+    }
+
+    #[test]
+    fn extensions() {
+        ["a.rb", "a.HB", "a.rua", "a.psa", "a.rza", "dir.mtx/a.csa"].iter().for_each(|path| assert!(is_rutherford_boeing_path(path), "{}", path));
+        ["a.mtx", "a.rue", "a", "a.rb.gz"].iter().for_each(|path| assert!(!is_rutherford_boeing_path(path), "{}", path));
+    }
+}
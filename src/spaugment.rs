@@ -1,10 +1,49 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
 use colored::Colorize;
-use itertools::{Itertools, enumerate};
+use itertools::Itertools;
 use linked_hash_map::LinkedHashMap;
 use sprs::{CsMat, TriMat};
 
-use crate::utils::{Pattern,Piece,OriginUwc,MetaPattern,MetaPatternPiece};
+use crate::utils::{Piece,OriginUwc,MetaPattern,MetaPatternPiece};
 use crate::utils::orig_uwc_to_piece_1d;
+use crate::augmentcost::{AugmentCostModel, AugmentPromotion};
+
+/// Directions along which pieces may be repeated when augmenting dimensionality
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AugmentDirection {
+    /// Any stride
+    Any,
+    /// Only strides between rows, (i, 0)
+    RowWise,
+    /// Only strides between columns, (0, j)
+    ColWise,
+}
+
+/// Bounds on the strides between repeated pieces when augmenting dimensionality. The absolute values of the row and
+/// column components are bounded separately, and only the components allowed by `direction` are bounded at all
+#[derive(Clone, Copy, Debug)]
+pub struct AugmentStrideBounds {
+    pub min_row_stride: usize,
+    pub max_row_stride: usize,
+    pub min_col_stride: usize,
+    pub max_col_stride: usize,
+    pub direction: AugmentDirection,
+}
+
+impl AugmentStrideBounds {
+    fn contains(&self, (row_stride, col_stride): (i32, i32)) -> bool {
+        let row_in_bounds = (self.min_row_stride..=self.max_row_stride).contains(&(row_stride.unsigned_abs() as usize));
+        let col_in_bounds = (self.min_col_stride..=self.max_col_stride).contains(&(col_stride.unsigned_abs() as usize));
+
+        match self.direction {
+            AugmentDirection::Any => row_in_bounds && col_in_bounds,
+            AugmentDirection::RowWise => col_stride == 0 && row_in_bounds,
+            AugmentDirection::ColWise => row_stride == 0 && col_in_bounds,
+        }
+    }
+}
 
 #[allow(dead_code)]
 pub struct SpAugment {
@@ -12,9 +51,18 @@ pub struct SpAugment {
     pub ncols: usize,
     pub nnz: usize,
     meta_patterns: LinkedHashMap<i32, MetaPattern>,
-    meta_pattern_pieces: LinkedHashMap<MetaPatternPiece, i32>
+    meta_pattern_pieces: LinkedHashMap<MetaPatternPiece, i32>,
+    /// Every group of pieces found augmenting dimensionality, and whether it was promoted
+    promotions: Vec<(AugmentPromotion, bool)>,
+    /// Groups that were not promoted
+    rejected_groups: HashSet<PieceGroup>,
+    /// Print every dimension, metapattern, group and piece found while augmenting
+    pub verbose: bool
 }
 
+/// Metapattern of a group of pieces, their repetition (n,i,j) and their origins
+type PieceGroup = (i32, (i32, i32, i32), Vec<MetaPatternPiece>);
+
 impl SpAugment {
     pub fn from_1d_origin_uwc_list(origin_uwc_list: Vec<(OriginUwc, i32)>, nrows: usize, ncols: usize, nnz: usize) -> Self {
 
@@ -50,88 +98,85 @@ impl SpAugment {
             ncols: ncols,
             nnz: nnz,
             meta_patterns: meta_patterns,
-            meta_pattern_pieces: meta_pattern_pieces
+            meta_pattern_pieces: meta_pattern_pieces,
+            promotions: vec![],
+            rejected_groups: HashSet::new(),
+            verbose: false
         }
     }
 
-    pub fn augment_dimensionality(&mut self, target_dim: usize, piece_cutoff: usize, min_stride: usize, max_stride: usize) {
+    /// Starts from metapatterns of any order, such as the shapes of a UZP file (see `UZPGen::from_uzp_file`), so they can
+    /// be augmented further. Pieces with id -1 are single nonzeros, which are only augmented by `augment_singletons`.
+    pub fn from_metapatterns(meta_patterns: LinkedHashMap<i32, MetaPattern>, meta_pattern_pieces: LinkedHashMap<MetaPatternPiece, i32>, nrows: usize, ncols: usize, nnz: usize) -> Self {
+        SpAugment {
+            nrows,
+            ncols,
+            nnz,
+            meta_patterns,
+            meta_pattern_pieces,
+            promotions: vec![],
+            rejected_groups: HashSet::new(),
+            verbose: false
+        }
+    }
+
+    /// Groups of pieces are only promoted to higher order shapes if `cost_model` says so, or always without one. The
+    /// pieces of the rest stay as they are
+    pub fn augment_dimensionality(&mut self, target_dim: usize, piece_cutoff: usize, stride_bounds: AugmentStrideBounds, cost_model: Option<&dyn AugmentCostModel>) {
 
         if piece_cutoff < 2 {
             panic!("\n{} How are you supposed to make length={} pieces?", "[spaugment]".red().bold(), piece_cutoff);
         }
 
-        // DEBUG UNCOMMENT
-        println!("\n------- AUGMENT DIMENSIONALITY -------\n");
-
-        let single_compensation: i64 = match self.meta_patterns.get(&-1) {
-            Some(_) => -1i64,
-            None => 0i64
-        };
-        // DEBUG UNCOMMENT
-        // println!("Compensation = {} (this accounts for elements with id=-1)", single_compensation);
-
-        // PRECONDITION: Metapatterns are not necessarily ordered, but same metapatterns are consecutive in the list
+        if self.verbose {
+            println!("\n------- AUGMENT DIMENSIONALITY -------\n");
+        }
 
-        // FIXME Maybe generalize this to support any-dimensional input
-        for curr_dim in 2..=target_dim {
-            // DEBUG UNCOMMENT
-            // println!("Searching for {}D", curr_dim);
+        // Metapatterns may already be of any order (e.g. read back from a UZP file), so augmentation goes on from the highest one
+        let start_dim = self.meta_pattern_pieces
+            .values()
+            .filter(|id| **id != -1)
+            .map(|id| self.meta_patterns.get(id).unwrap().1 as usize)
+            .max()
+            .unwrap_or(1);
+
+        for curr_dim in (start_dim + 1)..=target_dim {
+            if self.verbose {
+                println!("\n------- SEARCHING FOR {}D -------", curr_dim);
+            }
 
-            // Aux variables for processing
-            let mut origins_list: Vec<(i32, i32)> = vec![];
-            let mut curr_id: i32 = 0; // Zero here to save an iteration (only if meta_pattern_pieces is sorted). Not really needed apart from that
-            
-            // Advance pointer to current dimensionality pieces
-            let mut metapat_pieces_iter = self.meta_pattern_pieces.iter()
-                .filter(|(_,id)| **id != -1);
+            // Origins of the pieces of every metapattern, in order of first appearance. Single nonzeros (id = -1) are left to augment_singletons
+            let mut origins_lists: LinkedHashMap<i32, Vec<(i32, i32)>> = LinkedHashMap::new();
+            self.meta_pattern_pieces.iter()
+                .filter(|(_,id)| **id != -1)
+                .for_each(|((x,y),id)| origins_lists.entry(*id).or_default().push((*x as i32, *y as i32)));
 
-            let mut start_id = (self.meta_patterns.len() as i64 + single_compensation) as i32;
+            // New ids go after every existing one, which need not be consecutive
+            let mut start_id = self.meta_patterns.keys().max().map_or(0, |id| (id + 1).max(0));
             let curr_dim_start_id = start_id;
 
             let mut new_metapats: LinkedHashMap<i32, MetaPattern> = LinkedHashMap::new();
             let mut new_metapat_pieces: LinkedHashMap<MetaPatternPiece, i32> = LinkedHashMap::new();
 
-            // This loop loads all consecutive metapatterns with the same id into origins_list, then executes the "if opt.is_none() [...]"
-            loop {
-                let opt = metapat_pieces_iter.next();
-                
-                if opt.is_none() || matches!(opt, Some((_,id)) if curr_id != *id) {
-                    // DEBUG UNCOMMENT
+            for (curr_id, mut origins_list) in origins_lists {
+                if self.verbose {
                     println!("\n------- compute_metapatterns for id = {} -------", curr_id);
+                }
 
-                    // Compute metapatterns FIXME parametrize max and min strides
-                    match compute_metapatterns(&mut origins_list, piece_cutoff, start_id, curr_id, min_stride, max_stride) {
-                        Some((l_new_metapats, l_new_metapat_pieces)) => {
-                            start_id += l_new_metapats.len() as i32;
-
-                            // Extend new metapats
-                            new_metapats.extend(l_new_metapats);
-                            new_metapat_pieces.extend(l_new_metapat_pieces);
-                        },
-                        None => {},
-                    }
-
-                    // And prepare for next batch
-                    origins_list.clear();
-
-                    // Breaking if end was reached
-                    if opt.is_none() { break; }
+                if let Some((l_new_metapats, l_new_metapat_pieces)) = compute_metapatterns(&mut origins_list, piece_cutoff, start_id, curr_id, &stride_bounds, self.verbose) {
+                    start_id += l_new_metapats.len() as i32;
+                    let (l_new_metapats, l_new_metapat_pieces) = self.promote_groups(curr_id, self.shape_lengths(curr_id), l_new_metapats, l_new_metapat_pieces, cost_model);
 
-                    // Else update curr_id
-                    let (_,id) = opt.unwrap();
-                    curr_id = *id;
+                    // Extend new metapats
+                    new_metapats.extend(l_new_metapats);
+                    new_metapat_pieces.extend(l_new_metapat_pieces);
                 }
-
-                // Append consecutive same-id metapattern pieces to origins_list
-                let ((x,y),_) = opt.unwrap();
-                origins_list.push((*x as i32, *y as i32));
             }
 
             // DEBUG UNCOMMENT
             // println!("Startptr: --. Curr_id: {:?}", curr_id);
 
             // Add new_metapat_pieces and new_metapats to current ones
-            let new_metapats_len = new_metapats.len() as i32;
             self.meta_patterns.extend(new_metapats);
             self.meta_pattern_pieces.extend(new_metapat_pieces);
 
@@ -139,22 +184,26 @@ impl SpAugment {
             // last-d pieces contained in higher order pieces.
             // As we are traversing high order pieces, we now rewrite *order* value.
             let pieces = self.meta_pattern_pieces.iter()
-                .filter(|(_,val)| (**val >= curr_dim_start_id) && (**val < (curr_dim_start_id+new_metapats_len)))
+                .filter(|(_,val)| (**val >= curr_dim_start_id) && (**val < start_id))
                 .map(|((x,y),id)| ((*x,*y), *id))
                 .collect::<Vec<_>>();
 
-            // DEBUG UNCOMMENT
-            println!(" ------ INVALIDATE AND UPDATE ORDER ------ ");
+            if self.verbose {
+                println!(" ------ INVALIDATE AND UPDATE ORDER ------ ");
+            }
             for ((orig_x, orig_y), low_order_id) in pieces {
-                println!(" - {:?}: {}", (orig_x, orig_y), low_order_id);
+                if self.verbose {
+                    println!(" - {:?}: {}", (orig_x, orig_y), low_order_id);
+                }
 
                 // get pattern
                 let mp = self.meta_patterns.get(&low_order_id).unwrap();
 
                 let (n,i,j) = mp.0;
 
-                // Update dimensionality of current metapattern
-                self.meta_patterns.get_mut(&low_order_id).unwrap().1 = curr_dim as i32;
+                // Update dimensionality of current metapattern. Pieces of any lower order may be repeated, not only those of the last one
+                let sub_order = self.meta_patterns.get(&mp.2.unwrap()).unwrap().1;
+                self.meta_patterns.get_mut(&low_order_id).unwrap().1 = sub_order + 1;
 
                 // Skip the first one as it is already updated (hashmap properties)
                 for ii in 1..n {
@@ -171,6 +220,96 @@ impl SpAugment {
         } // for dims
     }
 
+    /// Finds single nonzeros (id = -1) regularly spaced along a stride, as if they were pieces of a base pattern of
+    /// length 1, and turns every group worth it into a 1D lattice shape of its own. Goes before `augment_dimensionality`,
+    /// which may then augment those lattices like any other 1D metapattern. The rest stay single nonzeros
+    pub fn augment_singletons(&mut self, piece_cutoff: usize, stride_bounds: &AugmentStrideBounds, cost_model: Option<&dyn AugmentCostModel>) {
+
+        if piece_cutoff < 2 {
+            panic!("\n{} How are you supposed to make length={} pieces?", "[spaugment]".red().bold(), piece_cutoff);
+        }
+
+        // DEBUG UNCOMMENT
+        // println!("\n------- AUGMENT SINGLE NONZEROS -------\n");
+
+        let mut origins_list: Vec<(i32, i32)> = self.meta_pattern_pieces.iter()
+            .filter(|(_,id)| **id == -1)
+            .map(|((x,y),_)| (*x as i32, *y as i32))
+            .collect();
+
+        let start_id = self.meta_patterns.keys().max().map_or(0, |id| (id + 1).max(0));
+        let Some((new_metapats, new_metapat_pieces)) = compute_metapatterns(&mut origins_list, piece_cutoff, start_id, -1, stride_bounds, self.verbose) else { return; };
+        let (new_metapats, new_metapat_pieces) = self.promote_groups(-1, vec![], new_metapats, new_metapat_pieces, cost_model);
+
+        // Single nonzeros have no shape, so their lattices are base 1D metapatterns
+        self.meta_patterns.extend(new_metapats.into_iter().map(|(id, (repetition, _, _))| (id, (repetition, 1, None))));
+
+        // DEBUG UNCOMMENT
+        // println!(" ------ INVALIDATE SINGLE NONZEROS ------ ");
+        for ((orig_x, orig_y), id) in new_metapat_pieces {
+            // println!(" - {:?}: {}", (orig_x, orig_y), id);
+
+            let ((n,i,j), _, _) = *self.meta_patterns.get(&id).unwrap();
+            self.meta_pattern_pieces.insert((orig_x, orig_y), id);
+            for ii in 1..n {
+                self.meta_pattern_pieces.remove(
+                    &((orig_x as i64 + (i as i64 * ii as i64)) as usize, (orig_y as i64 + (j as i64 * ii as i64)) as usize)
+                );
+            }
+        }
+    }
+
+    /// Keeps the groups of pieces of metapattern `sub_id`, of axes of `sub_lengths`, found by `compute_metapatterns`
+    /// that `cost_model` promotes (all of them without one), and records every group as a promotion
+    fn promote_groups(&mut self, sub_id: i32, sub_lengths: Vec<usize>, mut new_metapats: LinkedHashMap<i32, MetaPattern>, new_metapat_pieces: LinkedHashMap<MetaPatternPiece, i32>, cost_model: Option<&dyn AugmentCostModel>) -> (LinkedHashMap<i32, MetaPattern>, LinkedHashMap<MetaPatternPiece, i32>) {
+        let mut group_origins: HashMap<i32, Vec<MetaPatternPiece>> = HashMap::new();
+        new_metapat_pieces.iter().for_each(|(origin, id)| group_origins.entry(*id).or_default().push(*origin));
+
+        let mut rejected: HashSet<i32> = HashSet::new();
+        for (id, (repetition, _, _)) in new_metapats.iter() {
+            let group = (sub_id, *repetition, group_origins.remove(id).unwrap());
+            let promotion = AugmentPromotion { id: *id, sub_id, repetition: *repetition, sub_lengths: sub_lengths.clone(), pieces: group.2.len() };
+            if cost_model.is_none_or(|cost_model| cost_model.promotes(&promotion)) {
+                self.promotions.push((promotion, true));
+                continue;
+            }
+
+            // Its pieces are left as they are, so augmenting further may find the same group again
+            rejected.insert(*id);
+            if self.rejected_groups.insert(group) {
+                if self.verbose {
+                    println!("  -> Keep {} pieces of id={} as they are, as promoting them to id={} does not lower the cost", promotion.pieces, sub_id, id);
+                }
+                self.promotions.push((promotion, false));
+            }
+        }
+        rejected.iter().for_each(|id| { new_metapats.remove(id); });
+
+        let new_metapat_pieces = new_metapat_pieces.into_iter().filter(|(_, id)| !rejected.contains(id)).collect();
+        (new_metapats, new_metapat_pieces)
+    }
+
+    /// Nonzeros in pieces of metapatterns, that is, all but the single nonzeros (id = -1)
+    pub fn inc_nnz(&self) -> usize {
+        self.nnz - self.meta_pattern_pieces.values().filter(|id| **id == -1).count()
+    }
+
+    /// Length of every axis of the shape of a metapattern, outermost first
+    fn shape_lengths(&self, id: i32) -> Vec<usize> {
+        let mut lengths = vec![];
+        let mut curr_id = Some(id);
+        while let Some(id) = curr_id {
+            let ((n, _, _), _, sub_id) = self.meta_patterns.get(&id).unwrap();
+            lengths.push(*n as usize);
+            curr_id = *sub_id;
+        }
+        lengths
+    }
+
+    pub fn get_promotions(&self) -> &[(AugmentPromotion, bool)] {
+        &self.promotions
+    }
+
     pub fn get_metapatterns(&self) -> LinkedHashMap<i32, MetaPattern> {
         return self.meta_patterns.clone();
     }
@@ -182,7 +321,7 @@ impl SpAugment {
 
 #[inline(always)]
 #[allow(dead_code)]
-fn compute_metapatterns(origins_list: &mut Vec<(i32, i32)>, piece_cutoff: usize, start_id: i32, low_order_id: i32, min_stride: usize, max_stride: usize) -> Option<(LinkedHashMap<i32, MetaPattern>, LinkedHashMap<MetaPatternPiece, i32>)> {
+fn compute_metapatterns(origins_list: &mut Vec<(i32, i32)>, piece_cutoff: usize, start_id: i32, low_order_id: i32, stride_bounds: &AugmentStrideBounds, verbose: bool) -> Option<(LinkedHashMap<i32, MetaPattern>, LinkedHashMap<MetaPatternPiece, i32>)> {
     // DEBUG UNCOMMENT
     // println!("Metapatterns: {:?}", origins_list);
 
@@ -190,46 +329,43 @@ fn compute_metapatterns(origins_list: &mut Vec<(i32, i32)>, piece_cutoff: usize,
 
     // No feasible higher order metapatterns
     if origin_list_len < piece_cutoff {
-        println!("  -> Skip for pieces from id={} as len = {} < {} = piece cutoff", low_order_id, origin_list_len, piece_cutoff);
+        if verbose {
+            println!("  -> Skip for pieces from id={} as len = {} < {} = piece cutoff", low_order_id, origin_list_len, piece_cutoff);
+        }
         return None;
     }
 
     let mut meta_pattern_list: LinkedHashMap<i32, MetaPattern> = LinkedHashMap::new();
     let mut meta_pattern_piece_list: LinkedHashMap<MetaPatternPiece, i32> = LinkedHashMap::new();
 
-    let fn_tuple_sub = |(x1,y1):(i32,i32),(x2,y2):(i32,i32)| (x1-x2, y1-y2);
-
     let (_,max_col) = *origins_list.iter().max_by_key(|(_,col)| *col).unwrap();
     let (max_row,_) = *origins_list.iter().max_by_key(|(row,_)| *row).unwrap();
 
     // DEBUG UNCOMMENT
     // println!("Max col = {}, Max row = {}", max_col, max_row);
 
-    // Get all strides between pieces
-    let strides = origins_list
+    // Origins in the order the explored matrix is walked (by row, then by column), along with their index in origins_list
+    let sorted_origins: Vec<((i32, i32), usize)> = origins_list
         .iter()
-        .tuple_combinations()
-        .map(|(a,b)| fn_tuple_sub (*b, *a))
-        .filter(|(sx,sy)| {
-            let (absx, absy) = (i32::abs(*sx) as usize, i32::abs(*sy) as usize);
-            absx <= max_stride && absy <= max_stride && absx >= min_stride && absy >= min_stride
-        })
-        .collect::<Vec<(i32,i32)>>();
+        .enumerate()
+        .map(|(idx, origin)| (*origin, idx))
+        .sorted_unstable()
+        .collect();
+
+    let strides = stride_counts(&sorted_origins, stride_bounds);
 
     // DEBUG UNCOMMENT
     // println!("STRIDES: {:?}", strides);
 
+    let num_pairs: u64 = strides.values().map(|(_, pairs)| *pairs as u64).sum::<u64>() / 2;
     let mut occurrences = strides
-        .iter()
-        .into_group_map_by(|x| **x)
         .into_iter()
+        .filter(|(_, (count, _))| *count > 0)
         // Very important to add one to the length of the strides. Each time that a piece is found, one has to be added too.
-        .map(|(k,v)| (k, v.len() as u32 + 1u32))
-        // solve tie on equal reps by prioritizing closer pieces. i64 to avoid OF
-        .sorted_by_key(|((stride_x, stride_y),reps)| std::cmp::Reverse((*reps , ( -((*stride_x) as i64 * (*stride_x) as i64) ) as i64 - ((*stride_y) as i64 *(*stride_y) as i64) as i64 )))
-        .collect::<LinkedHashMap<(i32,i32),u32>>();
-
-    // println!("Strides = {:?}\nOccurrences = {:?}", strides, occurrences);
+        .map(|(k, (count, pairs))| (k, count + 1u32, pairs))
+        // solve tie on equal reps by prioritizing closer pieces, and then by stride so the order does not depend on hashing. i64 to avoid OF
+        .sorted_unstable_by_key(|((stride_x, stride_y), reps, _)| (std::cmp::Reverse(*reps), (*stride_x as i64).pow(2) + (*stride_y as i64).pow(2), (*stride_x, *stride_y)))
+        .collect::<Vec<StrideOccurrences>>();
 
     // DEBUG UNCOMMENT
     // println!("OCCURRENCES: {:?}", occurrences);
@@ -244,81 +380,70 @@ fn compute_metapatterns(origins_list: &mut Vec<(i32, i32)>, piece_cutoff: usize,
     // DEBUG UNCOMMENT
     // println!("Mat = {:?}", expl_matrix);
 
-    let mut best_piece: Piece;
-    let mut found_piece: bool;
-
-    'L1: loop {
-        // Reset best_piece and found_piece
-        best_piece = (0,0,((piece_cutoff-1) as i32,0,0));
-        found_piece = false;
+    // Candidates of the strides in occurrences[batch_start..batch_start + batch_len]
+    let (mut batch_start, mut batch_len, mut candidates, mut bounds) = (0, 0, vec![], vec![]);
 
-        'L2: for (((stride_x, stride_y), n), ((_, _), next_n)) in occurrences.iter().circular_tuple_windows::<((&(i32, i32), &u32), (&(i32, i32), &u32))>() {
-            // println!("  -> Comparing {:?} vs {:?}", n, next_n);
+    // Most repeated pattern first (MRPF). A stride that yields no piece never does again, as explored origins only grow,
+    // so the search goes on from the same stride after every piece instead of starting over
+    for pos in 0..occurrences.len() {
+        let ((stride_x, stride_y), _, pairs) = occurrences[pos];
 
-            // Most repeated pattern first (MRPF)
-            'L3: for (idx, (_,(e_row, e_col))) in enumerate(expl_matrix.iter()){
-                best_piece = (|p1:Piece,p2:Piece| std::cmp::max_by_key(p1, p2, |(_,_,(n,_,_))| *n)) (check_metapattern_reps(&expl_matrix, (e_row,e_col), &(*n as i32 + 1,*stride_x,*stride_y)), best_piece);
-
-                // This is equal to the piece of code above. TODO check speed difference.
-                // let curr_piece = check_metapattern_reps(&expl_matrix, (e_row,e_col), &(*n as i32 + 1,*stride_x,*stride_y));
-                // if curr_piece.2.0 > best_piece.2.0 {
-                //     best_piece = curr_piece;
-                // }
-
-                // FIXME check this now that no LHM reordering is being done
-                // if bp.n >= next_n        or         there are no remaining points to build a piece
-                if best_piece.2.0 as u32 >= *next_n+1 || best_piece.2.0 as usize >= origin_list_len-(idx as usize)-1 {
-                    break 'L3;
-                }
-            } // 'L3
-
-            if best_piece.2.0 >= (piece_cutoff as i32) {
-                /*** APPEND ROUTINE ***/
-                // Get suitable pattern id
-                let pat_id: i32 = match meta_pattern_list.back() {
-                    Some((id,((v_n, v_i, v_j), _, _))) => {
-                        if *v_n == best_piece.2.0 && *v_i == best_piece.2.1 && *v_j == best_piece.2.2 {
-                            *id
-                        } else {
-                            (*id)+1
-                        }
-                    },
-                    None => start_id,
-                };
+        // Pieces have one more origin than pairs along their stride at most
+        if pairs as usize + 1 < piece_cutoff {
+            continue;
+        }
+        if pos >= batch_start + batch_len {
+            batch_start = pos;
+            (batch_len, candidates, bounds) = stride_batch(&sorted_origins, stride_bounds, &occurrences[pos..], piece_cutoff, num_pairs);
+        }
+        let ranks = &candidates[bounds[pos - batch_start]..bounds[pos - batch_start + 1]];
+        let mut runs = StrideRuns::new(&expl_matrix, &sorted_origins, ranks, (stride_x, stride_y));
+
+        loop {
+            let n = occurrences[pos].1;
+            let next_n = occurrences[(pos + 1) % occurrences.len()].1;
+
+            let best_piece = match runs.best_piece(&sorted_origins, n as i32 + 1, next_n, piece_cutoff) {
+                Some(best_piece) => best_piece,
+                None => break,
+            };
+
+            /*** APPEND ROUTINE ***/
+            // Get suitable pattern id
+            let pat_id: i32 = match meta_pattern_list.back() {
+                Some((id,((v_n, v_i, v_j), _, _))) => {
+                    if *v_n == best_piece.2.0 && *v_i == best_piece.2.1 && *v_j == best_piece.2.2 {
+                        *id
+                    } else {
+                        (*id)+1
+                    }
+                },
+                None => start_id,
+            };
 
-                // Insert into metapattern list     n,i,j from best piece.
-                // If they are equal then nothing changes and we save an if statement (2Bbenchmarkd)
-                meta_pattern_list.insert(pat_id, (best_piece.2, 0, Some(low_order_id)));
-                //                                                ^^^ This has to be replaced out of this function
+            // Insert into metapattern list     n,i,j from best piece.
+            // If they are equal then nothing changes and we save an if statement (2Bbenchmarkd)
+            meta_pattern_list.insert(pat_id, (best_piece.2, 0, Some(low_order_id)));
+            //                                                ^^^ This has to be replaced out of this function
 
-                // Insert piece intro metapattern piece list
-                meta_pattern_piece_list.insert((best_piece.0, best_piece.1), pat_id);
+            // Insert piece intro metapattern piece list
+            meta_pattern_piece_list.insert((best_piece.0, best_piece.1), pat_id);
 
+            if verbose {
                 println!("  -> Found piece! {:?}", best_piece);
-                // Set flag for reordering occurrence list
-                found_piece = true;
-                break 'L2;
             }
-        } // 'L2
 
-        if found_piece {
-            /*** FIX OCCURRENCES IN LHM ***/
+            /*** FIX OCCURRENCES ***/
             let (x,y,(n,i,j)) = best_piece;
-            let remainder;
-            {// scoped so it does not interfere
-                let p = occurrences.get_mut(&(i,j)).unwrap();
-                // add one to account for the creation of an aditional vertex
-                remainder = *p - (n as u32) + 1u32;
-                *p = remainder;
-            }
+            // add one to account for the creation of an aditional vertex
+            occurrences[pos].1 = occurrences[pos].1 - (n as u32) + 1u32;
 
             // Set to found (true) members of the new pattern
             for ii in 0..n {
                 let pos_val = expl_matrix.get_mut((x as i64 + (i as i64 * ii as i64)) as usize, (y as i64 + (j as i64 * ii as i64)) as usize).unwrap();
                 *pos_val = true;
             }
-        } else {
-            break 'L1;
+            runs.explore_piece(&expl_matrix, &sorted_origins, &best_piece);
         }
     }
 
@@ -333,32 +458,365 @@ fn compute_metapatterns(origins_list: &mut Vec<(i32, i32)>, piece_cutoff: usize,
     }
 }
 
-#[inline(always)]
-#[allow(dead_code)]
-fn check_metapattern_reps(csmat: &CsMat<bool>, curr_pos: (usize, usize), pattern: &Pattern) -> Piece {
-    let &(max_n,i,j) = pattern;
-    let (x,y) = curr_pos;
-
-    // Discard already dumped patterns without computing bounds first
-    if *csmat.get(x,y).unwrap() {
-        // Could be all zeros, important thing is that N = 0
-        return (x,y,(0,i,j));
-    }
-
-    // We can start on the next pattern (length would be 2 if only the first iteration completes)
-    for ii in 1..max_n {
-        let position = csmat.get((x as i64 + (i as i64 * ii as i64)) as usize, (y as i64 + (j as i64 * ii as i64)) as usize);
-        match position {
-            Some(&is_in_pat) => {
-                if is_in_pat {
-                    return (x,y,(ii,i,j));
+/// Strides with their occurrences, as counted from origins_list, and the number of origins having another origin one
+/// stride away
+type StrideOccurrences = ((i32, i32), u32, u32);
+
+/// Candidates of the strides searched next are gathered at once, as long as they are at most this many
+const MAX_BATCH_CANDIDATES: usize = 1 << 24;
+
+/// Calls `f` with the stride and ranks in `sorted_origins` of every pair of origins whose stride is allowed by
+/// `stride_bounds`. Instead of trying every pair, only the window of rows and columns around every origin that the bounds
+/// allow is walked, so bounded searches scale with the number of origins
+fn for_each_stride_pair(sorted_origins: &[((i32, i32), usize)], stride_bounds: &AugmentStrideBounds, mut f: impl FnMut((i32, i32), usize, usize)) {
+    let max_row_stride = match stride_bounds.direction {
+        AugmentDirection::ColWise => 0,
+        _ => stride_bounds.max_row_stride.min(i32::MAX as usize) as i64,
+    };
+    let max_col_stride = match stride_bounds.direction {
+        AugmentDirection::RowWise => 0,
+        _ => stride_bounds.max_col_stride.min(i32::MAX as usize) as i64,
+    };
+
+    // Rank of the first origin of every row with origins
+    let row_starts: Vec<(i32, usize)> = sorted_origins
+        .iter()
+        .enumerate()
+        .dedup_by(|(_, ((row_a, _), _)), (_, ((row_b, _), _))| row_a == row_b)
+        .map(|(rank, ((row, _), _))| (*row, rank))
+        .collect();
+    let row_end = |row_idx: usize| row_starts.get(row_idx + 1).map_or(sorted_origins.len(), |(_, start)| *start);
+
+    for (row_idx, (row, row_start)) in row_starts.iter().enumerate() {
+        for rank_a in *row_start..row_end(row_idx) {
+            let ((row_a, col_a), _) = sorted_origins[rank_a];
+
+            // Only origins after this one, so every pair is found once
+            for (window_row_idx, (window_row, window_row_start)) in row_starts.iter().enumerate().skip(row_idx) {
+                if (*window_row - row) as i64 > max_row_stride {
+                    break;
+                }
+
+                let window = &sorted_origins[*window_row_start..row_end(window_row_idx)];
+                let first = if *window_row == row_a {
+                    rank_a + 1 - window_row_start
                 } else {
-                    continue;
+                    window.partition_point(|((_, col), _)| (*col as i64) < col_a as i64 - max_col_stride)
+                };
+
+                for (offset, ((row_b, col_b), _)) in window[first..].iter().enumerate() {
+                    if (*col_b as i64) > col_a as i64 + max_col_stride {
+                        break;
+                    }
+                    let stride = (row_b - row_a, col_b - col_a);
+                    if stride_bounds.contains(stride) {
+                        f(stride, rank_a, window_row_start + first + offset);
+                    }
                 }
-            },
-            None => return (x,y,(ii,i,j)),
+            }
         }
     }
+}
+
+/// Every pair counts for the stride and for the opposite stride, and only the one going forward in origins_list is an
+/// occurrence. Returns the occurrences and the number of pairs of every stride
+fn stride_counts(sorted_origins: &[((i32, i32), usize)], stride_bounds: &AugmentStrideBounds) -> HashMap<(i32, i32), (u32, u32)> {
+    let mut counts: HashMap<(i32, i32), (u32, u32)> = HashMap::new();
+    for_each_stride_pair(sorted_origins, stride_bounds, |stride, rank_a, rank_b| {
+        let (idx_a, idx_b) = (sorted_origins[rank_a].1, sorted_origins[rank_b].1);
+        let (count, pairs) = counts.entry(stride).or_default();
+        *count += (idx_a < idx_b) as u32;
+        *pairs += 1;
+        let (count, pairs) = counts.entry((-stride.0, -stride.1)).or_default();
+        *count += (idx_b < idx_a) as u32;
+        *pairs += 1;
+    });
+    counts
+}
+
+/// Candidates of the strides searched next, from the first one of `occurrences`: the ranks in `sorted_origins` of the
+/// origins having another origin one stride away, in ascending order. Strides that cannot make a piece of `piece_cutoff`
+/// origins get none. They are gathered walking all pairs once, unless there are so many that looking for the candidates
+/// of every stride on its own is cheaper. Returns the number of strides gathered and the bounds of their candidates
+fn stride_batch(sorted_origins: &[((i32, i32), usize)], stride_bounds: &AugmentStrideBounds, occurrences: &[StrideOccurrences], piece_cutoff: usize, num_pairs: u64) -> (usize, Vec<usize>, Vec<usize>) {
+    let usable = |pairs: u32| if pairs as usize + 1 < piece_cutoff { 0 } else { pairs as usize };
+
+    let mut bounds = vec![0];
+    for (_, _, pairs) in occurrences {
+        let end = bounds.last().unwrap() + usable(*pairs);
+        if bounds.len() > 1 && end > MAX_BATCH_CANDIDATES {
+            break;
+        }
+        bounds.push(end);
+    }
+    let num_strides = bounds.len() - 1;
+    let mut candidates = vec![0; *bounds.last().unwrap()];
+
+    if (num_strides as u64) * (sorted_origins.len() as u64) < num_pairs {
+        for (pos, (stride, _, pairs)) in occurrences[..num_strides].iter().enumerate() {
+            if usable(*pairs) > 0 {
+                candidates[bounds[pos]..bounds[pos + 1]].copy_from_slice(&stride_ranks(sorted_origins, *stride));
+            }
+        }
+    } else {
+        let positions: HashMap<(i32, i32), usize> = occurrences[..num_strides]
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, _, pairs))| usable(*pairs) > 0)
+            .map(|(pos, (stride, _, _))| (*stride, pos))
+            .collect();
+
+        let mut ends = bounds[..num_strides].to_vec();
+        let mut push = |stride: (i32, i32), rank: usize| {
+            if let Some(pos) = positions.get(&stride) {
+                candidates[ends[*pos]] = rank;
+                ends[*pos] += 1;
+            }
+        };
+        for_each_stride_pair(sorted_origins, stride_bounds, |stride, rank_a, rank_b| {
+            push(stride, rank_a);
+            push((-stride.0, -stride.1), rank_b);
+        });
+        bounds.windows(2).for_each(|range| candidates[range[0]..range[1]].sort_unstable());
+    }
+
+    (num_strides, candidates, bounds)
+}
+
+/// Ranks in `sorted_origins` of the origins having another origin one stride away, in ascending order. Origins one
+/// stride away from sorted origins are sorted too, so both are walked at once
+fn stride_ranks(sorted_origins: &[((i32, i32), usize)], (stride_x, stride_y): (i32, i32)) -> Vec<usize> {
+    let mut ranks = vec![];
+    let mut next_rank = 0;
+    for (rank, ((row, col), _)) in sorted_origins.iter().enumerate() {
+        let next = (*row as i64 + stride_x as i64, *col as i64 + stride_y as i64);
+        while next_rank < sorted_origins.len() && (sorted_origins[next_rank].0.0 as i64, sorted_origins[next_rank].0.1 as i64) < next {
+            next_rank += 1;
+        }
+        match sorted_origins.get(next_rank) {
+            Some(((row, col), _)) if (*row as i64, *col as i64) == next => ranks.push(rank),
+            Some(_) => (),
+            None => break,
+        }
+    }
+    ranks
+}
+
+/// Runs from the candidates of a stride: how many unexplored origins follow each other one stride apart from every one.
+/// They are kept in a max tree, so the first candidate with a longer run than a given one is found without walking them all
+struct StrideRuns<'a> {
+    stride: (i32, i32),
+    // Ranks of the candidates in sorted_origins, ascending
+    ranks: &'a [usize],
+    leaves: usize,
+    tree: Vec<u32>,
+}
+
+impl<'a> StrideRuns<'a> {
+    fn new(expl_matrix: &CsMat<bool>, sorted_origins: &[((i32, i32), usize)], ranks: &'a [usize], stride: (i32, i32)) -> Self {
+        let leaves = ranks.len().next_power_of_two();
+        let mut runs = StrideRuns { stride, ranks, leaves, tree: vec![0; 2 * leaves] };
+
+        // Runs are built backwards from the next origin along the stride, which comes later in CSR order for strides
+        // going down (or right along the same row)
+        let backwards: Vec<usize> = if stride > (0, 0) { (0..ranks.len()).rev().collect() } else { (0..ranks.len()).collect() };
+        for idx in backwards {
+            let (row, col) = sorted_origins[ranks[idx]].0;
+            let next = (row as i64 + stride.0 as i64, col as i64 + stride.1 as i64);
+
+            let run = match (explored(expl_matrix, (row as i64, col as i64)), explored(expl_matrix, next)) {
+                (Some(false), Some(false)) => 1 + runs.candidate_idx(sorted_origins, next).map_or(1, |next_idx| runs.tree[leaves + next_idx]),
+                (Some(false), _) => 1,
+                _ => 0,
+            };
+            runs.tree[leaves + idx] = run;
+        }
+        for node in (1..leaves).rev() {
+            runs.tree[node] = runs.tree[2 * node].max(runs.tree[2 * node + 1]);
+        }
+
+        runs
+    }
+
+    /// Longest piece with the stride and at most `max_n` points, as the explored matrix would be walked in CSR order to
+    /// find it: the first longest one, and the walk stops as soon as a piece as long as the next stride allows is found or
+    /// there are not enough origins left. None if no piece reaches the piece cutoff
+    fn best_piece(&self, sorted_origins: &[((i32, i32), usize)], max_n: i32, next_n: u32, piece_cutoff: usize) -> Option<Piece> {
+        let num_origins = sorted_origins.len() as i64;
+        let piece = |idx: usize, n: i32| {
+            let ((row, col), _) = sorted_origins[self.ranks[idx]];
+            (row as usize, col as usize, (n, self.stride.0, self.stride.1))
+        };
+
+        let mut best_n = piece_cutoff as i32 - 1;
+
+        // Then the walk stops right after the first origin
+        if best_n as i64 > next_n as i64 {
+            return match self.ranks.first() {
+                Some(0) if max_n.min(self.run(0) as i32) > best_n => Some(piece(0, max_n.min(self.run(0) as i32))),
+                _ => None,
+            };
+        }
+
+        let mut best_piece: Option<Piece> = None;
+        let mut from = 0;
+        while max_n > best_n {
+            let to = self.ranks.partition_point(|rank| (*rank as i64) < num_origins - best_n as i64);
+            let idx = match self.first_longer(1, 0, self.leaves, from..to, best_n as u32) {
+                Some(idx) => idx,
+                None => break,
+            };
+
+            best_n = max_n.min(self.run(idx) as i32);
+            best_piece = Some(piece(idx, best_n));
+            if best_n as i64 > next_n as i64 {
+                break;
+            }
+            from = idx + 1;
+        }
+
+        best_piece
+    }
 
-    return (x,y,(max_n,i,j));
-}
\ No newline at end of file
+    /// Runs once the origins of `piece`, which has this stride, are explored: its own are over, and those reaching it
+    /// along the stride stop right before it
+    fn explore_piece(&mut self, expl_matrix: &CsMat<bool>, sorted_origins: &[((i32, i32), usize)], piece: &Piece) {
+        let (x, y, (n, i, j)) = *piece;
+        let position = |k: i64| (x as i64 + i as i64 * k, y as i64 + j as i64 * k);
+
+        for k in 0..n as i64 {
+            if let Some(idx) = self.candidate_idx(sorted_origins, position(k)) {
+                self.set_run(idx, 0);
+            }
+        }
+
+        let mut k = 1;
+        while explored(expl_matrix, position(-k)) == Some(false) {
+            if let Some(idx) = self.candidate_idx(sorted_origins, position(-k)) {
+                self.set_run(idx, k as u32);
+            }
+            k += 1;
+        }
+    }
+
+    fn run(&self, idx: usize) -> u32 {
+        self.tree[self.leaves + idx]
+    }
+
+    fn set_run(&mut self, idx: usize, run: u32) {
+        let mut node = self.leaves + idx;
+        self.tree[node] = run;
+        while node > 1 {
+            node /= 2;
+            self.tree[node] = self.tree[2 * node].max(self.tree[2 * node + 1]);
+        }
+    }
+
+    /// First candidate in `range` with a run longer than `n`, under `node` (covering candidates lo..hi)
+    fn first_longer(&self, node: usize, lo: usize, hi: usize, range: Range<usize>, n: u32) -> Option<usize> {
+        if hi <= range.start || range.end <= lo || self.tree[node] <= n {
+            return None;
+        }
+        if hi - lo == 1 {
+            return Some(lo);
+        }
+        let mid = (lo + hi) / 2;
+        self.first_longer(2 * node, lo, mid, range.clone(), n).or_else(|| self.first_longer(2 * node + 1, mid, hi, range, n))
+    }
+
+    fn candidate_idx(&self, sorted_origins: &[((i32, i32), usize)], (row, col): (i64, i64)) -> Option<usize> {
+        let rank = sorted_origins.binary_search_by_key(&(row, col), |((row, col), _)| (*row as i64, *col as i64)).ok()?;
+        self.ranks.binary_search(&rank).ok()
+    }
+}
+
+/// Whether the origin at `position` has already been included in a piece. None if there is no origin there
+fn explored(expl_matrix: &CsMat<bool>, (row, col): (i64, i64)) -> Option<bool> {
+    if row < 0 || col < 0 {
+        return None;
+    }
+    expl_matrix.get(row as usize, col as usize).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANY: AugmentStrideBounds = AugmentStrideBounds { min_row_stride: 0, max_row_stride: usize::MAX, min_col_stride: 0, max_col_stride: usize::MAX, direction: AugmentDirection::Any };
+
+    /// A 4x3 grid of origins with stride (3,2), with isolated origins around it
+    fn grid_and_noise() -> Vec<(i32, i32)> {
+        let mut origins = vec![(1, 7), (10, 1)];
+        for row in [0, 3, 6, 9] {
+            for col in [0, 2, 4] {
+                origins.push((row, col));
+            }
+        }
+        origins.extend([(5, 11), (8, 13), (12, 4)]);
+        origins
+    }
+
+    /// Repetition of every metapattern and metapattern of every piece
+    type Found = (Vec<(i32, (i32, i32, i32))>, Vec<(MetaPatternPiece, i32)>);
+
+    /// Metapatterns and pieces found for `origins` of metapattern 3, numbered from 10, in order
+    fn metapatterns(mut origins: Vec<(i32, i32)>, piece_cutoff: usize, stride_bounds: AugmentStrideBounds) -> Option<Found> {
+        let (meta_patterns, pieces) = compute_metapatterns(&mut origins, piece_cutoff, 10, 3, &stride_bounds, false)?;
+        assert!(meta_patterns.values().all(|(_, order, sub_id)| *order == 0 && *sub_id == Some(3)));
+        Some((
+            meta_patterns.into_iter().map(|(id, (repetition, _, _))| (id, repetition)).collect(),
+            pieces.into_iter().collect(),
+        ))
+    }
+
+    #[test]
+    fn grid_and_noise_unbounded() {
+        // Longest runs first, then shortest strides
+        assert_eq!(metapatterns(grid_and_noise(), 2, ANY), Some((
+            vec![(10, (5, 3, 0)), (11, (4, 3, 0)), (12, (2, 3, 2)), (13, (2, 9, -6))],
+            vec![((0, 4), 10), ((0, 0), 11), ((0, 2), 11), ((5, 11), 12), ((1, 7), 13)],
+        )));
+        assert_eq!(metapatterns(grid_and_noise(), 3, ANY), Some((
+            vec![(10, (5, 3, 0)), (11, (4, 3, 0))],
+            vec![((0, 4), 10), ((0, 0), 11), ((0, 2), 11)],
+        )));
+    }
+
+    #[test]
+    fn grid_and_noise_bounded() {
+        let max_row_stride = AugmentStrideBounds { max_row_stride: 2, ..ANY };
+        assert_eq!(metapatterns(grid_and_noise(), 2, max_row_stride), Some((
+            vec![(10, (3, 0, 2)), (11, (2, 2, 3))],
+            vec![((0, 0), 10), ((3, 0), 10), ((6, 0), 10), ((9, 0), 10), ((10, 1), 11)],
+        )));
+
+        let min_strides = AugmentStrideBounds { min_row_stride: 3, min_col_stride: 3, ..ANY };
+        assert_eq!(metapatterns(grid_and_noise(), 2, min_strides), Some((
+            vec![(10, (2, 3, 4)), (11, (2, 5, 9)), (12, (2, 5, 11)), (13, (2, 5, -5)), (14, (2, -10, 3))],
+            vec![((0, 0), 10), ((3, 0), 10), ((6, 0), 10), ((9, 0), 10), ((0, 2), 11), ((3, 2), 12), ((1, 7), 13), ((10, 1), 14)],
+        )));
+    }
+
+    #[test]
+    fn grid_and_noise_by_direction() {
+        assert_eq!(metapatterns(grid_and_noise(), 2, AugmentStrideBounds { direction: AugmentDirection::RowWise, ..ANY }), Some((
+            vec![(10, (5, 3, 0)), (11, (4, 3, 0))],
+            vec![((0, 4), 10), ((0, 0), 11), ((0, 2), 11)],
+        )));
+        assert_eq!(metapatterns(grid_and_noise(), 2, AugmentStrideBounds { direction: AugmentDirection::ColWise, ..ANY }), Some((
+            vec![(10, (3, 0, 2))],
+            vec![((0, 0), 10), ((3, 0), 10), ((6, 0), 10), ((9, 0), 10)],
+        )));
+    }
+
+    #[test]
+    fn piece_cutoff() {
+        let diagonal = vec![(0, 0), (2, 1), (4, 2), (6, 3)];
+        // Every origin in a single piece
+        assert_eq!(metapatterns(diagonal.clone(), 4, ANY), Some((vec![(10, (4, 2, 1))], vec![((0, 0), 10)])));
+        // Fewer origins than the cutoff
+        assert_eq!(metapatterns(diagonal, 5, ANY), None);
+        // Runs shorter than the cutoff are left alone
+        assert_eq!(metapatterns(vec![(0, 0), (0, 1), (5, 0), (5, 2), (5, 4), (9, 9)], 3, ANY), Some((vec![(10, (3, 0, 2))], vec![((5, 0), 10)])));
+    }
+}
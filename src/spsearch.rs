@@ -1,18 +1,27 @@
-use colored::Colorize;
-use text_io::scan;
-use std::io::BufRead;
-use sprs::CsMat;
+use itertools::Itertools;
+use std::collections::{BinaryHeap, HashMap};
+use std::cmp::Reverse;
+use std::io::Write;
 use bitflags::bitflags;
 
-use crate::utils::{Piece,Pattern};
+use crate::utils::{Piece,Pattern,SearchPattern,InputFormat};
+use crate::mmio::MmSymmetry;
+use crate::occupancy::Occupancy;
 
+// Candidate piece of the Coverage search, ordered so the best one is popped first
+//                       Priority  Length     Pattern     Nonzero (row-major)  Conflicts
+type PackCandidate = (     u64,      i32,  Reverse<usize>,   Reverse<usize>,       u64   );
+
+#[derive(Clone)]
 pub struct SpSearchMatrix {
-    value_matrix: CsMat<bool>,
+    occupancy: Occupancy,
     // exploration_matrix: Vec<Prio>,
     pub nonzeros: usize,
     pub numrows: usize,
     pub numcols: usize,
-    patterns: Vec<Pattern>,
+    // Symmetry of the stored matrix. Anything but General means only the lower triangle is searched
+    pub symmetry: MmSymmetry,
+    patterns: Vec<SearchPattern>,
     found_pieces: Vec<Piece>,
 }
 
@@ -21,12 +30,16 @@ bitflags! {
         const NoFlags               = 0b0000_0000;
         const PatternFirst          = 0b0000_0001;
         const CellFirst             = 0b0000_0010;
+        const Coverage              = 0b0000_0100;
     }
 }
 
 impl SpSearchMatrix {
-    pub fn from_file(path: &str, transpose_input: bool) -> SpSearchMatrix {
-        let value_matrix = crate::utils::read_matrix_market_csr(path, transpose_input).map(|_: &f64| false);
+    pub fn from_file(path: &str, transpose_input: bool, input_format: InputFormat, symmetric: bool) -> SpSearchMatrix {
+        let (value_matrix, symmetry) = crate::utils::read_matrix_csr(path, transpose_input, input_format, symmetric);
+        let value_matrix = value_matrix.map(|_: &f64| false);
+        let occupancy = Occupancy::from_csmat(&value_matrix);
+        let symmetry = if symmetric { symmetry } else { MmSymmetry::General };
 
         let (numrows, numcols) = (value_matrix.rows(), value_matrix.cols());
         let nonzeros = value_matrix.nnz();
@@ -37,125 +50,324 @@ impl SpSearchMatrix {
         // });
 
         return SpSearchMatrix {
-            value_matrix: value_matrix,
+            occupancy,
             // exploration_matrix: exploration_matrix,
             nonzeros: nonzeros,
             numrows: numrows,
             numcols: numcols,
+            symmetry,
             patterns: vec![],
             found_pieces: vec![],
         };
     }
 
-    pub fn load_patterns(&mut self, patterns_file_path: &str) {
-        // Open patterns file
-        let patterns_file = std::fs::File::open(patterns_file_path).unwrap();
-        let lines: Vec<String> = std::io::BufReader::new(patterns_file).lines().collect::<Result<_,_>>().unwrap();
+    pub fn load_patterns(&mut self, patterns_file_path: &str, pattern_groups: Option<&[String]>) -> Result<(), String> {
+        let source = std::fs::read_to_string(patterns_file_path).map_err(|e| format!("Unable to read {}: {}", patterns_file_path, e))?;
 
-        // Set patterns
-        self.patterns = lines
-            .iter()
-            .map(|x| {
-                let (i,j,k): Pattern;
-                scan!(x.bytes() => "({},{},{})", i, j, k);
+        let groups = crate::patfile::parse_patterns(&source).map_err(|e| format!("{}:{}", patterns_file_path, e))?;
+        self.patterns = crate::patfile::select_patterns(&groups, pattern_groups)?;
 
-                if i < 2 {
-                    panic!("Detected pattern ({},{},{})! Pattern lengths must be greater than 1.\n{}", i,j,k, if i == 1 { format!("Perhaps you want to use the {} flag {}\n", "experimental".red().bold(), "--write-uninc-as-patterns".yellow().bold()) } else { "".to_string() });
-                };
-    
-                (i, j, k)
-            })
-            // Discard single-point patterns
-            .filter(|(i,_,_)| *i > 1)
+        Ok(())
+    }
+
+    /// Proposes patterns from the stride histogram between nonzeros instead of reading them from a file.
+    ///
+    /// Strides are counted between every pair of nonzeros at most `max_stride` rows and columns apart.
+    /// For the most frequent strides, the runs of consecutive nonzeros along them are measured and split
+    /// greedily into pieces, longest first, as PatternFirst would do. Patterns covering the most nonzeros
+    /// are kept (up to `max_patterns`), ordered by length so longer pieces are searched first.
+    ///
+    /// Returns the stride histogram, most frequent strides first.
+    pub fn discover_patterns(&mut self, max_stride: usize, max_length: usize, max_patterns: usize) -> Vec<((i32, i32), usize)> {
+        let max_stride = max_stride as i64;
+
+        // Count strides between nonzeros. Only strides going forward in row-major order are considered
+        let mut histogram: HashMap<(i32, i32), usize> = HashMap::new();
+        for idx in 0..self.occupancy.nnz() {
+            let (row, col) = self.occupancy.position(idx);
+            let (row, col) = (row as i64, col as i64);
+            for next_row in row..=std::cmp::min(row + max_stride, self.numrows as i64 - 1) {
+                let row_indices = self.occupancy.row_indices(next_row as usize);
+                let start = row_indices.partition_point(|c| (*c as i64) < col - max_stride);
+
+                for &next_col in row_indices[start..].iter().take_while(|c| (**c as i64) <= col + max_stride) {
+                    let stride = (next_row - row, next_col as i64 - col);
+                    if stride.0 > 0 || stride.1 > 0 {
+                        *histogram.entry((stride.0 as i32, stride.1 as i32)).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        let histogram = histogram
+            .into_iter()
+            // Ties are broken by proximity and then lexicographically, so the result is deterministic
+            .sorted_by_key(|((i, j), count)| (std::cmp::Reverse(*count), i*i + j*j, *i, *j))
+            .collect::<Vec<((i32, i32), usize)>>();
+
+        // Measure runs along the most frequent strides and estimate the coverage of each (N,I,J)
+        let mut candidates: Vec<(Pattern, usize)> = vec![];
+        for &((i, j), _) in histogram.iter().take(max_patterns) {
+            let mut remainders: Vec<usize> = (0..self.occupancy.nnz())
+                // Runs start on nonzeros without a predecessor along the stride
+                .filter(|idx| self.occupancy.step(*idx, (-i, -j)).is_none())
+                .map(|idx| {
+                    let (mut idx, mut len) = (idx, 1usize);
+                    while let Some(next_idx) = self.occupancy.step(idx, (i, j)) {
+                        (idx, len) = (next_idx, len + 1);
+                    }
+                    len
+                })
+                .filter(|len| *len > 1)
+                .collect();
+
+            for n in (2..=max_length).rev() {
+                let covered: usize = remainders.iter().map(|len| len - len % n).sum();
+                if covered > 0 {
+                    candidates.push(((n as i32, i, j), covered));
+                    remainders.iter_mut().for_each(|len| *len %= n);
+                }
+            }
+        }
+
+        self.patterns = candidates
+            .into_iter()
+            .sorted_by_key(|(_, covered)| std::cmp::Reverse(*covered))
+            .take(max_patterns)
+            .map(|(pattern, _)| pattern)
+            // Stable, so equally long patterns keep the coverage order
+            .sorted_by_key(|(n, _, _)| std::cmp::Reverse(*n))
+            .map(SearchPattern::Fixed)
             .collect();
+
+        histogram
+    }
+
+    pub fn write_patterns(&self, patterns_file_path: &str) {
+        let mut file = std::fs::File::create(patterns_file_path).unwrap_or_else(|e| panic!("Unable to create file {}: {}", patterns_file_path, e));
+        writeln!(file, "# Patterns discovered by z_polyhedrator, (N,I,J) longest first").unwrap();
+        self.patterns.iter().for_each(|pattern| {
+            writeln!(file, "{}", pattern).unwrap();
+        });
     }
 
     pub fn print_patterns(&self) {
         println!("N\tI\tJ");
-        self.patterns.iter().for_each(|&(i,j,k)| {
-            println!("{}\t{}\t{}", i, j, k);
+        self.patterns.iter().for_each(|pattern| {
+            match *pattern {
+                SearchPattern::Fixed((i,j,k)) => println!("{}\t{}\t{}", i, j, k),
+                SearchPattern::Maximal((i,j,k)) => println!("{}..\t{}\t{}", i, j, k),
+            }
         });
     }
 
-    pub fn search_patterns(&mut self, flags: SpSearchPatternsFlags) {
+    /// Searches the loaded patterns. With more than one thread, candidate pieces are evaluated in parallel
+    /// over bands of rows and then resolved serially, so the resulting piece list is identical to the
+    /// single-threaded one (see `search_patterns_parallel`).
+    pub fn search_patterns(&mut self, flags: SpSearchPatternsFlags, threads: usize) {
         // Parse possible flags
         // let skip_on_invalidation = flags.contains(SpGSearxPatternsFlags::SkipOnInvalidation);
 
         let pattern_first = flags.contains(SpSearchPatternsFlags::PatternFirst);
         let cell_first = flags.contains(SpSearchPatternsFlags::CellFirst);
+        let coverage = flags.contains(SpSearchPatternsFlags::Coverage);
 
-        if !(pattern_first ^ cell_first) {
+        if [pattern_first, cell_first, coverage].iter().filter(|f| **f).count() != 1 {
             panic!("Specify only one search priority flag!");
         }
         
-        if cell_first {
-            // Generate access positions
-            let mut nonzero_positions: Vec<(usize, usize)> = Vec::new();
-            self.value_matrix.iter().for_each(|(_, (row, col))| {
-                nonzero_positions.push((row,col));
-            });
+        if threads > 1 && (pattern_first || cell_first) {
+            self.search_patterns_parallel(cell_first, threads);
+        }
 
-            // First pass looking for patterns
-            nonzero_positions.iter().for_each(|(row, col)| {
-                let mut found: bool = false;
-                let mut piece: Piece = (0,0,(0,0,0));
-                'pattern_search: for pattern in self.patterns.iter(){
-                    let result = check_pattern(&self.value_matrix, (*row,*col), pattern);
-                    match result {
-                        None => continue,
-                        Some(found_piece) => {
-                            found = true;
-                            piece = found_piece;
-                            break 'pattern_search;
-                        },
-                    }
-                }
+        if threads <= 1 && cell_first {
+            // First pass looking for patterns, on every nonzero in row-major order
+            for idx in 0..self.occupancy.nnz() {
+                let result = self.patterns.iter().find_map(|pattern| check_pattern(&self.occupancy, idx, pattern));
 
-                if found {
+                if let Some(found_piece) = result {
                     // set places to found and add piece
-                    let (x,y,(n,i,j)) = piece;
-                    for ii in 0..n {
-                        let pos_val = self.value_matrix.get_mut((x as i64 + (i as i64 * ii as i64)) as usize, (y as i64 + (j as i64 * ii as i64)) as usize).unwrap();
-                        *pos_val = true;
-                    }
+                    cover_piece(&mut self.occupancy, &found_piece);
+                    self.found_pieces.push(found_piece);
+                }
+            }
+        }
 
-                    self.found_pieces.push(piece);
+        if threads <= 1 && pattern_first {
+            for pattern in self.patterns.iter() {
+                // Only nonzeros left uncovered by the previous patterns are visited
+                self.occupancy.compact();
+
+                for pos in 0..self.occupancy.uncovered().len() {
+                    let idx = self.occupancy.uncovered()[pos];
+                    if let Some(found_piece) = check_pattern(&self.occupancy, idx, pattern) {
+                        cover_piece(&mut self.occupancy, &found_piece);
+                        self.found_pieces.push(found_piece);
+                    }
                 }
-            });
+            }
+        }
+
+        if coverage {
+            self.search_patterns_coverage(threads);
+        }
+
+        // Add last nonzeros
+        self.occupancy.compact();
+        for &idx in self.occupancy.uncovered() {
+            let (row, col) = self.occupancy.position(idx);
+            self.found_pieces.push((row, col, (1, 0, 0)));
         }
 
-        if pattern_first {
-            self.patterns.iter().for_each(|pattern| {
-
-                let mut nonzero_positions: Vec<(usize, usize)> = Vec::new();
-                self.value_matrix.iter().filter(|(val, (_,_))| !**val).for_each(|(_, (row, col))| {
-                    nonzero_positions.push((row,col));
-                });
-
-                for (row,col) in nonzero_positions {
-                    let result = check_pattern(&self.value_matrix, (row,col), pattern);
-                    match result {
-                        None => continue,
-                        Some(found_piece) => {
-                            let (x,y,(n,i,j)) = found_piece;
-                            for ii in 0..n {
-                                let pos_val = self.value_matrix.get_mut((x as i64 + (i as i64 * ii as i64)) as usize, (y as i64 + (j as i64 * ii as i64)) as usize).unwrap();
-                                *pos_val = true;
-                            }
-                            self.found_pieces.push(found_piece);
-                        },
+    }
+
+    /// Parallel PatternFirst and CellFirst searches. Candidates are found against the matrix state before
+    /// the search step (a pattern for PatternFirst, the whole search for CellFirst) in bands of rows, then
+    /// taken in the serial order when none of their nonzeros was claimed since.
+    ///
+    /// This is exactly what the serial search does for fixed patterns: a piece valid before the step is valid
+    /// at its turn unless a piece taken in between overlaps it. Maximal pieces of one pattern never overlap each
+    /// other, as each one covers a whole run of uncovered nonzeros along the stride, so PatternFirst results are
+    /// identical too. For CellFirst, where other patterns may cut or start runs, maximal patterns are checked
+    /// serially at their turn.
+    fn search_patterns_parallel(&mut self, cell_first: bool, threads: usize) {
+        if !cell_first {
+            for pattern in self.patterns.iter() {
+                self.occupancy.compact();
+                for (_, found_piece, _) in find_candidates(&self.occupancy, &[(0, *pattern)], threads) {
+                    if piece_uncovered(&self.occupancy, &found_piece) {
+                        cover_piece(&mut self.occupancy, &found_piece);
+                        self.found_pieces.push(found_piece);
                     }
                 }
+            }
+            return;
+        }
+
+        let fixed_patterns: Vec<(usize, SearchPattern)> = self.patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, pattern)| matches!(pattern, SearchPattern::Fixed(_)))
+            .map(|(idx, pattern)| (idx, *pattern))
+            .collect();
+        self.occupancy.compact();
+        let candidates = find_candidates(&self.occupancy, &fixed_patterns, threads);
+        let mut next_candidate = 0usize;
+
+        for idx in 0..self.occupancy.nnz() {
+            // Candidates come in row-major order, then pattern order
+            let first_candidate = next_candidate;
+            while next_candidate < candidates.len() && candidates[next_candidate].0 == idx {
+                next_candidate += 1;
+            }
+            let position_candidates = &candidates[first_candidate..next_candidate];
+
+            let found_piece = self.patterns.iter().enumerate().find_map(|(pattern_idx, pattern)| match pattern {
+                SearchPattern::Fixed(_) => position_candidates
+                    .iter()
+                    .find(|(_, _, candidate_idx)| *candidate_idx == pattern_idx)
+                    .map(|(_, piece, _)| *piece)
+                    .filter(|piece| piece_uncovered(&self.occupancy, piece)),
+                SearchPattern::Maximal(_) => check_pattern(&self.occupancy, idx, pattern),
             });
+
+            if let Some(found_piece) = found_piece {
+                cover_piece(&mut self.occupancy, &found_piece);
+                self.found_pieces.push(found_piece);
+            }
         }
+    }
 
-        // Add last nonzeros
-        self.value_matrix.iter().for_each(|(&val, (row, col))| {
-            if !val {
-                self.found_pieces.push((row, col, (1, 0, 0)));
+    /// Coverage oriented search. Every candidate piece of every pattern competes at once, instead of
+    /// following the patterns file order. Candidates are packed twice, longest first and by length over
+    /// conflicts + 1 (GWMIN greedy on the graph of overlapping candidates, so a long piece blocking several
+    /// others may lose against them), and the packing covering more nonzeros is kept.
+    fn search_patterns_coverage(&mut self, threads: usize) {
+        let patterns: Vec<(usize, SearchPattern)> = self.patterns.iter().copied().enumerate().collect();
+        self.occupancy.compact();
+        let candidates = find_candidates(&self.occupancy, &patterns, threads);
+
+        // Number of candidates covering each nonzero
+        let mut cover_count: Vec<u32> = vec![0; self.occupancy.nnz()];
+        candidates.iter().for_each(|(_, piece, _)| {
+            self.occupancy.piece_indices(piece).into_iter().for_each(|idx| cover_count[idx] += 1);
+        });
+        let conflicts: Vec<u64> = candidates
+            .iter()
+            .map(|(_, piece, _)| self.occupancy.piece_indices(piece).into_iter().map(|idx| (cover_count[idx] - 1) as u64).sum())
+            .collect();
+
+        let (longest_occupancy, longest_pieces) = self.pack_candidates(&candidates, &vec![0; candidates.len()]);
+        let (gwmin_occupancy, gwmin_pieces) = self.pack_candidates(&candidates, &conflicts);
+
+        let covered = |pieces: &Vec<Piece>| pieces.iter().map(|(_, _, (n, _, _))| *n as usize).sum::<usize>();
+        let (occupancy, mut found_pieces) = {
+            if (covered(&gwmin_pieces), Reverse(gwmin_pieces.len())) > (covered(&longest_pieces), Reverse(longest_pieces.len())) {
+                (gwmin_occupancy, gwmin_pieces)
+            } else {
+                (longest_occupancy, longest_pieces)
             }
+        };
+
+        // Row-major order, as CellFirst produces
+        found_pieces.sort_by_key(|(row, col, _)| (*row, *col));
+
+        self.occupancy = occupancy;
+        self.found_pieces.extend(found_pieces);
+    }
+
+    /// Greedily packs non-overlapping candidates by length over conflicts + 1, ties going to longer
+    /// pieces and then to the patterns file order. Returns the resulting occupancy and pieces.
+    ///
+    /// Candidates are evaluated lazily. When a popped piece overlaps already taken ones it is
+    /// discarded, or re-queued with its new length if it is a maximal piece that can still be shortened.
+    fn pack_candidates(&self, candidates: &[(usize, Piece, usize)], conflicts: &[u64]) -> (Occupancy, Vec<Piece>) {
+        let mut occupancy = self.occupancy.clone();
+        let mut found_pieces: Vec<Piece> = vec![];
+
+        let mut queue: BinaryHeap<PackCandidate> = BinaryHeap::new();
+        let priority = |n: i32, conflicts: u64| ((n as u64) << 32) / (conflicts + 1);
+
+        candidates.iter().zip(conflicts.iter()).for_each(|(&(idx, (_, _, (n, _, _)), pattern_idx), &conflicts)| {
+            queue.push((priority(n, conflicts), n, Reverse(pattern_idx), Reverse(idx), conflicts));
         });
 
+        while let Some((_, n, Reverse(pattern_idx), Reverse(idx), conflicts)) = queue.pop() {
+            let found_piece = match check_pattern(&occupancy, idx, &self.patterns[pattern_idx]) {
+                None => continue,
+                Some(found_piece) => found_piece,
+            };
+
+            // Shortened maximal pieces have to compete again with their new length
+            if found_piece.2.0 < n {
+                queue.push((priority(found_piece.2.0, conflicts), found_piece.2.0, Reverse(pattern_idx), Reverse(idx), conflicts));
+                continue;
+            }
+
+            cover_piece(&mut occupancy, &found_piece);
+            found_pieces.push(found_piece);
+        }
+
+        // Cut maximal runs leave new run heads behind, which are filled in patterns file order
+        for pattern in self.patterns.iter().filter(|pattern| matches!(pattern, SearchPattern::Maximal(_))) {
+            occupancy.compact();
+            for pos in 0..occupancy.uncovered().len() {
+                if let Some(found_piece) = check_pattern(&occupancy, occupancy.uncovered()[pos], pattern) {
+                    cover_piece(&mut occupancy, &found_piece);
+                    found_pieces.push(found_piece);
+                }
+            }
+        }
+
+        (occupancy, found_pieces)
+    }
+
+    /// Number of pieces found and nonzeros they cover, single nonzeros excluded
+    pub fn coverage_stats(&self) -> (usize, usize) {
+        self.found_pieces
+            .iter()
+            .filter(|(_, _, (n, _, _))| *n > 1)
+            .fold((0, 0), |(pieces, covered), (_, _, (n, _, _))| (pieces + 1, covered + *n as usize))
     }
 
     pub fn print_pieces(&self) {
@@ -174,15 +386,15 @@ impl SpSearchMatrix {
 
 #[inline(always)]
 #[allow(dead_code)]
-fn check_pattern(csmat: &CsMat<bool>, curr_pos: (usize, usize), pattern: &Pattern) -> Option<Piece> {
+fn check_pattern(occupancy: &Occupancy, start: usize, pattern: &SearchPattern) -> Option<Piece> {
     // println!("Checking pattern {:?}", pattern);
-    let &(n,i,j) = pattern;
-    let (x,y) = curr_pos;
+    let (n,i,j) = pattern.pattern();
+    let (x,y) = occupancy.position(start);
 
     // println!("{:?}", (x,y,n,i,j));
 
     // Discard already dumped patterns without computing bounds first
-    if *csmat.get(x,y).unwrap() {
+    if occupancy.is_covered(start) {
         return None;
     }
 
@@ -190,24 +402,76 @@ fn check_pattern(csmat: &CsMat<bool>, curr_pos: (usize, usize), pattern: &Patter
     let max_pos_y = y as i64 + (n-1) as i64 * j as i64;
 
     // Discard out-of-bounds patterns
-    if max_pos_x < 0 || max_pos_x >= csmat.rows() as i64 || max_pos_y < 0 || max_pos_y >= csmat.cols() as i64 {
+    if max_pos_x < 0 || max_pos_x >= occupancy.rows() as i64 || max_pos_y < 0 || max_pos_y >= occupancy.cols() as i64 {
         return None;
     }
 
     // We can start on the next pattern
-    for ii in 1..n {
-        let position = csmat.get((x as i64 + (i as i64 * ii as i64)) as usize, (y as i64 + (j as i64 * ii as i64)) as usize);
-        match position {
-            Some(&is_in_pat) => {
-                if is_in_pat {
-                    return None;
-                } else {
-                    continue;
-                }
-            },
-            None    => return None,
+    let mut idx = start;
+    for _ in 1..n {
+        match occupancy.step(idx, (i,j)) {
+            Some(next_idx) if !occupancy.is_covered(next_idx) => idx = next_idx,
+            _ => return None,
+        }
+    }
+
+    if let SearchPattern::Maximal(_) = pattern {
+        // Only start on the head of the run, otherwise strides going backwards in row-major order
+        // would be cut by the positions visited first
+        if matches!(occupancy.step(start, (-i,-j)), Some(prev_idx) if !occupancy.is_covered(prev_idx)) {
+            return None;
+        }
+
+        // Extend as far as the run of uncovered nonzeros goes
+        let mut n = n;
+        while let Some(next_idx) = occupancy.step(idx, (i,j)).filter(|next_idx| !occupancy.is_covered(*next_idx)) {
+            (idx, n) = (next_idx, n + 1);
         }
+
+        return Some((x,y,(n,i,j)));
     }
 
     return Some((x,y,(n,i,j)));
-}
\ No newline at end of file
+}
+
+#[inline(always)]
+fn cover_piece(occupancy: &mut Occupancy, piece: &Piece) {
+    occupancy.piece_indices(piece).into_iter().for_each(|idx| occupancy.cover(idx));
+}
+
+#[inline(always)]
+fn piece_uncovered(occupancy: &Occupancy, piece: &Piece) -> bool {
+    occupancy.piece_indices(piece).into_iter().all(|idx| !occupancy.is_covered(idx))
+}
+
+/// Checks `patterns` (along with their index) on the uncovered nonzeros of `occupancy`, splitting them
+/// in `threads` bands of consecutive rows. Found pieces are returned along with their starting nonzero
+/// in row-major order and then in the given pattern order, whatever the number of threads.
+fn find_candidates(occupancy: &Occupancy, patterns: &[(usize, SearchPattern)], threads: usize) -> Vec<(usize, Piece, usize)> {
+    let search_band = |band: &[usize]| -> Vec<(usize, Piece, usize)> {
+        let mut candidates: Vec<(usize, Piece, usize)> = vec![];
+        for &idx in band {
+            for (pattern_idx, pattern) in patterns.iter() {
+                if let Some(piece) = check_pattern(occupancy, idx, pattern) {
+                    candidates.push((idx, piece, *pattern_idx));
+                }
+            }
+        }
+        candidates
+    };
+
+    let uncovered = occupancy.uncovered();
+    if threads <= 1 || uncovered.len() < threads {
+        return search_band(uncovered);
+    }
+
+    let search_band = &search_band;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = uncovered
+            .chunks(uncovered.len().div_ceil(threads))
+            .map(|band| scope.spawn(move || search_band(band)))
+            .collect();
+
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    })
+}
@@ -0,0 +1,26 @@
+// Helpers shared by the unit tests
+use sprs::CsMat;
+
+/// (row, col, value) of every nonzero, in row-major order
+pub fn entries(matrix: &CsMat<f64>) -> Vec<(usize, usize, f64)> {
+    matrix.iter().map(|(val, (row, col))| (row, col, *val)).collect()
+}
+
+/// File in the temporary directory, removed when dropped. `name` must be unique among the tests
+pub struct TempFile {
+    pub path: String,
+}
+
+impl TempFile {
+    pub fn new(name: &str, contents: &[u8]) -> TempFile {
+        let path = std::env::temp_dir().join(format!("z_polyhedrator_{}_{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        TempFile { path: path.to_str().unwrap().to_string() }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -1,11 +1,10 @@
 use linked_hash_map::LinkedHashMap;
-use num_traits::{Num, NumCast};
-use project_root::get_project_root;
-use sprs::CsMat;
-use stringreader::StringReader;
-use std::{io::BufReader, process::{Command, Stdio}};
+use num_traits::NumCast;
+use sprs::{CsMat, TriMat};
 use colored::Colorize;
 
+use crate::mmio::{MmField, MmSymmetry};
+
 use std::io::prelude::*;
 
 /* COMMON TYPES */
@@ -14,6 +13,31 @@ pub type Piece = (usize, usize, Pattern);
 pub type Uwc = (Vec<Vec<i32>>, Vec<i32>, Vec<i32>);
 pub type OriginUwc = (usize, usize, Uwc);
 
+// Patterns searched by SpSearch. Maximal patterns use N as minimum length and extend
+// as far as the run of uncovered nonzeros goes, so their pieces may be longer than N
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SearchPattern {
+    Fixed(Pattern),
+    Maximal(Pattern),
+}
+
+impl SearchPattern {
+    pub fn pattern(&self) -> Pattern {
+        match *self {
+            SearchPattern::Fixed(pattern) | SearchPattern::Maximal(pattern) => pattern,
+        }
+    }
+}
+
+impl std::fmt::Display for SearchPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            SearchPattern::Fixed((n, i, j)) => write!(f, "({},{},{})", n, i, j),
+            SearchPattern::Maximal((n, i, j)) => write!(f, "({}..,{},{})", n, i, j),
+        }
+    }
+}
+
 //                         N    I    J    Order  Sub-Pattern
 pub type MetaPattern = ( (i32, i32, i32),  i32,  Option<i32> );
 // If Option is None -> N,I,J describe the base pattern
@@ -21,82 +45,65 @@ pub type MetaPattern = ( (i32, i32, i32),  i32,  Option<i32> );
 //                             X     Y
 pub type MetaPatternPiece = (usize,usize);
 
-pub fn read_matrix_market_csr<
-    T: Num +
-       NumCast +
-       std::clone::Clone +
-       sprs::num_kinds::PrimitiveKind +
-       sprs::num_matrixmarket::MatrixMarketRead +
-       sprs::num_matrixmarket::MatrixMarketConjugate +
-       std::ops::Neg<Output = T>
-    > (path: &str, transpose_input: bool) -> CsMat<T> {
-    let value_matrix: CsMat<T> = {
-        match sprs::io::read_matrix_market(path) {
-            Ok(mat) => {
-                if transpose_input {
-                    mat.transpose_view().to_csr()
-                } else {
-                    mat.to_csr()
-                }
-            },
-            Err(_) => {
-                eprintln!(
-                    "\n{} MatrixMarket file was incompatible with {} crate. Trying to convert it on the fly...",
-                    "[IMPORTANT]".bold().red(),
-                    "sprs".green()
-                );
-
-                let mut project_root = get_project_root().unwrap();
-                project_root.push("utils");
-                project_root.push("transcode_mm.py");
-                let executable_path = project_root.to_str().unwrap();
-
-                let cmd_output = Command::new("python3")
-                    .arg(executable_path).arg(path).arg("stdout")
-                    .stdout(Stdio::piped())
-                    .output()
-                    .expect("Failed to execute python3 script");
-
-                let py_stdout = String::from_utf8(cmd_output.stdout).unwrap();
-
-                let streader = StringReader::new(&py_stdout);
-                let mut bufreader = BufReader::new(streader);
-
-                eprintln!(
-                    "{} MatrixMarket file was converted succesfully. If the files will be accessed often, seriously consider transcoding it with the tool located on {} for efficient CPU usage and faster runtime.\n",
-                    "[PY-INFO]".bold().blue(),
-                    executable_path.bright_blue()
-                );
-
-                let mat = match sprs::io::read_matrix_market_from_bufread(&mut bufreader){
-                    Ok(mat) => mat,
-                    Err(e) => {
-                        eprintln!(
-                            "{} An error occured while reading the converted MatrixMarket file. ERROR: {}",
-                            "[ERROR]".bold().red(),
-                            format!("{}",e).bold().red()
-                        );
-                        eprintln!(
-                            "\nIf this is not a filesystem related error, try executing {} {} {} {} manually. If it outputs a valid MatrixMarket file, please report the issue on the repository. If it does not, please check your pip dependencies in the {} file and update those packages via pip or your desired package manager.\n",
-                            "python3".bright_blue(),
-                            executable_path.bright_blue(),
-                            path.bright_blue(),
-                            "stdout".bright_blue(),
-                            "requirements.txt".bright_blue()
-                        );
-                        std::process::exit(1);
-                    }
-                };
-
-                if transpose_input {
-                    mat.transpose_view().to_csr()
-                } else {
-                    mat.to_csr()
-                }
-            },
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Chosen from the file extension, MatrixMarket unless it looks like a Rutherford-Boeing file
+    Auto,
+    MatrixMarket,
+    RutherfordBoeing,
+}
+
+/// Reads a matrix file into CSR, returning it along with the symmetry declared by the file.
+///
+/// When `stored_triangle` is set and the matrix is not general, only the lower triangle
+/// (after the optional transposition) is kept, so each symmetric pair appears once.
+pub fn read_matrix_csr<T: NumCast + Clone>(path: &str, transpose_input: bool, input_format: InputFormat, stored_triangle: bool) -> (CsMat<T>, MmSymmetry) {
+    let input_format = match input_format {
+        InputFormat::Auto => if crate::rbio::is_rutherford_boeing_path(path) { InputFormat::RutherfordBoeing } else { InputFormat::MatrixMarket },
+        fmt => fmt,
+    };
+
+    let (format_name, result) = match input_format {
+        InputFormat::RutherfordBoeing => ("Rutherford-Boeing", crate::rbio::read_rutherford_boeing_triplets(path).map(|(triplets, header)| (triplets, header.symmetry, header.mxtype.starts_with('c')))),
+        _ => ("MatrixMarket", crate::mmio::read_matrix_market_triplets(path).map(|(triplets, header)| (triplets, header.symmetry, header.field == MmField::Complex))),
+    };
+
+    let (triplets, symmetry, complex) = match result {
+        Ok(triplets) => triplets,
+        Err(e) => {
+            eprintln!(
+                "{} An error occured while reading {} file {}. ERROR: {}",
+                "[ERROR]".bold().red(),
+                format_name,
+                path.bright_blue(),
+                e.bold().red()
+            );
+            std::process::exit(1);
+        }
+    };
+
+    // UZP files only hold real values
+    if complex {
+        eprintln!("{} {} holds a complex matrix. Only the real part of its values is kept.", "[WARNING]".yellow().bold(), path.bright_blue());
+    }
+
+    let mut value_matrix: CsMat<f64> = {
+        if transpose_input {
+            triplets.transpose_view().to_csr()
+        } else {
+            triplets.to_csr()
         }
     };
-    return value_matrix;
+
+    if stored_triangle && symmetry != MmSymmetry::General {
+        let mut lower_triangle: TriMat<f64> = TriMat::with_capacity(value_matrix.shape(), value_matrix.nnz());
+        value_matrix.iter()
+            .filter(|(_, (row, col))| row >= col)
+            .for_each(|(val, (row, col))| lower_triangle.add_triplet(row, col, *val));
+        value_matrix = lower_triangle.to_csr();
+    }
+
+    (value_matrix.map(|val| T::from(*val).unwrap()), symmetry)
 }
 
 #[inline(always)]
@@ -209,4 +216,37 @@ pub fn convex_hull_hyperrectangle_nd(u: &Vec<Vec<i32>>, w: &Vec<i32>, dense: boo
     }
 
     return ch;
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::{entries, TempFile};
+
+    fn read_entries(file: &TempFile, transpose_input: bool, stored_triangle: bool) -> (Vec<(usize, usize, f64)>, MmSymmetry) {
+        let (matrix, symmetry) = read_matrix_csr::<f64>(&file.path, transpose_input, InputFormat::Auto, stored_triangle);
+        (entries(&matrix), symmetry)
+    }
+
+    #[test]
+    fn stored_triangle_of_symmetric_matrices() {
+        let file = TempFile::new("utils_symmetric.mtx", b"%%MatrixMarket matrix coordinate real symmetric\n3 3 4\n1 1 1.0\n2 1 2.0\n3 2 3.0\n3 3 4.0\n");
+
+        let full = vec![(0, 0, 1.0), (0, 1, 2.0), (1, 0, 2.0), (1, 2, 3.0), (2, 1, 3.0), (2, 2, 4.0)];
+        let lower = vec![(0, 0, 1.0), (1, 0, 2.0), (2, 1, 3.0), (2, 2, 4.0)];
+        assert_eq!(read_entries(&file, false, false), (full, MmSymmetry::Symmetric));
+        assert_eq!(read_entries(&file, false, true), (lower.clone(), MmSymmetry::Symmetric));
+        // The lower triangle is taken after transposing
+        assert_eq!(read_entries(&file, true, true), (lower, MmSymmetry::Symmetric));
+    }
+
+    #[test]
+    fn stored_triangle_keeps_signs_and_general_matrices() {
+        let file = TempFile::new("utils_skew.mtx", b"%%MatrixMarket matrix coordinate real skew-symmetric\n3 3 2\n2 1 2.0\n3 1 -5.0\n");
+        assert_eq!(read_entries(&file, false, true), (vec![(1, 0, 2.0), (2, 0, -5.0)], MmSymmetry::SkewSymmetric));
+        assert_eq!(read_entries(&file, true, true), (vec![(1, 0, -2.0), (2, 0, 5.0)], MmSymmetry::SkewSymmetric));
+
+        let file = TempFile::new("utils_general.mtx", b"%%MatrixMarket matrix coordinate real general\n2 2 2\n1 2 1.0\n2 1 2.0\n");
+        assert_eq!(read_entries(&file, false, true), (vec![(0, 1, 1.0), (1, 0, 2.0)], MmSymmetry::General));
+    }
+}
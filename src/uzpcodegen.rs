@@ -0,0 +1,248 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use colored::Colorize;
+
+use crate::mmio::MmSymmetry;
+use crate::uzpfile::{UzpFile, UzpShapeEncoding, UzpUninc};
+
+/* C CODE GENERATION
+ *
+ * Emits a self-contained C file computing y += A·x for the matrix of a UZP file:
+ *
+ *   uzp_data                The UZP data section as is, so origin data offsets index it directly
+ *   shape_<id>_origins      {row, col, data offset} of every origin of the shape
+ *   uzp_spmv                One loop nest per shape iterating over its origins, the first axis being
+ *                           the outermost loop as in the data section, then a CSR or COO loop over
+ *                           the uninc nonzeros (whose values start at UZP_INC_NNZ)
+ *   main                    Driver filling x like the spmv subcommand and comparing the checksum of y
+ *                           against the one computed while generating. Left out with -DUZP_NO_MAIN
+ *
+ * Rows, columns, offsets and indices are uzp_index_t: int, or int64_t for wide layout files, whose
+ * matrices may not fit in int.
+ */
+
+// Values per line on generated array initializers
+const VALUES_PER_LINE: usize = 8;
+
+/// Generates the C source for a UZP file, exiting with an error message if it cannot be read or written.
+pub fn codegen_uzp(input_uzp_file_path: &str, output_c_file_path: &str) {
+    let uzp_file = crate::uzpgen::read_uzp_file(input_uzp_file_path);
+
+    // Checksum of y for the driver input vector
+    let x: Vec<f64> = crate::uzpspmv::input_vector(uzp_file.header.ncols);
+    let mut y: Vec<f64> = vec![0.0; uzp_file.header.nrows];
+    crate::uzpspmv::uzp_spmv(&uzp_file, &x, &mut y);
+    let expected_checksum: f64 = y.iter().sum();
+
+    let source_name = std::path::Path::new(input_uzp_file_path).file_name().unwrap().to_string_lossy();
+    if let Err(e) = write_c_spmv(&uzp_file, &source_name, expected_checksum, output_c_file_path) {
+        eprintln!("{} Unable to write C file {}. ERROR: {}", "[ERROR]".red().bold(), output_c_file_path.bright_blue(), e.to_string().bold().red());
+        std::process::exit(1);
+    }
+}
+
+/// Writes the C source for the matrix of `uzp_file`. `expected_checksum` is the sum of the elements of y
+/// for the driver input vector.
+pub fn write_c_spmv(uzp_file: &UzpFile, source_name: &str, expected_checksum: f64, output_c_file_path: &str) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(output_c_file_path)?);
+
+    let mirror_sign = match uzp_file.symmetry {
+        MmSymmetry::General => None,
+        MmSymmetry::SkewSymmetric => Some("-"),
+        MmSymmetry::Symmetric | MmSymmetry::Hermitian => Some(""),
+    };
+
+    writeln!(out, "/* SpMV kernel generated by z_polyhedrator from {} */", source_name)?;
+    if mirror_sign.is_some() {
+        writeln!(out, "/* {:?} matrix: only one triangle is stored, off-diagonal nonzeros are mirrored */", uzp_file.symmetry)?;
+    }
+    writeln!(out, "#include <math.h>")?;
+    writeln!(out, "#include <stdint.h>")?;
+    writeln!(out, "#include <stdio.h>")?;
+    writeln!(out, "#include <stdlib.h>")?;
+    writeln!(out, "#include <string.h>")?;
+    writeln!(out, "#include <time.h>")?;
+    writeln!(out)?;
+    writeln!(out, "#define UZP_NROWS {}", uzp_file.header.nrows)?;
+    writeln!(out, "#define UZP_NCOLS {}", uzp_file.header.ncols)?;
+    writeln!(out, "#define UZP_NNZ {}", uzp_file.header.nnz)?;
+    writeln!(out, "#define UZP_INC_NNZ {}", uzp_file.header.inc_nnz)?;
+    writeln!(out)?;
+    writeln!(out, "typedef {} uzp_index_t;", if uzp_file.header.layout.wide { "int64_t" } else { "int" })?;
+    writeln!(out)?;
+
+    write_array(&mut out, "static const double uzp_data[UZP_NNZ + 1]", uzp_file.data.iter().map(|val| format_double(*val)))?;
+
+    // Origins grouped by shape, keeping the order of the shapes in the file
+    for (shape, origins) in uzp_file.shapes().zip(uzp_file.origins_by_shape()) {
+        let origins: Vec<String> = origins
+            .into_iter()
+            .map(|origin| format!("{{{}, {}, {}}}", origin.row, origin.col, origin.data_offset))
+            .collect();
+
+        writeln!(out, "#define SHAPE_{}_ORIGINS {}", shape_name(shape.id), origins.len())?;
+        write_array(&mut out, &format!("static const uzp_index_t shape_{}_origins[SHAPE_{}_ORIGINS + 1][3]", shape_name(shape.id), shape_name(shape.id)), origins.into_iter())?;
+    }
+
+    match &uzp_file.uninc {
+        UzpUninc::Csr { indptr, indices } => {
+            write_array(&mut out, "static const uzp_index_t uninc_indptr[UZP_NROWS + 1]", indptr.iter().map(|v| v.to_string()))?;
+            write_array(&mut out, "static const uzp_index_t uninc_indices[UZP_NNZ - UZP_INC_NNZ + 1]", indices.iter().map(|v| v.to_string()))?;
+        },
+        UzpUninc::Coo { rows, cols } => {
+            write_array(&mut out, "static const uzp_index_t uninc_rows[UZP_NNZ - UZP_INC_NNZ + 1]", rows.iter().map(|v| v.to_string()))?;
+            write_array(&mut out, "static const uzp_index_t uninc_cols[UZP_NNZ - UZP_INC_NNZ + 1]", cols.iter().map(|v| v.to_string()))?;
+        },
+    }
+
+    writeln!(out, "/* y += A*x */")?;
+    writeln!(out, "void uzp_spmv(const double *restrict data, const double *restrict x, double *restrict y) {{")?;
+    for shape in uzp_file.shapes() {
+        let name = shape_name(shape.id);
+        let axes: Vec<String> = (0..shape.dim)
+            .map(|axis| {
+                let (len, _, (row_step, col_step)) = shape.axis_walk(axis);
+                format!("{}x({},{})", len, row_step, col_step)
+            })
+            .collect();
+        if shape.encoding == UzpShapeEncoding::VertexRec {
+            writeln!(out, "    /* Shape {}: {} */", shape.id, axes.join(" * "))?;
+        } else {
+            writeln!(out, "    /* Shape {}: {:?} within {} */", shape.id, shape.encoding, axes.join(" * "))?;
+        }
+        writeln!(out, "    for (uzp_index_t o = 0; o < SHAPE_{}_ORIGINS; o++) {{", name)?;
+        writeln!(out, "        const uzp_index_t r0 = shape_{}_origins[o][0], c0 = shape_{}_origins[o][1];", name, name)?;
+        writeln!(out, "        const double *v = data + shape_{}_origins[o][2];", name)?;
+
+        // Loop nest over the index points, first axis outermost
+        let mut indent = String::from("        ");
+        for axis in 0..shape.dim {
+            let increment = if shape.strides[axis] == 1 { format!("k{}++", axis) } else { format!("k{} += {}", axis, shape.strides[axis]) };
+            writeln!(out, "{}for (int k{} = {}; k{} <= {}; {}) {{", indent, axis, shape.min_point[axis], axis, shape.max_point[axis], increment)?;
+            indent.push_str("    ");
+        }
+
+        // Non rectangular shapes walk their bounding box, skipping the points out of the shape
+        if shape.encoding != UzpShapeEncoding::VertexRec {
+            let conditions: Vec<String> = shape.u.iter().zip(shape.w.iter()).map(|(u_row, w)| format!("{} >= {}", offset_expr("", u_row.iter().copied()), -w)).collect();
+            writeln!(out, "{}if (!({})) continue;", indent, if conditions.is_empty() { "1".to_string() } else { conditions.join(" && ") })?;
+        }
+
+        let row_expr = offset_expr("r0", (0..shape.dim).map(|axis| shape.lattice[2*axis] as i64));
+        let col_expr = offset_expr("c0", (0..shape.dim).map(|axis| shape.lattice[2*axis+1] as i64));
+        writeln!(out, "{}const uzp_index_t r = {}, c = {};", indent, row_expr, col_expr)?;
+        writeln!(out, "{}y[r] += *v * x[c];", indent)?;
+        if let Some(sign) = mirror_sign {
+            writeln!(out, "{}if (r != c) y[c] += {}*v * x[r];", indent, sign)?;
+        }
+        writeln!(out, "{}v++;", indent)?;
+
+        for _ in 0..shape.dim {
+            indent.truncate(indent.len() - 4);
+            writeln!(out, "{}}}", indent)?;
+        }
+        writeln!(out, "    }}")?;
+    }
+
+    writeln!(out, "    /* Uninc nonzeros */")?;
+    writeln!(out, "    const double *v = data + UZP_INC_NNZ;")?;
+    match &uzp_file.uninc {
+        UzpUninc::Csr { .. } => {
+            writeln!(out, "    for (uzp_index_t r = 0; r < UZP_NROWS; r++) {{")?;
+            writeln!(out, "        for (uzp_index_t k = uninc_indptr[r]; k < uninc_indptr[r+1]; k++) {{")?;
+            writeln!(out, "            const uzp_index_t c = uninc_indices[k];")?;
+        },
+        UzpUninc::Coo { .. } => {
+            writeln!(out, "    {{")?;
+            writeln!(out, "        for (uzp_index_t k = 0; k < UZP_NNZ - UZP_INC_NNZ; k++) {{")?;
+            writeln!(out, "            const uzp_index_t r = uninc_rows[k], c = uninc_cols[k];")?;
+        },
+    }
+    writeln!(out, "            y[r] += v[k] * x[c];")?;
+    if let Some(sign) = mirror_sign {
+        writeln!(out, "            if (r != c) y[c] += {}v[k] * x[r];", sign)?;
+    }
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    writeln!(out, "#ifndef UZP_NO_MAIN")?;
+    writeln!(out, "#define EXPECTED_CHECKSUM {}", format_double(expected_checksum))?;
+    writeln!(out)?;
+    writeln!(out, "/* Usage: ./a.out [repetitions]. Returns 1 if the checksum of y does not match the expected one */")?;
+    writeln!(out, "int main(int argc, char **argv) {{")?;
+    writeln!(out, "    const int repetitions = argc > 1 ? atoi(argv[1]) : 1;")?;
+    writeln!(out, "    double *x = malloc((UZP_NCOLS + 1) * sizeof(double));")?;
+    writeln!(out, "    double *y = malloc((UZP_NROWS + 1) * sizeof(double));")?;
+    writeln!(out, "    for (uzp_index_t i = 0; i < UZP_NCOLS; i++) x[i] = 1.0 + (double)(i % 13) / 13.0; /* Same as uzpspmv::input_vector */")?;
+    writeln!(out)?;
+    writeln!(out, "    const clock_t start = clock();")?;
+    writeln!(out, "    for (int rep = 0; rep < (repetitions > 0 ? repetitions : 1); rep++) {{")?;
+    writeln!(out, "        memset(y, 0, (UZP_NROWS + 1) * sizeof(double));")?;
+    writeln!(out, "        uzp_spmv(uzp_data, x, y);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    const double elapsed = (double)(clock() - start) / CLOCKS_PER_SEC;")?;
+    writeln!(out)?;
+    writeln!(out, "    double checksum = 0.0;")?;
+    writeln!(out, "    for (uzp_index_t i = 0; i < UZP_NROWS; i++) checksum += y[i];")?;
+    writeln!(out, "    const int ok = fabs(checksum - EXPECTED_CHECKSUM) <= 1e-9 * fmax(1.0, fabs(EXPECTED_CHECKSUM));")?;
+    writeln!(out, "    printf(\"checksum %.17g, expected %.17g: %s\\n\", checksum, EXPECTED_CHECKSUM, ok ? \"OK\" : \"MISMATCH\");")?;
+    writeln!(out, "    printf(\"%d SpMV took %.9f seconds (%.9f seconds per SpMV)\\n\", repetitions, elapsed, elapsed / (repetitions > 0 ? repetitions : 1));")?;
+    writeln!(out)?;
+    writeln!(out, "    free(x);")?;
+    writeln!(out, "    free(y);")?;
+    writeln!(out, "    return ok ? 0 : 1;")?;
+    writeln!(out, "}}")?;
+    writeln!(out, "#endif")?;
+
+    out.flush()
+}
+
+/// C identifier suffix of a shape id. Ids are signed so they may be negative
+#[inline(always)]
+fn shape_name(id: i32) -> String {
+    if id < 0 { format!("m{}", -(id as i64)) } else { id.to_string() }
+}
+
+/// `base + coefs[0]*k0 + coefs[1]*k1 + ...`, skipping zero terms. An empty base starts with the first term
+fn offset_expr(base: &str, coefs: impl Iterator<Item = i64>) -> String {
+    let mut expr = String::from(base);
+    coefs.enumerate().filter(|(_, coef)| *coef != 0).for_each(|(axis, coef)| {
+        let term = if coef.abs() == 1 { format!("k{}", axis) } else { format!("{}*k{}", coef.abs(), axis) };
+        match (expr.is_empty(), coef < 0) {
+            (true, false) => expr.push_str(&term),
+            (true, true) => expr.push_str(&format!("-{}", term)),
+            (false, false) => expr.push_str(&format!(" + {}", term)),
+            (false, true) => expr.push_str(&format!(" - {}", term)),
+        }
+    });
+    if expr.is_empty() { "0".to_string() } else { expr }
+}
+
+/// Exact C literal of a double. Rust prints the shortest representation that reads back to the same value
+#[inline(always)]
+fn format_double(val: f64) -> String {
+    if val.is_nan() {
+        "NAN".to_string()
+    } else if val.is_infinite() {
+        if val > 0.0 { "INFINITY".to_string() } else { "-INFINITY".to_string() }
+    } else {
+        format!("{:e}", val)
+    }
+}
+
+/// Writes `declaration = { values };`. Arrays are declared one element larger than needed so empty ones are valid C
+fn write_array(out: &mut impl Write, declaration: &str, values: impl Iterator<Item = String>) -> io::Result<()> {
+    writeln!(out, "{} = {{", declaration)?;
+    let values: Vec<String> = values.collect();
+    for chunk in values.chunks(VALUES_PER_LINE) {
+        writeln!(out, "    {},", chunk.join(", "))?;
+    }
+    if values.is_empty() {
+        writeln!(out, "    0")?;
+    }
+    writeln!(out, "}};")?;
+    writeln!(out)
+}
@@ -0,0 +1,1494 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use itertools::Itertools;
+use sprs::TriMat;
+
+use crate::crc32::Crc32;
+use crate::mmio::MmSymmetry;
+
+// Limit on the facets searched for vertex_gen shapes, so a broken file cannot keep the reader busy forever
+const MAX_HULL_SUBSETS: u128 = 1 << 22;
+// Vectors grow by chunks while read, so a broken length runs into the end of the file instead of allocating it whole
+const READ_CHUNK_LEN: usize = 1 << 16;
+
+/// Magic number opening versioned UZP files. Legacy files start straight with nnz
+pub const UZP_MAGIC: [u8; 4] = *b"UZPF";
+/// Latest version of the versioned layout
+pub const UZP_VERSION: u16 = 2;
+// Flags byte of versioned files
+const UZP_FLAG_WIDE: u8 = 0x01;
+// Bytes of the prefix of versioned files and of every entry of the section table
+const UZP_PREFIX_LEN: u64 = 8;
+const UZP_SECTION_ENTRY_LEN: u64 = 20;
+
+/* UZP FILE LAYOUT (little endian)
+ *
+ *   PREFIX     Versioned files only: 4 x u8 magic "UZPF", u16 version, u8 flags (0x01 = wide), u8 symmetry.
+ *              Legacy files have no prefix, and keep the symmetry on the uninc format byte
+ *   SECTIONS   Version 2 onwards: 5 x { u64 offset, u64 length, u32 crc32 } of the header, shapes (HIER included),
+ *              origins, uninc and data sections, in that order. The checksum of the header also covers the prefix
+ *   HEADER     I nnz, I inc_nnz, I nrows, I ncols, i16 dims (always 2), I num_shapes, I num_hier_shapes,
+ *              P data_ptr (byte offset of the data section), i16 max_dims, max_dims x i32 (unused, zeros)
+ *   SHAPES     num_shapes x { ID id, i16 encoding, i16 dim, dim x i32 min point, dim x i32 max point,
+ *                             encoding data, 2*dim x i32 lattice }
+ *              Encoding data depends on the encoding:
+ *                0 = vertex_rec  dim x i32 strides
+ *                1 = vertex_gen  i32 num_vertices, num_vertices x (dim x i32) vertices
+ *                2 = ineqs       i32 num_ineqs, num_ineqs x (dim x i32) U rows, num_ineqs x i32 w
+ *              Index points p of vertex_rec shapes go from the min point to the max point in steps of the stride
+ *              along every axis, at row and col offsets sum(p[axis] * lattice[2*axis..2*axis+2]) from the origin.
+ *              Non rectangular shapes hold the points of their min/max bounding box in the convex hull of
+ *              the vertices or satisfying U·p + w >= 0
+ *   HIER       num_hier_shapes x { ID id, ID sub shape id, i16 dim, dim x i32 min point, dim x i32 max point,
+ *                                  dim x i32 strides, 2*dim x i32 lattice }
+ *              A hyperrectangle of repetitions of a shape written before it, first axes outermost. Shapes and
+ *              hierarchical shapes share ids
+ *   ORIGINS    I num_origins, num_origins x { ID shape id, I row, I col, I data offset }
+ *   UNINC      u8 format (low nibble: 0 = CSR, 2 = COO. High nibble: symmetry of the stored matrix in legacy files),
+ *              CSR: (nrows+1) x I indptr, (nnz-inc_nnz) x I indices
+ *              COO: (nnz-inc_nnz) x I rows, (nnz-inc_nnz) x I cols
+ *   DATA       nnz x f64 at data_ptr. Shape points in origin order first, then the uninc nonzeros
+ *
+ * Counters, offsets and indices (I) are i32 and shape ids (ID) i16, or i64 and i32 in wide files. The data
+ * pointer (P) is i32 in legacy files and u64 in versioned ones.
+ */
+
+/// Sections of a UZP file, in file order. Hierarchical shapes belong to the shapes section
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UzpSection {
+    Header,
+    Shapes,
+    Origins,
+    Uninc,
+    Data,
+}
+
+impl UzpSection {
+    pub const ALL: [UzpSection; 5] = [UzpSection::Header, UzpSection::Shapes, UzpSection::Origins, UzpSection::Uninc, UzpSection::Data];
+}
+
+impl fmt::Display for UzpSection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            UzpSection::Header => "header",
+            UzpSection::Shapes => "shapes",
+            UzpSection::Origins => "origins",
+            UzpSection::Uninc => "uninc",
+            UzpSection::Data => "data",
+        })
+    }
+}
+
+/// Entry of the section table of version 2 files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UzpSectionEntry {
+    pub offset: u64,
+    pub length: u64,
+    pub crc32: u32,
+}
+
+/// Why a UZP file could not be read, naming the section at fault
+#[derive(Debug)]
+pub enum UzpError {
+    Io(io::Error),
+    UnsupportedVersion(u16),
+    /// The file ends before the section does
+    Truncated { section: UzpSection },
+    ChecksumMismatch { section: UzpSection, stored: u32, computed: u32 },
+    Invalid { section: UzpSection, message: String },
+    /// A nonzero (0-based row and col) lies outside the matrix
+    OutOfBounds { section: UzpSection, row: i64, col: i64, nrows: usize, ncols: usize },
+    CountMismatch { section: UzpSection, what: &'static str, expected: usize, found: usize },
+}
+
+impl UzpError {
+    /// Errors found while parsing a section. Running out of bytes means the section is truncated
+    fn in_section(section: UzpSection, error: io::Error) -> UzpError {
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof => UzpError::Truncated { section },
+            io::ErrorKind::InvalidData => UzpError::Invalid { section, message: error.to_string() },
+            _ => UzpError::Io(error),
+        }
+    }
+}
+
+impl fmt::Display for UzpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UzpError::Io(e) => write!(f, "{}", e),
+            UzpError::UnsupportedVersion(version) => write!(f, "unsupported UZP version {} (latest supported is {})", version, UZP_VERSION),
+            UzpError::Truncated { section } => write!(f, "the file ends in the middle of the {} section", section),
+            UzpError::ChecksumMismatch { section, stored, computed } => {
+                write!(f, "the {} section is damaged, its checksum is {:08x} instead of {:08x}", section, computed, stored)
+            },
+            UzpError::Invalid { section, message } => write!(f, "invalid {} section: {}", section, message),
+            UzpError::OutOfBounds { section, row, col, nrows, ncols } => {
+                write!(f, "the {} section places a nonzero at ({}, {}), outside the {}x{} matrix", section, row + 1, col + 1, nrows, ncols)
+            },
+            UzpError::CountMismatch { section, what, expected, found } => {
+                write!(f, "the {} section holds {} {}, but {} were expected", section, found, what, expected)
+            },
+        }
+    }
+}
+
+impl std::error::Error for UzpError {}
+
+impl From<io::Error> for UzpError {
+    fn from(error: io::Error) -> UzpError {
+        UzpError::Io(error)
+    }
+}
+
+/// Integer widths of a UZP file. Legacy files (version 0) have no prefix and are never wide
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UzpLayout {
+    pub version: u16,
+    /// Counters, offsets and indices are i64 and shape ids i32, instead of i32 and i16
+    pub wide: bool,
+}
+
+impl UzpLayout {
+    pub const LEGACY: UzpLayout = UzpLayout { version: 0, wide: false };
+
+    pub fn versioned(wide: bool) -> UzpLayout {
+        UzpLayout { version: UZP_VERSION, wide }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 0
+    }
+
+    /// Whether counters and indices up to `max_count` and shape ids up to `max_id` can be written
+    pub fn fits(&self, max_count: usize, max_id: usize) -> bool {
+        self.wide || (max_count <= i32::MAX as usize && max_id <= i16::MAX as usize)
+    }
+
+    /// Layout written for counters and indices up to `max_count` and shape ids up to `max_id`: the legacy one if asked
+    /// for, or else the versioned one, wide if asked for or needed. Legacy layouts may not fit
+    pub fn for_write(legacy: bool, wide: bool, max_count: usize, max_id: usize) -> UzpLayout {
+        if legacy { UzpLayout::LEGACY } else { UzpLayout::versioned(wide || !UzpLayout::versioned(false).fits(max_count, max_id)) }
+    }
+
+    /// Bytes of every counter, offset and index
+    pub fn count_bytes(&self) -> u64 {
+        if self.wide { 8 } else { 4 }
+    }
+
+    /// Bytes of every shape id
+    pub fn id_bytes(&self) -> u64 {
+        if self.wide { 4 } else { 2 }
+    }
+
+    /// Whether the file starts with a section table holding the position and checksum of every section
+    pub fn has_section_table(&self) -> bool {
+        self.version >= 2
+    }
+
+    /// Reads the prefix of versioned files, returning their layout, symmetry and the raw prefix bytes. Legacy files
+    /// are rewound to their start
+    fn read_prefix<R: Read + Seek>(file: &mut R) -> Result<(UzpLayout, Option<MmSymmetry>, Vec<u8>), UzpError> {
+        let header_error = |e| UzpError::in_section(UzpSection::Header, e);
+
+        let mut prefix = vec![0u8; UZP_PREFIX_LEN as usize];
+        file.read_exact(&mut prefix[..4]).map_err(header_error)?;
+        if prefix[..4] != UZP_MAGIC {
+            file.seek(SeekFrom::Start(0))?;
+            return Ok((UzpLayout::LEGACY, None, vec![]));
+        }
+        file.read_exact(&mut prefix[4..]).map_err(header_error)?;
+
+        let version = u16::from_le_bytes([prefix[4], prefix[5]]);
+        if version == 0 || version > UZP_VERSION {
+            return Err(UzpError::UnsupportedVersion(version));
+        }
+        let (flags, symmetry_code) = (prefix[6], prefix[7]);
+        let symmetry = symmetry_from_uzp_code(symmetry_code)
+            .ok_or_else(|| UzpError::Invalid { section: UzpSection::Header, message: format!("unsupported symmetry code {}", symmetry_code) })?;
+
+        Ok((UzpLayout { version, wide: flags & UZP_FLAG_WIDE != 0 }, Some(symmetry), prefix))
+    }
+
+    /// Writes the prefix of versioned files, followed by a blank section table that `write_section_table` fills
+    /// once every section is written. Legacy files have none
+    pub fn write_prefix(&self, file: &mut impl Write, symmetry: MmSymmetry) -> io::Result<()> {
+        if self.is_legacy() {
+            return Ok(());
+        }
+        file.write_all(&UZP_MAGIC)?;
+        file.write_u16::<LittleEndian>(self.version)?;
+        file.write_u8(if self.wide { UZP_FLAG_WIDE } else { 0 })?;
+        file.write_u8(symmetry_to_uzp_code(symmetry))?;
+
+        if self.has_section_table() {
+            file.write_all(&[0u8; (UzpSection::ALL.len() as u64 * UZP_SECTION_ENTRY_LEN) as usize])?;
+        }
+        Ok(())
+    }
+
+    fn read_section_table(&self, file: &mut impl Read) -> Result<Option<[UzpSectionEntry; 5]>, UzpError> {
+        if !self.has_section_table() {
+            return Ok(None);
+        }
+
+        let mut sections = [UzpSectionEntry::default(); 5];
+        for entry in sections.iter_mut() {
+            let offset = file.read_u64::<LittleEndian>().map_err(|e| UzpError::in_section(UzpSection::Header, e))?;
+            let length = file.read_u64::<LittleEndian>().map_err(|e| UzpError::in_section(UzpSection::Header, e))?;
+            let crc32 = file.read_u32::<LittleEndian>().map_err(|e| UzpError::in_section(UzpSection::Header, e))?;
+            *entry = UzpSectionEntry { offset, length, crc32 };
+        }
+        Ok(Some(sections))
+    }
+
+    /// Fills the section table of a versioned file whose sections start at `section_starts` (in `UzpSection::ALL`
+    /// order) and end at `end`, reading the sections back to checksum them. The file is left at `end`
+    pub fn write_section_table<F: Read + Write + Seek>(&self, file: &mut F, section_starts: [u64; 5], end: u64) -> io::Result<()> {
+        if !self.has_section_table() {
+            return Ok(());
+        }
+
+        let mut sections = [UzpSectionEntry::default(); 5];
+        for (idx, section) in UzpSection::ALL.iter().enumerate() {
+            let offset = section_starts[idx];
+            let length = section_starts.get(idx + 1).unwrap_or(&end) - offset;
+
+            let mut crc = Crc32::new();
+            if *section == UzpSection::Header {
+                update_crc(file, 0, UZP_PREFIX_LEN, &mut crc)?;
+            }
+            update_crc(file, offset, length, &mut crc)?;
+            sections[idx] = UzpSectionEntry { offset, length, crc32: crc.finish() };
+        }
+
+        file.seek(SeekFrom::Start(UZP_PREFIX_LEN))?;
+        for entry in &sections {
+            file.write_u64::<LittleEndian>(entry.offset)?;
+            file.write_u64::<LittleEndian>(entry.length)?;
+            file.write_u32::<LittleEndian>(entry.crc32)?;
+        }
+        file.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    fn read_count(&self, file: &mut impl Read, what: &str) -> io::Result<usize> {
+        if !self.wide {
+            return read_count(file, what);
+        }
+        match file.read_i64::<LittleEndian>()? {
+            count if count >= 0 => Ok(count as usize),
+            count => Err(invalid_data(format!("negative {} ({})", what, count))),
+        }
+    }
+
+    fn read_index(&self, file: &mut impl Read) -> io::Result<i64> {
+        if self.wide { file.read_i64::<LittleEndian>() } else { file.read_i32::<LittleEndian>().map(|v| v as i64) }
+    }
+
+    fn read_index_vec(&self, file: &mut impl Read, len: usize) -> io::Result<Vec<i64>> {
+        if self.wide {
+            read_chunked(file, len, |file, values| file.read_i64_into::<LittleEndian>(values))
+        } else {
+            Ok(read_i32_vec(file, len)?.into_iter().map(|v| v as i64).collect())
+        }
+    }
+
+    fn read_id(&self, file: &mut impl Read) -> io::Result<i32> {
+        if self.wide { file.read_i32::<LittleEndian>() } else { file.read_i16::<LittleEndian>().map(|id| id as i32) }
+    }
+
+    fn read_data_ptr(&self, file: &mut impl Read) -> io::Result<u64> {
+        if self.is_legacy() { read_count(file, "data_ptr").map(|ptr| ptr as u64) } else { file.read_u64::<LittleEndian>() }
+    }
+
+    pub fn write_count(&self, file: &mut impl Write, count: usize) -> io::Result<()> {
+        self.write_index(file, count as i64)
+    }
+
+    pub fn write_index(&self, file: &mut impl Write, value: i64) -> io::Result<()> {
+        if self.wide {
+            file.write_i64::<LittleEndian>(value)
+        } else {
+            let value = i32::try_from(value).map_err(|_| invalid_data(format!("{} does not fit in a narrow UZP file", value)))?;
+            file.write_i32::<LittleEndian>(value)
+        }
+    }
+
+    pub fn write_id(&self, file: &mut impl Write, id: i32) -> io::Result<()> {
+        if self.wide {
+            file.write_i32::<LittleEndian>(id)
+        } else {
+            let id = i16::try_from(id).map_err(|_| invalid_data(format!("shape id {} does not fit in a narrow UZP file", id)))?;
+            file.write_i16::<LittleEndian>(id)
+        }
+    }
+
+    pub fn write_data_ptr(&self, file: &mut impl Write, data_ptr: u64) -> io::Result<()> {
+        if self.is_legacy() {
+            let data_ptr = i32::try_from(data_ptr).map_err(|_| invalid_data(format!("data section at byte {} does not fit in a legacy UZP file", data_ptr)))?;
+            file.write_i32::<LittleEndian>(data_ptr)
+        } else {
+            file.write_u64::<LittleEndian>(data_ptr)
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct UzpHeader {
+    pub layout: UzpLayout,
+    pub nnz: usize,
+    pub inc_nnz: usize,
+    pub nrows: usize,
+    pub ncols: usize,
+    pub dims: i16,
+    pub num_shapes: usize,
+    pub num_hier_shapes: usize,
+    pub data_ptr: u64,
+    pub max_dims: i16,
+    /// Section table of version 2 files, in `UzpSection::ALL` order
+    pub sections: Option<[UzpSectionEntry; 5]>,
+}
+
+/// How the index points of a shape are described. Every encoding has a bounding box (min and max point)
+/// and a lattice, moving `lattice[2*axis..2*axis+2]` rows and columns per step along each axis
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UzpShapeEncoding {
+    /// Hyperrectangle, every point of the box
+    #[default]
+    VertexRec,
+    /// Integer points of the convex hull of a list of vertices
+    VertexGen,
+    /// Points of the box satisfying U·p + w >= 0
+    Ineqs,
+}
+
+impl UzpShapeEncoding {
+    pub fn code(&self) -> i16 {
+        match self {
+            UzpShapeEncoding::VertexRec => 0,
+            UzpShapeEncoding::VertexGen => 1,
+            UzpShapeEncoding::Ineqs => 2,
+        }
+    }
+
+    /// Name of the encoding in `--shape-encoding`
+    pub fn name(&self) -> &'static str {
+        match self {
+            UzpShapeEncoding::VertexRec => "vertex_rec",
+            UzpShapeEncoding::VertexGen => "vertex_gen",
+            UzpShapeEncoding::Ineqs => "ineqs",
+        }
+    }
+
+    pub fn from_code(code: i16) -> Option<UzpShapeEncoding> {
+        match code {
+            0 => Some(UzpShapeEncoding::VertexRec),
+            1 => Some(UzpShapeEncoding::VertexGen),
+            2 => Some(UzpShapeEncoding::Ineqs),
+            _ => None,
+        }
+    }
+}
+
+/// Shape of `dim` axes. Points are laid out in the data section in lexicographic order, first axis outermost.
+/// Hierarchical shapes are expanded on read, so they are plain shapes with the axes of the repetition first.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct UzpShape {
+    pub id: i32,
+    pub encoding: UzpShapeEncoding,
+    pub dim: usize,
+    pub min_point: Vec<i32>,
+    pub max_point: Vec<i32>,
+    /// Step between index points along every axis, from the min point. Only vertex_rec shapes and hierarchical
+    /// repetitions write it, it is 1 for the rest
+    pub strides: Vec<i32>,
+    /// vertex_gen only
+    pub vertices: Vec<Vec<i32>>,
+    /// Inequalities U·p + w >= 0 of ineqs shapes, or the facets of the hull of vertex_gen shapes
+    pub u: Vec<Vec<i64>>,
+    pub w: Vec<i64>,
+    pub lattice: Vec<i32>,
+    /// Shape repeated by hierarchical shapes
+    pub sub_shape: Option<i32>,
+}
+
+/// Number of points, (row, col) offset of the first one and (row, col) step between them along an axis of a shape
+pub type AxisWalk = (usize, (i64, i64), (i64, i64));
+
+#[derive(Clone, Copy, Debug)]
+pub struct UzpOrigin {
+    pub shape_id: i32,
+    pub row: i64,
+    pub col: i64,
+    pub data_offset: i64,
+}
+
+/// Positions of the nonzeros not included in any shape
+#[derive(Clone, Debug)]
+pub enum UzpUninc {
+    Csr { indptr: Vec<i64>, indices: Vec<i64> },
+    Coo { rows: Vec<i64>, cols: Vec<i64> },
+}
+
+#[derive(Clone, Debug)]
+pub struct UzpFile {
+    pub header: UzpHeader,
+    pub shapes: Vec<UzpShape>,
+    pub origins: Vec<UzpOrigin>,
+    pub uninc: UzpUninc,
+    /// Symmetry of the stored matrix. Anything but General means only the lower triangle is stored
+    pub symmetry: MmSymmetry,
+    pub data: Vec<f64>,
+    shape_index: HashMap<i32, usize>,
+    // Index in `shapes` of the shape of every origin
+    origin_shapes: Vec<usize>,
+    // Points of every shape, in the same order as `shapes`
+    shape_points: Vec<Vec<(i64, i64)>>,
+}
+
+impl UzpShape {
+    /// Number of points of the shape, which is also the number of values it takes in the data section
+    pub fn npoints(&self) -> usize {
+        match self.encoding {
+            UzpShapeEncoding::VertexRec => (0..self.dim).map(|axis| self.axis_len(axis)).product(),
+            _ => {
+                let (mut npoints, stride) = (0, self.strides[self.dim - 1] as i64);
+                self.for_each_run(|_, first, last| npoints += ((last - first) / stride + 1) as usize);
+                npoints
+            },
+        }
+    }
+
+    /// Number of index points along an axis of the bounding box
+    pub fn axis_len(&self, axis: usize) -> usize {
+        let (min, max) = (self.min_point[axis] as i64, self.max_point[axis] as i64);
+        if max < min { 0 } else { ((max - min) / self.strides[axis] as i64 + 1) as usize }
+    }
+
+    /// Walk along an axis of the bounding box: number of points, (row, col) offset of the first one and (row, col) step between them
+    pub fn axis_walk(&self, axis: usize) -> AxisWalk {
+        let (row_step, col_step) = (self.lattice[2*axis] as i64, self.lattice[2*axis+1] as i64);
+        let (min, stride) = (self.min_point[axis] as i64, self.strides[axis] as i64);
+        (self.axis_len(axis), (min * row_step, min * col_step), (stride * row_step, stride * col_step))
+    }
+
+    /// Walks along every axis, as in `axis_walk`, if the points of the shape fill a hyperrectangle of evenly spaced index
+    /// points, which need not be its bounding box. None for any other shape
+    pub fn box_walks(&self) -> Option<Vec<AxisWalk>> {
+        if self.encoding == UzpShapeEncoding::VertexRec {
+            return Some((0..self.dim).map(|axis| self.axis_walk(axis)).collect());
+        }
+
+        let index_points = self.index_points();
+        let mut box_points: usize = 1;
+        let walks = (0..self.dim)
+            .map(|axis| {
+                let values: BTreeSet<i64> = index_points.iter().map(|point| point[axis]).collect();
+                let min = *values.first()?;
+                let step = values.iter().nth(1).map_or(1, |second| second - min);
+                if values.iter().enumerate().any(|(k, value)| *value != min + k as i64 * step) {
+                    return None;
+                }
+                box_points *= values.len();
+
+                let (row_step, col_step) = (self.lattice[2*axis] as i64, self.lattice[2*axis+1] as i64);
+                Some((values.len(), (min * row_step, min * col_step), (step * row_step, step * col_step)))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        if box_points == index_points.len() { Some(walks) } else { None }
+    }
+
+    /// Walks along both sides of 2D shapes whose points fill a parallelogram, as in `box_walks`. The first walk starts at a
+    /// corner and the second one at no offset, so their starts add up to it. The side of the first facet goes second,
+    /// as in the parallelograms written by `write_uzp`. None for any other shape
+    pub fn parallelogram_walks(&self) -> Option<Vec<AxisWalk>> {
+        if self.encoding == UzpShapeEncoding::VertexRec || self.dim != 2 {
+            return None;
+        }
+
+        // Sides run along the facets, so they are perpendicular to their normals
+        let sides: Vec<(i64, i64)> = self.u.iter().filter_map(|u_row| forward_direction(-u_row[1], u_row[0])).unique().collect();
+        let (outer, inner) = match sides[..] {
+            [inner, outer] => (outer, inner),
+            _ => return None,
+        };
+        let det = outer.0 * inner.1 - outer.1 * inner.0;
+        if det.abs() != 1 {
+            return None;
+        }
+
+        // Every index point is k·outer + l·inner, and they must fill the (k, l) box
+        let index_points = self.index_points();
+        let (ks, ls): (Vec<i64>, Vec<i64>) = index_points
+            .iter()
+            .map(|point| (det * (point[0] * inner.1 - point[1] * inner.0), det * (outer.0 * point[1] - outer.1 * point[0])))
+            .unzip();
+        let (k_min, k_max) = ks.into_iter().minmax().into_option()?;
+        let (l_min, l_max) = ls.into_iter().minmax().into_option()?;
+        if (k_max - k_min + 1) * (l_max - l_min + 1) != index_points.len() as i64 {
+            return None;
+        }
+
+        let lattice: Vec<i64> = self.lattice.iter().map(|v| *v as i64).collect();
+        let offset = |(i, j): (i64, i64)| (i * lattice[0] + j * lattice[2], i * lattice[1] + j * lattice[3]);
+        let corner = (k_min * outer.0 + l_min * inner.0, k_min * outer.1 + l_min * inner.1);
+        Some(vec![
+            ((k_max - k_min + 1) as usize, offset(corner), offset(outer)),
+            ((l_max - l_min + 1) as usize, (0, 0), offset(inner)),
+        ])
+    }
+
+    /// (row, col) offsets of the points of the shape from its origin, in data order
+    pub fn points(&self) -> Vec<(i64, i64)> {
+        match self.encoding {
+            UzpShapeEncoding::VertexRec => {
+                let mut points: Vec<(i64, i64)> = Vec::with_capacity(self.npoints());
+                push_points(self, 0, (0, 0), &mut points);
+                points
+            },
+            _ => self
+                .index_points()
+                .iter()
+                .map(|point| point.iter().enumerate().fold((0, 0), |(row, col), (axis, k)| {
+                    (row + self.lattice[2*axis] as i64 * k, col + self.lattice[2*axis+1] as i64 * k)
+                }))
+                .collect(),
+        }
+    }
+
+    /// Expands a hierarchical shape repeating `sub_shape` over the hyperrectangle of the leading axes. Repetitions of
+    /// non rectangular shapes are described by the inequalities of the sub-shape.
+    fn hierarchical(id: i32, min_point: Vec<i32>, max_point: Vec<i32>, strides: Vec<i32>, lattice: Vec<i32>, sub_shape: &UzpShape) -> UzpShape {
+        let outer_dim = min_point.len();
+        let concat = |outer: Vec<i32>, inner: &[i32]| outer.into_iter().chain(inner.iter().copied()).collect::<Vec<i32>>();
+
+        let encoding = match sub_shape.encoding {
+            UzpShapeEncoding::VertexRec => UzpShapeEncoding::VertexRec,
+            _ => UzpShapeEncoding::Ineqs,
+        };
+        let u = sub_shape.u.iter().map(|u_row| std::iter::repeat_n(0, outer_dim).chain(u_row.iter().copied()).collect()).collect();
+
+        UzpShape {
+            id,
+            encoding,
+            dim: outer_dim + sub_shape.dim,
+            min_point: concat(min_point, &sub_shape.min_point),
+            max_point: concat(max_point, &sub_shape.max_point),
+            strides: concat(strides, &sub_shape.strides),
+            vertices: vec![],
+            u,
+            w: sub_shape.w.clone(),
+            lattice: concat(lattice, &sub_shape.lattice),
+            sub_shape: Some(sub_shape.id),
+        }
+    }
+
+    /// Strides must be positive
+    fn validate(&self) -> io::Result<()> {
+        if let Some((axis, stride)) = self.strides.iter().enumerate().find(|(_, stride)| **stride < 1) {
+            return Err(invalid_data(format!("shape {} has stride {} on axis {}", self.id, stride, axis)));
+        }
+        Ok(())
+    }
+
+    /// Number of index points of the bounding box along every axis but the last one, which is what walking the points
+    /// of non rectangular shapes costs
+    fn leading_volume(&self) -> u128 {
+        (0..self.dim - 1).map(|axis| self.axis_len(axis) as u128).product()
+    }
+
+    /// Calls `visit` with the leading coordinates of every index point of the bounding box but the last axis, and the
+    /// first and last values of the last axis in the shape, if any. The inequalities bound the last axis to an interval
+    fn for_each_run(&self, mut visit: impl FnMut(&[i64], i64, i64)) {
+        if self.min_point.iter().zip(self.max_point.iter()).any(|(min, max)| min > max) {
+            return;
+        }
+
+        let last = self.dim - 1;
+        let (min, max, stride) = (self.min_point[last] as i128, self.max_point[last] as i128, self.strides[last] as i128);
+        let mut leading: Vec<i64> = self.min_point[..last].iter().map(|min| *min as i64).collect();
+        loop {
+            // u_last·k + rest >= 0 bounds k from below or above, or holds for no k at all
+            let (mut lo, mut hi) = (min, max);
+            for (u_row, w) in self.u.iter().zip(self.w.iter()) {
+                let rest = u_row[..last].iter().zip(leading.iter()).map(|(u, k)| *u as i128 * *k as i128).sum::<i128>() + *w as i128;
+                let u_last = u_row[last] as i128;
+                if u_last > 0 {
+                    lo = lo.max(-rest.div_euclid(u_last));
+                } else if u_last < 0 {
+                    hi = hi.min(rest.div_euclid(-u_last));
+                } else if rest < 0 {
+                    hi = lo - 1;
+                }
+            }
+
+            // Only the values a whole number of strides away from the min point are index points
+            let first = min + (lo - min + stride - 1).div_euclid(stride) * stride;
+            if first <= hi {
+                visit(&leading, first as i64, (first + (hi - first).div_euclid(stride) * stride) as i64);
+            }
+
+            // Next leading point, last leading axis innermost
+            let mut axis = last;
+            loop {
+                if axis == 0 {
+                    return;
+                }
+                axis -= 1;
+                if leading[axis] + (self.strides[axis] as i64) <= self.max_point[axis] as i64 {
+                    leading[axis] += self.strides[axis] as i64;
+                    break;
+                }
+                leading[axis] = self.min_point[axis] as i64;
+            }
+        }
+    }
+
+    /// Index points of the bounding box satisfying the inequalities, in lexicographic order
+    fn index_points(&self) -> Vec<Vec<i64>> {
+        let mut points: Vec<Vec<i64>> = vec![];
+        let stride = self.strides[self.dim - 1] as usize;
+        self.for_each_run(|leading, first, last| {
+            points.extend((first..=last).step_by(stride).map(|k| leading.iter().copied().chain(std::iter::once(k)).collect()));
+        });
+        points
+    }
+}
+
+#[allow(dead_code)]
+impl UzpUninc {
+    /// (row, col) positions of the uninc nonzeros, in data order
+    pub fn positions(&self) -> Vec<(usize, usize)> {
+        match self {
+            UzpUninc::Csr { indptr, indices } => indptr
+                .windows(2)
+                .enumerate()
+                .flat_map(|(row, ptrs)| indices[ptrs[0] as usize..ptrs[1] as usize].iter().map(move |col| (row, *col as usize)))
+                .collect(),
+            UzpUninc::Coo { rows, cols } => rows.iter().zip(cols.iter()).map(|(row, col)| (*row as usize, *col as usize)).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            UzpUninc::Csr { indices, .. } => indices.len(),
+            UzpUninc::Coo { rows, .. } => rows.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl UzpFile {
+    /// Reads and checks a UZP file. Sections listed in the section table of version 2 files are checked against
+    /// their checksum, and every file must place its nonzeros inside the matrix and account for all of them
+    pub fn from_file(path: &str) -> Result<UzpFile, UzpError> {
+        let mut file = BufReader::new(File::open(path)?);
+        let file_len = file.get_ref().metadata()?.len();
+
+        // Read header, after the prefix and section table of versioned files
+        let (layout, prefix_symmetry, prefix) = UzpLayout::read_prefix(&mut file)?;
+        let sections = layout.read_section_table(&mut file)?;
+        let header = read_section(&mut file, file_len, sections.as_ref(), UzpSection::Header, &prefix, |file| read_header(file, layout, sections))?;
+        header.check_data_section(file_len)?;
+
+        // Read shapes and hierarchical shapes
+        let (shapes, shape_index) = read_section(&mut file, file_len, sections.as_ref(), UzpSection::Shapes, &[], |file| read_shapes(file, &header))?;
+        let shape_points: Vec<Vec<(i64, i64)>> = shapes.iter().map(|shape| expand_shape(&header, shape)).collect::<Result<_, _>>()?;
+
+        // Read origins
+        let origins = read_section(&mut file, file_len, sections.as_ref(), UzpSection::Origins, &[], |file| read_origins(file, layout, &shape_index))?;
+        let origin_shapes: Vec<usize> = origins.iter().map(|origin| shape_index[&origin.shape_id]).collect();
+        check_origins(&header, &origins, &origin_shapes, &shape_points)?;
+
+        // Read uninc
+        let (uninc, symmetry) = read_section(&mut file, file_len, sections.as_ref(), UzpSection::Uninc, &[], |file| read_uninc(file, &header, prefix_symmetry))?;
+        uninc.check(&header)?;
+
+        // Read data
+        if sections.is_none() {
+            file.seek(SeekFrom::Start(header.data_ptr))?;
+        }
+        let data = read_section(&mut file, file_len, sections.as_ref(), UzpSection::Data, &[], |file| {
+            read_chunked(file, header.nnz, |file, values| file.read_f64_into::<LittleEndian>(values))
+        })?;
+
+        Ok(UzpFile { header, shapes, origins, uninc, symmetry, data, shape_index, origin_shapes, shape_points })
+    }
+
+    pub fn shapes(&self) -> impl Iterator<Item = &UzpShape> {
+        self.shapes.iter()
+    }
+
+    pub fn shape(&self, id: i32) -> &UzpShape {
+        &self.shapes[self.shape_index[&id]]
+    }
+
+    pub fn origins(&self) -> impl Iterator<Item = &UzpOrigin> {
+        self.origins.iter()
+    }
+
+    /// (row, col) offsets of the points of a shape from its origin, in data order
+    pub fn shape_points(&self, id: i32) -> &[(i64, i64)] {
+        &self.shape_points[self.shape_index[&id]]
+    }
+
+    /// Origins along with their shape
+    pub fn origin_shapes(&self) -> impl Iterator<Item = (&UzpOrigin, &UzpShape)> {
+        self.origins.iter().zip(self.origin_shapes.iter()).map(move |(origin, idx)| (origin, &self.shapes[*idx]))
+    }
+
+    /// Origins of every shape, in the order of `shapes()`
+    pub fn origins_by_shape(&self) -> Vec<Vec<&UzpOrigin>> {
+        let mut origins: Vec<Vec<&UzpOrigin>> = vec![Vec::new(); self.shapes.len()];
+        for (origin, idx) in self.origins.iter().zip(self.origin_shapes.iter()) {
+            origins[*idx].push(origin);
+        }
+        origins
+    }
+
+    /// Stored (row, col, value) triplets, shape points in origin order first and then the uninc nonzeros.
+    /// Symmetric matrices only yield their stored triangle.
+    pub fn triplets(&self) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
+        let included = self.origins.iter().zip(self.origin_shapes.iter()).flat_map(move |(origin, idx)| {
+            let points = &self.shape_points[*idx];
+            points.iter().enumerate().map(move |(idx, &(row, col))| {
+                ((origin.row + row) as usize, (origin.col + col) as usize, self.data[origin.data_offset as usize + idx])
+            })
+        });
+
+        let uninc = self.uninc
+            .positions()
+            .into_iter()
+            .enumerate()
+            .map(move |(idx, (row, col))| (row, col, self.data[self.header.inc_nnz + idx]));
+
+        included.chain(uninc)
+    }
+
+    /// Expands the file into a triplet matrix. With `mirror`, the stored triangle of symmetric matrices
+    /// is mirrored so the result holds the full matrix.
+    pub fn to_trimat(&self, mirror: bool) -> TriMat<f64> {
+        let (mut rowvec, mut colvec, mut datavec): (Vec<usize>, Vec<usize>, Vec<f64>) = (
+            Vec::with_capacity(self.header.nnz),
+            Vec::with_capacity(self.header.nnz),
+            Vec::with_capacity(self.header.nnz),
+        );
+        self.triplets().for_each(|(row, col, val)| {
+            rowvec.push(row);
+            colvec.push(col);
+            datavec.push(val);
+        });
+
+        if mirror {
+            mirror_stored_triangle(self.symmetry, &mut rowvec, &mut colvec, &mut datavec);
+        }
+
+        TriMat::from_triplets((self.header.nrows, self.header.ncols), rowvec, colvec, datavec)
+    }
+}
+
+impl UzpHeader {
+    /// The data section must hold nnz values, and be where the data pointer says
+    fn check_data_section(&self, file_len: u64) -> Result<(), UzpError> {
+        if self.inc_nnz > self.nnz {
+            return Err(UzpError::Invalid { section: UzpSection::Header, message: format!("inc_nnz {} is greater than nnz {}", self.inc_nnz, self.nnz) });
+        }
+
+        let data_len = 8 * self.nnz as u128;
+        match self.sections {
+            Some(sections) => {
+                let entry = sections[UzpSection::Data as usize];
+                if entry.offset != self.data_ptr {
+                    let message = format!("data pointer {} does not match the data section at byte {}", self.data_ptr, entry.offset);
+                    return Err(UzpError::Invalid { section: UzpSection::Header, message });
+                }
+                if entry.length as u128 != data_len {
+                    return Err(UzpError::CountMismatch { section: UzpSection::Data, what: "values", expected: self.nnz, found: (entry.length / 8) as usize });
+                }
+            },
+            None => {
+                if self.data_ptr as u128 + data_len > file_len as u128 {
+                    return Err(UzpError::Truncated { section: UzpSection::Data });
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[allow(dead_code)]
+impl UzpUninc {
+    /// CSR row pointers must go from 0 to the number of uninc nonzeros without decreasing, and every
+    /// position must be inside the matrix
+    fn check(&self, header: &UzpHeader) -> Result<(), UzpError> {
+        let section = UzpSection::Uninc;
+        let inside = |row: i64, col: i64| (0..header.nrows as i64).contains(&row) && (0..header.ncols as i64).contains(&col);
+        let out_of_bounds = |row: i64, col: i64| UzpError::OutOfBounds { section, row, col, nrows: header.nrows, ncols: header.ncols };
+
+        match self {
+            UzpUninc::Csr { indptr, indices } => {
+                if indptr[0] != 0 {
+                    return Err(UzpError::Invalid { section, message: format!("row pointers start at {} instead of 0", indptr[0]) });
+                }
+                if let Some(row) = indptr.windows(2).position(|ptrs| ptrs[1] < ptrs[0]) {
+                    return Err(UzpError::Invalid { section, message: format!("row pointers decrease after row {}", row + 1) });
+                }
+                let last = indptr[indptr.len() - 1] as usize;
+                if last != indices.len() {
+                    return Err(UzpError::CountMismatch { section, what: "nonzeros in its row pointers", expected: indices.len(), found: last });
+                }
+                for (row, ptrs) in indptr.windows(2).enumerate() {
+                    if let Some(col) = indices[ptrs[0] as usize..ptrs[1] as usize].iter().find(|col| !inside(row as i64, **col)) {
+                        return Err(out_of_bounds(row as i64, *col));
+                    }
+                }
+            },
+            UzpUninc::Coo { rows, cols } => {
+                if let Some((row, col)) = rows.iter().zip(cols.iter()).find(|(row, col)| !inside(**row, **col)) {
+                    return Err(out_of_bounds(*row, *col));
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Reader of a section. Sections listed in a section table are read whole and checked before parsing them,
+/// the rest are parsed straight from the file
+enum SectionReader<'a> {
+    Checked(Cursor<Vec<u8>>),
+    Stream(&'a mut BufReader<File>),
+}
+
+impl Read for SectionReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SectionReader::Checked(cursor) => cursor.read(buf),
+            SectionReader::Stream(file) => file.read(buf),
+        }
+    }
+}
+
+/// Parses a section, naming it on errors. With a section table, the section is checked against its checksum
+/// (which starts with `checked_prefix`) and must be consumed whole
+fn read_section<T>(
+    file: &mut BufReader<File>,
+    file_len: u64,
+    sections: Option<&[UzpSectionEntry; 5]>,
+    section: UzpSection,
+    checked_prefix: &[u8],
+    parse: impl FnOnce(&mut SectionReader) -> io::Result<T>,
+) -> Result<T, UzpError> {
+    let Some(entry) = sections.map(|sections| sections[section as usize]) else {
+        return parse(&mut SectionReader::Stream(file)).map_err(|e| UzpError::in_section(section, e));
+    };
+
+    if entry.offset.checked_add(entry.length).is_none_or(|end| end > file_len) {
+        return Err(UzpError::Truncated { section });
+    }
+    let mut bytes = vec![0u8; entry.length as usize];
+    file.seek(SeekFrom::Start(entry.offset))?;
+    file.read_exact(&mut bytes).map_err(|e| UzpError::in_section(section, e))?;
+
+    let mut crc = Crc32::new();
+    crc.update(checked_prefix);
+    crc.update(&bytes);
+    if crc.finish() != entry.crc32 {
+        return Err(UzpError::ChecksumMismatch { section, stored: entry.crc32, computed: crc.finish() });
+    }
+
+    let mut reader = SectionReader::Checked(Cursor::new(bytes));
+    let value = parse(&mut reader).map_err(|e| UzpError::in_section(section, e))?;
+    if let SectionReader::Checked(cursor) = reader {
+        if cursor.position() != entry.length {
+            return Err(UzpError::Invalid { section, message: format!("{} bytes left unread at its end", entry.length - cursor.position()) });
+        }
+    }
+    Ok(value)
+}
+
+fn read_header(file: &mut impl Read, layout: UzpLayout, sections: Option<[UzpSectionEntry; 5]>) -> io::Result<UzpHeader> {
+    let nnz = layout.read_count(file, "nnz")?;
+    let inc_nnz = layout.read_count(file, "inc_nnz")?;
+    let nrows = layout.read_count(file, "nrows")?;
+    let ncols = layout.read_count(file, "ncols")?;
+
+    let dims = file.read_i16::<LittleEndian>()?;
+    if dims != 2 {
+        return Err(invalid_data(format!("only 2D matrices are supported, found {} dimensions", dims)));
+    }
+
+    let num_shapes = layout.read_count(file, "num_shapes")?;
+    let num_hier_shapes = layout.read_count(file, "num_hier_shapes")?;
+    let data_ptr = layout.read_data_ptr(file)?;
+
+    // max_dims data is unused
+    let max_dims = file.read_i16::<LittleEndian>()?;
+    read_i32_vec(file, max_dims.max(0) as usize)?;
+
+    Ok(UzpHeader { layout, nnz, inc_nnz, nrows, ncols, dims, num_shapes, num_hier_shapes, data_ptr, max_dims, sections })
+}
+
+fn read_shapes(file: &mut impl Read, header: &UzpHeader) -> io::Result<(Vec<UzpShape>, HashMap<i32, usize>)> {
+    let layout = header.layout;
+    let (num_shapes, num_hier_shapes) = (header.num_shapes, header.num_hier_shapes);
+    let mut shapes: Vec<UzpShape> = Vec::with_capacity((num_shapes + num_hier_shapes).min(READ_CHUNK_LEN));
+    let mut shape_index: HashMap<i32, usize> = HashMap::with_capacity((num_shapes + num_hier_shapes).min(READ_CHUNK_LEN));
+
+    let add_shape = |shape: UzpShape, shapes: &mut Vec<UzpShape>, shape_index: &mut HashMap<i32, usize>| -> io::Result<()> {
+        shape.validate()?;
+        // Rectangular shapes are expanded whole, so they cannot hold more points than the matrix has included nonzeros
+        let volume = (0..shape.dim).map(|axis| shape.axis_len(axis) as u128).product::<u128>();
+        if shape.encoding == UzpShapeEncoding::VertexRec && volume > header.inc_nnz as u128 {
+            return Err(invalid_data(format!("shape {} has {} points, more than the {} included nonzeros", shape.id, volume, header.inc_nnz)));
+        }
+        if shape_index.insert(shape.id, shapes.len()).is_some() {
+            return Err(invalid_data(format!("shape {} is defined twice", shape.id)));
+        }
+        shapes.push(shape);
+        Ok(())
+    };
+
+    for _ in 0..num_shapes {
+        let id = layout.read_id(file)?;
+        let encoding_code = file.read_i16::<LittleEndian>()?;
+        let encoding = UzpShapeEncoding::from_code(encoding_code)
+            .ok_or_else(|| invalid_data(format!("shape {} uses unknown encoding {}", id, encoding_code)))?;
+
+        let dim = file.read_i16::<LittleEndian>()?;
+        if dim < 1 {
+            return Err(invalid_data(format!("shape {} has dimension {}", id, dim)));
+        }
+        let dim = dim as usize;
+
+        let min_point = read_i32_vec(file, dim)?;
+        let max_point = read_i32_vec(file, dim)?;
+
+        let mut strides: Vec<i32> = vec![1; dim];
+        let mut vertices: Vec<Vec<i32>> = vec![];
+        let (mut u, mut w): (Vec<Vec<i64>>, Vec<i64>) = (vec![], vec![]);
+        match encoding {
+            UzpShapeEncoding::VertexRec => strides = read_i32_vec(file, dim)?,
+            UzpShapeEncoding::VertexGen => {
+                let num_vertices = read_count(file, "number of vertices")?;
+                vertices = (0..num_vertices).map(|_| read_i32_vec(file, dim)).collect::<io::Result<_>>()?;
+                (u, w) = hull_inequalities(&vertices, dim).map_err(|e| invalid_data(format!("vertex_gen shape {}: {}", id, e)))?;
+            },
+            UzpShapeEncoding::Ineqs => {
+                let num_ineqs = read_count(file, "number of inequalities")?;
+                u = (0..num_ineqs).map(|_| read_i32_vec(file, dim).map(|row| row.into_iter().map(|v| v as i64).collect())).collect::<io::Result<_>>()?;
+                w = read_i32_vec(file, num_ineqs)?.into_iter().map(|v| v as i64).collect();
+            },
+        }
+        let lattice = read_i32_vec(file, 2 * dim)?;
+
+        add_shape(UzpShape { id, encoding, dim, min_point, max_point, strides, vertices, u, w, lattice, sub_shape: None }, &mut shapes, &mut shape_index)?;
+    }
+
+    // Hierarchical shapes are expanded over their (already read) sub-shape
+    for _ in 0..num_hier_shapes {
+        let id = layout.read_id(file)?;
+        let sub_id = layout.read_id(file)?;
+        let sub_idx = *shape_index
+            .get(&sub_id)
+            .ok_or_else(|| invalid_data(format!("hierarchical shape {} references shape {}, which is not defined before it", id, sub_id)))?;
+
+        let dim = file.read_i16::<LittleEndian>()?;
+        if dim < 1 {
+            return Err(invalid_data(format!("hierarchical shape {} has dimension {}", id, dim)));
+        }
+        let dim = dim as usize;
+
+        let min_point = read_i32_vec(file, dim)?;
+        let max_point = read_i32_vec(file, dim)?;
+        let strides = read_i32_vec(file, dim)?;
+        let lattice = read_i32_vec(file, 2 * dim)?;
+
+        let shape = UzpShape::hierarchical(id, min_point, max_point, strides, lattice, &shapes[sub_idx]);
+        add_shape(shape, &mut shapes, &mut shape_index)?;
+    }
+
+    Ok((shapes, shape_index))
+}
+
+fn read_origins(file: &mut impl Read, layout: UzpLayout, shape_index: &HashMap<i32, usize>) -> io::Result<Vec<UzpOrigin>> {
+    let num_origins = layout.read_count(file, "num_origins")?;
+    let mut origins: Vec<UzpOrigin> = Vec::with_capacity(num_origins.min(READ_CHUNK_LEN));
+    for _ in 0..num_origins {
+        let shape_id = layout.read_id(file)?;
+        let row = layout.read_index(file)?;
+        let col = layout.read_index(file)?;
+        let data_offset = layout.read_index(file)?;
+
+        if !shape_index.contains_key(&shape_id) {
+            return Err(invalid_data(format!("origin ({}, {}) references unknown shape {}", row, col, shape_id)));
+        }
+        origins.push(UzpOrigin { shape_id, row, col, data_offset });
+    }
+    Ok(origins)
+}
+
+/// Points of a shape. Non rectangular shapes are walked along their leading axes and counted before they are expanded, so
+/// both must stay within the nonzeros of the matrix
+fn expand_shape(header: &UzpHeader, shape: &UzpShape) -> Result<Vec<(i64, i64)>, UzpError> {
+    let section = UzpSection::Shapes;
+    if shape.encoding != UzpShapeEncoding::VertexRec {
+        let leading_volume = shape.leading_volume();
+        if leading_volume > header.nnz as u128 {
+            return Err(UzpError::CountMismatch { section, what: "leading index points in a shape", expected: header.nnz, found: leading_volume.min(usize::MAX as u128) as usize });
+        }
+        let npoints = shape.npoints();
+        if npoints > header.inc_nnz {
+            return Err(UzpError::CountMismatch { section, what: "points in a shape", expected: header.inc_nnz, found: npoints });
+        }
+    }
+    Ok(shape.points())
+}
+
+/// Every origin must take its values from the included ones, which they must account for, and place the points
+/// of its shape inside the matrix
+fn check_origins(header: &UzpHeader, origins: &[UzpOrigin], origin_shapes: &[usize], shape_points: &[Vec<(i64, i64)>]) -> Result<(), UzpError> {
+    let section = UzpSection::Origins;
+    let inside = |row: i64, col: i64| (0..header.nrows as i64).contains(&row) && (0..header.ncols as i64).contains(&col);
+
+    // (min row, max row, min col, max col) offsets of the points of every shape, so only their corners are checked
+    let extents: Vec<Option<(i64, i64, i64, i64)>> = shape_points
+        .iter()
+        .map(|points| points.iter().fold(None, |extent, &(row, col)| match extent {
+            None => Some((row, row, col, col)),
+            Some((min_row, max_row, min_col, max_col)) => Some((min_row.min(row), max_row.max(row), min_col.min(col), max_col.max(col))),
+        }))
+        .collect();
+
+    let mut inc_points: usize = 0;
+    for (origin, idx) in origins.iter().zip(origin_shapes.iter()) {
+        let npoints = shape_points[*idx].len();
+        inc_points += npoints;
+
+        if origin.data_offset < 0 || origin.data_offset as u128 + npoints as u128 > header.inc_nnz as u128 {
+            let message = format!("origin ({}, {}) takes values {}..{} of the data section, past the {} included nonzeros",
+                                  origin.row + 1, origin.col + 1, origin.data_offset, origin.data_offset as i128 + npoints as i128, header.inc_nnz);
+            return Err(UzpError::Invalid { section, message });
+        }
+
+        if let Some((min_row, max_row, min_col, max_col)) = extents[*idx] {
+            if !inside(origin.row.saturating_add(min_row), origin.col.saturating_add(min_col)) || !inside(origin.row.saturating_add(max_row), origin.col.saturating_add(max_col)) {
+                let (row, col) = shape_points[*idx]
+                    .iter()
+                    .map(|(row, col)| (origin.row.saturating_add(*row), origin.col.saturating_add(*col)))
+                    .find(|(row, col)| !inside(*row, *col))
+                    .unwrap();
+                return Err(UzpError::OutOfBounds { section, row, col, nrows: header.nrows, ncols: header.ncols });
+            }
+        }
+    }
+
+    if inc_points != header.inc_nnz {
+        return Err(UzpError::CountMismatch { section, what: "shape points", expected: header.inc_nnz, found: inc_points });
+    }
+    Ok(())
+}
+
+/// Reads the uninc nonzeros and the symmetry of the stored matrix, which legacy files keep on the high nibble of the format byte
+fn read_uninc(file: &mut impl Read, header: &UzpHeader, prefix_symmetry: Option<MmSymmetry>) -> io::Result<(UzpUninc, MmSymmetry)> {
+    let layout = header.layout;
+    let uninc_format_byte = file.read_u8()?;
+    let symmetry = match prefix_symmetry {
+        Some(symmetry) => symmetry,
+        None => symmetry_from_uzp_code(uninc_format_byte >> 4)
+            .ok_or_else(|| invalid_data(format!("unsupported symmetry code {}", uninc_format_byte >> 4)))?,
+    };
+    let uninc_nnz = header.nnz - header.inc_nnz;
+
+    let uninc = match uninc_format_byte & 0x0F {
+        0 => UzpUninc::Csr { indptr: layout.read_index_vec(file, header.nrows + 1)?, indices: layout.read_index_vec(file, uninc_nnz)? },
+        2 => UzpUninc::Coo { rows: layout.read_index_vec(file, uninc_nnz)?, cols: layout.read_index_vec(file, uninc_nnz)? },
+        format => return Err(invalid_data(format!("unsupported uninc format {}", format))),
+    };
+    Ok((uninc, symmetry))
+}
+
+// Symmetry of the stored matrix is kept on the high nibble of the uninc_format byte:
+//  -> General = 0
+//  -> Symmetric = 1
+//  -> SkewSymmetric = 2
+//  -> Hermitian = 3
+#[inline(always)]
+pub fn symmetry_to_uzp_code(symmetry: MmSymmetry) -> u8 {
+    match symmetry {
+        MmSymmetry::General => 0,
+        MmSymmetry::Symmetric => 1,
+        MmSymmetry::SkewSymmetric => 2,
+        MmSymmetry::Hermitian => 3,
+    }
+}
+
+#[inline(always)]
+pub fn symmetry_from_uzp_code(code: u8) -> Option<MmSymmetry> {
+    match code {
+        0 => Some(MmSymmetry::General),
+        1 => Some(MmSymmetry::Symmetric),
+        2 => Some(MmSymmetry::SkewSymmetric),
+        3 => Some(MmSymmetry::Hermitian),
+        _ => None,
+    }
+}
+
+/// Appends the mirrored counterpart of every off-diagonal entry, so a single stored triangle becomes the full matrix.
+#[inline(always)]
+fn mirror_stored_triangle(symmetry: MmSymmetry, rowvec: &mut Vec<usize>, colvec: &mut Vec<usize>, datavec: &mut Vec<f64>) {
+    if symmetry == MmSymmetry::General {
+        return;
+    }
+
+    let stored = rowvec.len();
+    for idx in 0..stored {
+        if rowvec[idx] != colvec[idx] {
+            let value = if symmetry == MmSymmetry::SkewSymmetric { -datavec[idx] } else { datavec[idx] };
+            rowvec.push(colvec[idx]);
+            colvec.push(rowvec[idx]);
+            datavec.push(value);
+        }
+    }
+}
+
+fn push_points(shape: &UzpShape, axis: usize, base: (i64, i64), points: &mut Vec<(i64, i64)>) {
+    let (len, (row_start, col_start), (row_step, col_step)) = shape.axis_walk(axis);
+    for ii in 0..len as i64 {
+        let point = (base.0 + row_start + row_step * ii, base.1 + col_start + col_step * ii);
+        if axis + 1 == shape.dim {
+            points.push(point);
+        } else {
+            push_points(shape, axis + 1, point, points);
+        }
+    }
+}
+
+/// Inequalities U·p + w >= 0 whose solutions are the points of the convex hull of `vertices`.
+///
+/// Axes where every vertex has the same coordinate are pinned to it, the hull must be full dimensional
+/// on the rest. Facets are found among the hyperplanes through every `free axes` vertices, keeping the ones
+/// with all vertices on the same side.
+fn hull_inequalities(vertices: &[Vec<i32>], dim: usize) -> Result<(Vec<Vec<i64>>, Vec<i64>), String> {
+    if vertices.is_empty() {
+        return Err("no vertices".to_string());
+    }
+
+    let vertices: Vec<Vec<i64>> = vertices.iter().map(|v| v.iter().map(|c| *c as i64).collect()).unique().collect();
+    let (mut u, mut w): (Vec<Vec<i64>>, Vec<i64>) = (vec![], vec![]);
+
+    let mut free_axes: Vec<usize> = vec![];
+    for axis in 0..dim {
+        let (min, max) = vertices.iter().map(|v| v[axis]).minmax().into_option().unwrap();
+        if min == max {
+            let mut row = vec![0; dim];
+            row[axis] = 1;
+            u.push(row.clone());
+            w.push(-min);
+            row[axis] = -1;
+            u.push(row);
+            w.push(min);
+        } else {
+            free_axes.push(axis);
+        }
+    }
+
+    let free_dim = free_axes.len();
+    let project = |v: &Vec<i64>| -> Vec<i128> { free_axes.iter().map(|axis| v[*axis] as i128).collect() };
+    let projected: Vec<Vec<i128>> = vertices.iter().map(project).collect();
+
+    if free_dim >= 2 {
+        let differences: Vec<Vec<i128>> = projected[1..].iter().map(|v| v.iter().zip(projected[0].iter()).map(|(a, b)| a - b).collect()).collect();
+        if rank(differences) < free_dim {
+            return Err("the hull of the vertices is not full dimensional".to_string());
+        }
+
+        let num_subsets = (0..free_dim).fold(1u128, |acc, k| acc * (projected.len() - k) as u128 / (k as u128 + 1));
+        if num_subsets > MAX_HULL_SUBSETS {
+            return Err(format!("{} vertices are too many for a {}-dimensional hull", projected.len(), free_dim));
+        }
+    }
+
+    let mut facets: Vec<(Vec<i128>, i128)> = vec![];
+    if free_dim == 1 {
+        let (min, max) = projected.iter().map(|v| v[0]).minmax().into_option().unwrap();
+        facets.push((vec![1], -min));
+        facets.push((vec![-1], max));
+    } else if free_dim >= 2 {
+        for subset in projected.iter().combinations(free_dim) {
+            // Normal of the hyperplane through the subset, as the cofactors of its edge vectors
+            let edges: Vec<Vec<i128>> = subset[1..].iter().map(|v| v.iter().zip(subset[0].iter()).map(|(a, b)| a - b).collect()).collect();
+            let mut normal: Vec<i128> = (0..free_dim)
+                .map(|col| {
+                    let minor: Vec<Vec<i128>> = edges.iter().map(|row| row.iter().enumerate().filter(|(c, _)| *c != col).map(|(_, v)| *v).collect()).collect();
+                    if col % 2 == 0 { determinant(minor) } else { -determinant(minor) }
+                })
+                .collect();
+
+            let divisor = normal.iter().fold(0i128, |acc, v| gcd(acc, *v));
+            if divisor == 0 {
+                continue;
+            }
+            normal.iter_mut().for_each(|v| *v /= divisor);
+
+            let offset: i128 = normal.iter().zip(subset[0].iter()).map(|(n, v)| n * v).sum();
+            let sides: Vec<i128> = projected.iter().map(|v| normal.iter().zip(v.iter()).map(|(n, c)| n * c).sum::<i128>() - offset).collect();
+            let facet = if sides.iter().all(|side| *side >= 0) {
+                (normal, -offset)
+            } else if sides.iter().all(|side| *side <= 0) {
+                (normal.into_iter().map(|v| -v).collect(), offset)
+            } else {
+                continue;
+            };
+
+            if !facets.contains(&facet) {
+                facets.push(facet);
+            }
+        }
+    }
+
+    for (normal, offset) in facets {
+        let mut row = vec![0; dim];
+        for (idx, axis) in free_axes.iter().enumerate() {
+            row[*axis] = i64::try_from(normal[idx]).map_err(|_| "hull inequality coefficients overflow".to_string())?;
+        }
+        u.push(row);
+        w.push(i64::try_from(offset).map_err(|_| "hull inequality coefficients overflow".to_string())?);
+    }
+
+    Ok((u, w))
+}
+
+/// Determinant of a square matrix, with fraction-free (Bareiss) elimination so it stays exact
+fn determinant(mut matrix: Vec<Vec<i128>>) -> i128 {
+    let n = matrix.len();
+    if n == 0 {
+        return 1;
+    }
+
+    let mut sign = 1;
+    let mut prev_pivot = 1;
+    for k in 0..n-1 {
+        if matrix[k][k] == 0 {
+            match (k+1..n).find(|row| matrix[*row][k] != 0) {
+                Some(row) => { matrix.swap(k, row); sign = -sign; },
+                None => return 0,
+            }
+        }
+        for i in k+1..n {
+            for j in k+1..n {
+                matrix[i][j] = (matrix[i][j] * matrix[k][k] - matrix[i][k] * matrix[k][j]) / prev_pivot;
+            }
+        }
+        prev_pivot = matrix[k][k];
+    }
+
+    sign * matrix[n-1][n-1]
+}
+
+/// Rank of a matrix, with fraction-free elimination
+fn rank(mut matrix: Vec<Vec<i128>>) -> usize {
+    let ncols = matrix.first().map_or(0, |row| row.len());
+    let mut rank = 0;
+    for col in 0..ncols {
+        let pivot = match (rank..matrix.len()).find(|row| matrix[*row][col] != 0) {
+            Some(pivot) => pivot,
+            None => continue,
+        };
+        matrix.swap(rank, pivot);
+
+        let (pivot_rows, rest) = matrix.split_at_mut(rank + 1);
+        let pivot_row = &pivot_rows[rank];
+        for row in rest.iter_mut() {
+            let factor = row[col];
+            if factor == 0 {
+                continue;
+            }
+            row.iter_mut().zip(pivot_row.iter()).skip(col).for_each(|(v, p)| *v = *v * pivot_row[col] - p * factor);
+            // Keep values small
+            let divisor = row.iter().fold(0i128, |acc, v| gcd(acc, *v));
+            if divisor > 1 {
+                row.iter_mut().for_each(|v| *v /= divisor);
+            }
+        }
+        rank += 1;
+    }
+    rank
+}
+
+#[inline(always)]
+fn gcd(a: i128, b: i128) -> i128 {
+    if b == 0 { a.abs() } else { gcd(b, a % b) }
+}
+
+/// Primitive direction of (i, j) pointing forward, with its first nonzero coordinate positive. None for (0, 0)
+fn forward_direction(i: i64, j: i64) -> Option<(i64, i64)> {
+    let divisor = gcd(i as i128, j as i128) as i64;
+    if divisor == 0 {
+        return None;
+    }
+    let sign = if i < 0 || (i == 0 && j < 0) { -1 } else { 1 };
+    Some((sign * i / divisor, sign * j / divisor))
+}
+
+#[inline(always)]
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[inline(always)]
+fn read_count(file: &mut impl Read, what: &str) -> io::Result<usize> {
+    match file.read_i32::<LittleEndian>()? {
+        count if count >= 0 => Ok(count as usize),
+        count => Err(invalid_data(format!("negative {} ({})", what, count))),
+    }
+}
+
+#[inline(always)]
+fn read_i32_vec(file: &mut impl Read, len: usize) -> io::Result<Vec<i32>> {
+    read_chunked(file, len, |file, values| file.read_i32_into::<LittleEndian>(values))
+}
+
+fn read_chunked<R: Read, T: Copy + Default>(file: &mut R, len: usize, read_into: impl Fn(&mut R, &mut [T]) -> io::Result<()>) -> io::Result<Vec<T>> {
+    let mut values: Vec<T> = Vec::with_capacity(len.min(READ_CHUNK_LEN));
+    while values.len() < len {
+        let start = values.len();
+        values.resize(start + (len - start).min(READ_CHUNK_LEN), T::default());
+        read_into(file, &mut values[start..])?;
+    }
+    Ok(values)
+}
+
+/// Adds `length` bytes of the file from `offset` to a running checksum
+fn update_crc<F: Read + Seek>(file: &mut F, offset: u64, length: u64, crc: &mut Crc32) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0u8; READ_CHUNK_LEN.min(length as usize)];
+    let mut left = length;
+    while left > 0 {
+        let len = (left as usize).min(buffer.len());
+        file.read_exact(&mut buffer[..len])?;
+        crc.update(&buffer[..len]);
+        left -= len as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sprs::CsMat;
+
+    use crate::testutil::TempFile;
+    use crate::utils::InputFormat;
+    use crate::uzpgen::{UZPGen, UzpWriteOptions};
+
+    // 6x6 matrix: a row piece, a diagonal piece and two single nonzeros
+    const PIECES: [(usize, usize, (i32, i32, i32)); 4] = [(0, 0, (4, 0, 1)), (1, 1, (3, 1, 1)), (5, 0, (1, 0, 0)), (4, 5, (1, 0, 0))];
+
+    fn value_matrix() -> CsMat<f64> {
+        let mut triplets: TriMat<f64> = TriMat::new((6, 6));
+        PIECES.iter()
+            .flat_map(|(row, col, (n, i, j))| (0..*n).map(move |k| ((*row as i32 + k * i) as usize, (*col as i32 + k * j) as usize)))
+            .enumerate()
+            .for_each(|(idx, (row, col))| triplets.add_triplet(row, col, 0.5 + idx as f64));
+        triplets.to_csr()
+    }
+
+    /// Writes the matrix with `options` to a file of its own, named after `name`
+    fn write_test_file(name: &str, options: &UzpWriteOptions) -> TempFile {
+        let mut contents: Vec<u8> = vec![];
+        sprs::io::write_matrix_market_to_bufwrite(&mut contents, &value_matrix()).unwrap();
+        let matrix_file = TempFile::new(&format!("uzpfile_{}.mtx", name), &contents);
+
+        let uzp_file = TempFile::new(&format!("uzpfile_{}.uzp", name), &[]);
+        let uzpgen = UZPGen::from_piece_list(PIECES.to_vec(), 6, 6, value_matrix().nnz());
+        uzpgen.write_uzp(&matrix_file.path, InputFormat::MatrixMarket, &uzp_file.path, options);
+        uzp_file
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let layouts = [
+            ("legacy", UzpWriteOptions { legacy_layout: true, ..Default::default() }, UzpLayout::LEGACY),
+            ("versioned", UzpWriteOptions::default(), UzpLayout::versioned(false)),
+            ("wide", UzpWriteOptions { wide_layout: true, ..Default::default() }, UzpLayout::versioned(true)),
+        ];
+
+        for (name, options, layout) in layouts {
+            let uzp_file = UzpFile::from_file(&write_test_file(name, &options).path).unwrap();
+
+            assert_eq!(uzp_file.header.layout, layout, "{}", name);
+            assert_eq!((uzp_file.header.nrows, uzp_file.header.ncols, uzp_file.header.nnz, uzp_file.header.inc_nnz), (6, 6, 9, 7), "{}", name);
+            assert_eq!(uzp_file.header.sections.is_some(), layout.has_section_table(), "{}", name);
+            assert_eq!(uzp_file.shapes().count(), 2, "{}", name);
+            assert_eq!(uzp_file.origins().count(), 2, "{}", name);
+            assert_eq!(uzp_file.uninc.len(), 2, "{}", name);
+            assert_eq!(uzp_file.symmetry, MmSymmetry::General, "{}", name);
+            assert_eq!(uzp_file.to_trimat(false).to_csr::<usize>(), value_matrix(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn oversized_shapes() {
+        let options = UzpWriteOptions { shape_encoding: UzpShapeEncoding::Ineqs, ..Default::default() };
+        let header = UzpFile::from_file(&write_test_file("oversized", &options).path).unwrap().header;
+        let half_plane = |max_point: Vec<i32>, u: Vec<i64>| UzpShape {
+            id: 0,
+            encoding: UzpShapeEncoding::Ineqs,
+            dim: 2,
+            min_point: vec![0, 0],
+            max_point,
+            strides: vec![1, 1],
+            vertices: vec![],
+            u: vec![u],
+            w: vec![0],
+            lattice: vec![1, 0, 0, 1],
+            sub_shape: None,
+        };
+
+        // Too many rows to walk, whatever their points
+        let shape = half_plane(vec![i32::MAX, i32::MAX], vec![1, -1]);
+        assert!(matches!(expand_shape(&header, &shape), Err(UzpError::CountMismatch { found, .. }) if found == i32::MAX as usize + 1));
+
+        // A single row, counted without walking it, with more points than the included nonzeros
+        let shape = half_plane(vec![0, i32::MAX], vec![0, 1]);
+        assert!(matches!(expand_shape(&header, &shape), Err(UzpError::CountMismatch { expected: 7, found, .. }) if found == i32::MAX as usize + 1));
+
+        // Within both limits: the lower triangle of a 3x3 box
+        let shape = half_plane(vec![2, 2], vec![1, -1]);
+        assert_eq!(expand_shape(&header, &shape).unwrap(), vec![(0, 0), (1, 0), (1, 1), (2, 0), (2, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn damaged_files() {
+        let file = write_test_file("damaged", &UzpWriteOptions::default());
+        let mut bytes = std::fs::read(&file.path).unwrap();
+        let sections = UzpFile::from_file(&file.path).unwrap().header.sections.unwrap();
+
+        // Flip a bit of the last value
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&file.path, &bytes).unwrap();
+        assert!(matches!(UzpFile::from_file(&file.path), Err(UzpError::ChecksumMismatch { section: UzpSection::Data, .. })));
+
+        // Cut in the middle of the origins
+        let origins = sections[UzpSection::ALL.iter().position(|section| *section == UzpSection::Origins).unwrap()];
+        std::fs::write(&file.path, &bytes[..(origins.offset + origins.length / 2) as usize]).unwrap();
+        assert!(matches!(UzpFile::from_file(&file.path), Err(UzpError::Truncated { .. })));
+
+        // Newer version
+        bytes[4..6].copy_from_slice(&(UZP_VERSION + 1).to_le_bytes());
+        std::fs::write(&file.path, &bytes).unwrap();
+        assert!(matches!(UzpFile::from_file(&file.path), Err(UzpError::UnsupportedVersion(version)) if version == UZP_VERSION + 1));
+    }
+}
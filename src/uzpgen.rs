@@ -1,13 +1,36 @@
-use std::{collections::HashMap, fs::File, io::{Seek, SeekFrom, Write}, path::PathBuf, time::Instant};
+use std::{collections::{HashMap, HashSet}, fs::{File, OpenOptions}, io::{Seek, SeekFrom, Write}, path::PathBuf, time::Instant};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, WriteBytesExt};
 use colored::Colorize;
 use itertools::Itertools;
 use linked_hash_map::LinkedHashMap;
 use linked_hash_set::LinkedHashSet;
 use sprs::{CsMat, TriMat};
 
-use crate::utils::{Pattern,Piece,Uwc,OriginUwc, MetaPattern, MetaPatternPiece, convex_hull_hyperrectangle_nd, metapattern_to_hyperrectangle_uwc};
+use crate::utils::{Pattern,Piece,Uwc,OriginUwc, MetaPattern, MetaPatternPiece, InputFormat, convex_hull_hyperrectangle_nd, metapattern_to_hyperrectangle_uwc};
+use crate::mmio::MmSymmetry;
+use crate::uzpfile::{UzpFile, UzpLayout, UzpShapeEncoding, symmetry_to_uzp_code};
+
+/// Flags controlling how `UZPGen::write_uzp` reads the value matrix and lays out the UZP file
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UzpWriteOptions {
+    pub transpose_input: bool,
+    pub transpose_output: bool,
+    /// Write not included single-points as 1-length patterns
+    pub uninc_as_patterns: bool,
+    /// Only the lower triangle of symmetric matrices is stored
+    pub symmetric: bool,
+    /// Encoding of the shapes. vertex_gen and ineqs write sheared 2D shapes as parallelograms, the rest are hyperrectangles
+    pub shape_encoding: UzpShapeEncoding,
+    /// Write higher order metapatterns as hierarchical shapes repeating their sub-pattern shape
+    pub hierarchical_shapes: bool,
+    /// Write the gcd of the lattice of every axis as its stride, for the shapes that have strides
+    pub normalize_lattice: bool,
+    /// Write the legacy layout, without prefix and with 32-bit counters, instead of the versioned one
+    pub legacy_layout: bool,
+    /// Write 64-bit counters, offsets and indices even if the matrix does not need them
+    pub wide_layout: bool,
+}
 
 pub struct UZPGen {
     pub nrows: usize,
@@ -90,6 +113,91 @@ impl UZPGen {
         }
     }
 
+    /// Rebuilds the metapatterns and pieces of a UZP file, so it can be augmented again. Every shape becomes a chain of
+    /// metapatterns, one per axis with the first axis on top, shared between shapes with the same axes. Shapes with a
+    /// single point become single nonzeros (id = -1), like the uninc nonzeros. Non rectangular shapes have no metapattern.
+    pub fn from_uzp_file(uzp_file: &UzpFile) -> Result<Self, String> {
+        let header = &uzp_file.header;
+
+        let mut meta_patterns: LinkedHashMap<i32, MetaPattern> = LinkedHashMap::new();
+        let mut chain_ids: HashMap<(Pattern, Option<i32>), i32> = HashMap::new();
+        // Top metapattern of the chain of every shape, and (row, col) offset its pieces start at
+        let mut shape_ids: HashMap<i32, (i32, (i64, i64))> = HashMap::new();
+
+        // Shapes without origins are left out
+        let used_shapes: HashSet<i32> = uzp_file.origins().map(|origin| origin.shape_id).collect();
+
+        for shape in uzp_file.shapes().filter(|shape| used_shapes.contains(&shape.id)) {
+            let npoints = uzp_file.shape_points(shape.id).len();
+            if npoints < 2 {
+                continue;
+            }
+            let walks = match shape.box_walks().or_else(|| shape.parallelogram_walks()) {
+                Some(walks) => walks,
+                None => return Err(format!("shape {} is neither a hyperrectangle nor a parallelogram, so it cannot be described by metapatterns", shape.id)),
+            };
+            let start = walks.iter().fold((0, 0), |(row, col), (_, (row_start, col_start), _)| (row + row_start, col + col_start));
+
+            let mut sub_id: Option<i32> = None;
+            for (axis, (len, _, (row_step, col_step))) in walks.into_iter().enumerate().rev() {
+                let pattern: Pattern = match (i32::try_from(len), i32::try_from(row_step), i32::try_from(col_step)) {
+                    (Ok(n), Ok(i), Ok(j)) => (n, i, j),
+                    _ => return Err(format!("axis {} of shape {} does not fit in a metapattern", axis, shape.id)),
+                };
+
+                let next_id = chain_ids.len() as i32;
+                let id = *chain_ids.entry((pattern, sub_id)).or_insert_with(|| {
+                    meta_patterns.insert(next_id, (pattern, (shape.dim - axis) as i32, sub_id));
+                    next_id
+                });
+                sub_id = Some(id);
+            }
+            shape_ids.insert(shape.id, (sub_id.unwrap(), start));
+        }
+        meta_patterns.insert(-1i32, ((1,0,0), 1, None));
+
+        // Pieces start where the walks of their shape start. Single nonzeros go last
+        let mut meta_pattern_pieces: LinkedHashMap<MetaPatternPiece, i32> = LinkedHashMap::new();
+        let mut single_nonzeros: Vec<MetaPatternPiece> = uzp_file.uninc.positions();
+        for (origin, shape) in uzp_file.origin_shapes() {
+            let points = uzp_file.shape_points(shape.id);
+            let positions = points.iter().map(|(row, col)| ((origin.row + row) as usize, (origin.col + col) as usize));
+            match shape_ids.get(&shape.id) {
+                Some((id, (row_start, col_start))) => {
+                    let first_point = ((origin.row + row_start) as usize, (origin.col + col_start) as usize);
+                    if meta_pattern_pieces.insert(first_point, *id).is_some() {
+                        return Err(format!("more than one shape starts at ({}, {})", first_point.0 + 1, first_point.1 + 1));
+                    }
+                },
+                None => single_nonzeros.extend(positions),
+            }
+        }
+
+        let inc_nnz = header.nnz - single_nonzeros.len();
+        for position in single_nonzeros {
+            if meta_pattern_pieces.insert(position, -1).is_some() {
+                return Err(format!("more than one piece starts at ({}, {})", position.0 + 1, position.1 + 1));
+            }
+        }
+
+        Ok(UZPGen {
+            nrows: header.nrows,
+            ncols: header.ncols,
+            nnz: header.nnz,
+            inc_nnz,
+            meta_patterns,
+            meta_pattern_pieces
+        })
+    }
+
+    pub fn get_metapatterns(&self) -> LinkedHashMap<i32, MetaPattern> {
+        self.meta_patterns.clone()
+    }
+
+    pub fn get_metapattern_pieces(&self) -> LinkedHashMap<MetaPatternPiece, i32> {
+        self.meta_pattern_pieces.clone()
+    }
+
     // #[allow(dead_code)] // UNUSED AND REDUNDANT. TBDeleted
     // pub fn print_ast_list(&self) {
     //     println!("AST_List:\nRow\tCol\tN\tI\tJ");
@@ -142,52 +250,157 @@ impl UZPGen {
             .collect::<Vec<(OriginUwc, i32)>>()
     }
 
-    pub fn write_uzp(&self, input_value_matrix: &str, output_file_path: &str, transpose_input: bool, transpose_output: bool, uninc_as_patterns: bool) {
-        // Read matrixmarket f64 value matrix
-        let f64_value_matrix: CsMat<f64> = crate::utils::read_matrix_market_csr(input_value_matrix, transpose_input);
+    /// Writes the metapatterns and their origins as an iscc script (ISL/Barvinok notation):
+    ///
+    ///   S<id> := { [r0, c0] -> [r, c] : exists (i, j... : bounds and r, c on the lattice from r0, c0) };
+    ///   O<id> := { [row, col]; ... };      origins of the metapattern
+    ///   U := { [row, col]; ... };          nonzeros not included in any metapattern
+    ///   A := S0(O0) + S1(O1) + ... + U;    every nonzero of the (stored) matrix
+    ///
+    /// Bounds come from the Uwc description of the metapattern (U·index + w >= 0), so any dimensionality is written.
+    pub fn write_isl(&self, output_file_path: &str, transpose_output: bool) -> std::io::Result<()> {
+        let mut file = std::io::BufWriter::new(File::create(output_file_path)?);
+
+        let (row_name, col_name) = if !transpose_output { ("r", "c") } else { ("c", "r") };
+        let position = |(row, col): (usize, usize)| if !transpose_output { format!("[{}, {}]", row, col) } else { format!("[{}, {}]", col, row) };
+
+        writeln!(file, "# Union of Z-polyhedra of a {}x{} matrix with {} nonzeros ({} included in metapatterns)", if !transpose_output { self.nrows } else { self.ncols }, if !transpose_output { self.ncols } else { self.nrows }, self.nnz, self.inc_nnz)?;
+        writeln!(file, "# Load with iscc. `card A;` counts the nonzeros")?;
+
+        let mut origins_by_id: HashMap<i32, Vec<String>> = HashMap::new();
+        self.meta_pattern_pieces.iter().for_each(|(origin, id)| origins_by_id.entry(*id).or_default().push(position(*origin)));
+
+        let mut union_terms: Vec<String> = vec![];
+        for id in self.meta_patterns.keys().filter(|id| **id != -1) {
+            // Sub-patterns of higher order metapatterns have no origins of their own
+            let origins = match origins_by_id.get(id) {
+                Some(origins) => origins,
+                None => continue,
+            };
+
+            let (u, w, c) = metapattern_to_hyperrectangle_uwc(*id, &self.meta_patterns);
+            let vars = index_variable_names(u[0].len());
+
+            let mut constraints: Vec<String> = u
+                .iter()
+                .zip(w.iter())
+                .map(|(u_row, w_val)| {
+                    let expr = isl_affine_expr("", u_row.iter().zip(vars.iter()).map(|(coef, var)| (*coef, var.clone())));
+                    match *w_val {
+                        0 => format!("{} >= 0", expr),
+                        w_val if w_val < 0 => format!("{} - {} >= 0", expr, -w_val),
+                        w_val => format!("{} + {} >= 0", expr, w_val),
+                    }
+                })
+                .collect();
+            constraints.push(format!("r = {}", isl_affine_expr("r0", c.iter().step_by(2).zip(vars.iter()).map(|(coef, var)| (*coef, var.clone())))));
+            constraints.push(format!("c = {}", isl_affine_expr("c0", c.iter().skip(1).step_by(2).zip(vars.iter()).map(|(coef, var)| (*coef, var.clone())))));
+
+            writeln!(file)?;
+            writeln!(file, "# Metapattern {}: U = {:?}, w = {:?}, c = {:?}", id, u, w, c)?;
+            writeln!(file, "S{} := {{ [{}0, {}0] -> [{}, {}] : exists ({} : {}) }};", id, row_name, col_name, row_name, col_name, vars.join(", "), constraints.join(" and "))?;
+            writeln!(file, "O{} := {{ {} }};", id, origins.join("; "))?;
+            union_terms.push(format!("S{}(O{})", id, id));
+        }
+
+        writeln!(file)?;
+        writeln!(file, "# Nonzeros not included in any metapattern")?;
+        writeln!(file, "U := {{ {} }};", origins_by_id.get(&-1).map_or(String::new(), |uninc| uninc.join("; ")))?;
+        union_terms.push("U".to_string());
+
+        writeln!(file)?;
+        writeln!(file, "A := {};", union_terms.join(" + "))?;
+        file.flush()
+    }
+
+    pub fn write_uzp(&self, input_value_matrix: &str, input_format: InputFormat, output_file_path: &str, options: &UzpWriteOptions) {
+        // Read f64 value matrix. It must be read the same way it was searched, so the stored triangle matches
+        let (f64_value_matrix, symmetry): (CsMat<f64>, MmSymmetry) = crate::utils::read_matrix_csr(input_value_matrix, options.transpose_input, input_format, options.symmetric);
+        let symmetry = if options.symmetric { symmetry } else { MmSymmetry::General };
+
+        self.write_uzp_with_values(&f64_value_matrix, symmetry, output_file_path, options);
+    }
+
+    /// Writes the UZP file taking the values from a matrix already in memory, holding the stored nonzeros (one triangle
+    /// of `symmetry` matrices) in the orientation they were searched in. `transpose_input` and `symmetric` are not used.
+    pub fn write_uzp_with_values(&self, f64_value_matrix: &CsMat<f64>, symmetry: MmSymmetry, output_file_path: &str, options: &UzpWriteOptions) {
+        let UzpWriteOptions { transpose_output, uninc_as_patterns, shape_encoding, hierarchical_shapes, normalize_lattice, legacy_layout, wide_layout, .. } = *options;
 
         // Quick sanity check
         if f64_value_matrix.nnz() != self.nnz {
             panic!("NNZ of value matrix and pattern list do not match. Maybe double check your params?");
         }
 
-        let mut file = File::create(output_file_path).expect(format!("Unable to create file {}", output_file_path).as_str());
+        // Get index of single nonzeros (not in a pattern to filter them out of the next foreach)
+        // let ninc_nonzero_pattern_id = self.distinct_patterns.get(&(1,0,0)).unwrap();
+        // If we want to dump everything as patterns, we can just filter the single nonzeros with a never-used id, like min i32
+        let ninc_nonzero_pattern_id = if uninc_as_patterns { std::i32::MIN } else { -1i32 };
+
+        // This has been brought from down below, as it is useful for the following computation
+        // We also know that regular pieces are at the end of the list
+        let piece_cutoff = self.meta_pattern_pieces.iter().filter(|(_, id)| **id != ninc_nonzero_pattern_id).count();
+        // println!("Piece cutoff = {}", piece_cutoff);
+
+        // Shapes of the metapatterns with origins. Also we have to take into account removing the single nonzeros
+        let origin_ids: Vec<i32> = self.meta_pattern_pieces
+            .iter()
+            .filter(|(_, id)| **id != ninc_nonzero_pattern_id)
+            .unique_by(|(_, id)| **id)
+            .map(|(_, id)| *id)
+            .collect();
+
+        // Hierarchical shapes need the shapes of their sub-patterns too, even if those have no origins
+        let (base_ids, hier_ids): (Vec<i32>, Vec<i32>) = if hierarchical_shapes { self.hierarchical_shape_ids(&origin_ids) } else { (origin_ids, vec![]) };
+
+        // Counters and indices are bounded by the matrix size, shape ids by the number of shapes
+        let max_count = self.nnz.max(self.nrows + 1).max(self.ncols + 1);
+        let max_id = (base_ids.len() + hier_ids.len()).saturating_sub(1);
+        let layout = UzpLayout::for_write(legacy_layout, wide_layout, max_count, max_id);
+        if !layout.fits(max_count, max_id) {
+            eprintln!("{} The matrix needs 64-bit counters or more than {} shapes, which legacy UZP files cannot hold. Write a versioned file instead.", "[ERROR]".red().bold(), i16::MAX as usize + 1);
+            std::process::exit(1);
+        }
+
+        // Read back once written, to checksum the sections of versioned files
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output_file_path)
+            .unwrap_or_else(|e| {
+                eprintln!("{} Unable to create file {}. ERROR: {}", "[ERROR]".red().bold(), output_file_path.bright_blue(), e.to_string().bold().red());
+                std::process::exit(1);
+            });
 
         let path = PathBuf::from(output_file_path);
         eprintln!("Writing to file {}", path.to_str().unwrap().bright_blue());
 
         // Write header
-        file.write_i32::<LittleEndian>(self.nnz as i32).unwrap();
-        file.write_i32::<LittleEndian>( if uninc_as_patterns { self.nnz as i32 } else { self.inc_nnz as i32 } ).unwrap();
+        layout.write_prefix(&mut file, symmetry).unwrap();
+        // Start of the header, shapes, origins, uninc and data sections
+        let mut section_starts: [u64; 5] = [0; 5];
+        section_starts[0] = file.stream_position().unwrap();
+        layout.write_count(&mut file, self.nnz).unwrap();
+        layout.write_count(&mut file, if uninc_as_patterns { self.nnz } else { self.inc_nnz }).unwrap();
         if !transpose_output {
             // Write matrix in a normal way
-            file.write_i32::<LittleEndian>(self.nrows as i32).unwrap();
-            file.write_i32::<LittleEndian>(self.ncols as i32).unwrap();
+            layout.write_count(&mut file, self.nrows).unwrap();
+            layout.write_count(&mut file, self.ncols).unwrap();
         } else {
             // Write it transposed
-            file.write_i32::<LittleEndian>(self.ncols as i32).unwrap();
-            file.write_i32::<LittleEndian>(self.nrows as i32).unwrap();
+            layout.write_count(&mut file, self.ncols).unwrap();
+            layout.write_count(&mut file, self.nrows).unwrap();
         }
 
-        // Get index of single nonzeros (not in a pattern to filter them out of the next foreach)
-        // let ninc_nonzero_pattern_id = self.distinct_patterns.get(&(1,0,0)).unwrap();
-        // If we want to dump everything as patterns, we can just filter the single nonzeros with a never-used id, like min i32
-        let ninc_nonzero_pattern_id = if uninc_as_patterns { std::i32::MIN } else { -1i32 };
-
-        // This has been brought from down below, as it is useful for the following computation
-        // We also know that regular pieces are at the end of the list
-        let piece_cutoff = self.meta_pattern_pieces.iter().filter(|(_, id)| **id != ninc_nonzero_pattern_id).count();
-        // println!("Piece cutoff = {}", piece_cutoff);
-
         // Write dimensions
         file.write_i16::<LittleEndian>(2i16).unwrap();
-        // number of base shapes is actual found shapes, not unfound ones. Also we have to take into account removing the single nonzeros
-        file.write_i32::<LittleEndian>((self.meta_pattern_pieces.iter().filter(|(_, id)| **id != ninc_nonzero_pattern_id).unique_by(|(_, id)| **id).count()) as i32).unwrap();
-        // write zero hierarchical shapes
-        file.write_i32::<LittleEndian>(0i32).unwrap();
-        // write TEMPORARY ZERO as pointer to start of data. Will need to fseek to position 26 later
-        //  (python code `f.seek ( 26 )` on write_uzp func at around line 810)
-        file.write_i32::<LittleEndian>(0i32).unwrap();
+        // number of base shapes is actual found shapes, not unfound ones
+        layout.write_count(&mut file, base_ids.len()).unwrap();
+        layout.write_count(&mut file, hier_ids.len()).unwrap();
+        // write TEMPORARY ZERO as pointer to start of data. Will need to fseek to it later
+        let data_ptr_pos = file.stream_position().unwrap();
+        layout.write_data_ptr(&mut file, 0).unwrap();
 
         let shape_dims_max: i16 = {
             if piece_cutoff == 0 { 0i16 }
@@ -204,53 +417,98 @@ impl UZPGen {
             file.write_i32::<LittleEndian>(0i32).unwrap();
         }
 
-        // Create REORDER dictionary
-        let reorder: LinkedHashMap<i32, usize> = self.meta_pattern_pieces
+        section_starts[1] = file.stream_position().unwrap();
+
+        // Create REORDER dictionary. Hierarchical shapes go after the base ones
+        let reorder: LinkedHashMap<i32, usize> = base_ids
             .iter()
-            .filter(|(_, id)| **id != ninc_nonzero_pattern_id)
-            .unique_by(|(_, id)| **id)
+            .chain(hier_ids.iter())
             .enumerate()
-            .map(|(idx, (_, id))| (*id, idx))
+            .map(|(idx, id)| (*id, idx))
             .collect();
 
         // DEBUG -- eprintln!("REORDER: {:?}", reorder);
 
-        self.meta_pattern_pieces
+        // Sheared 2D shapes only become genuine parallelograms with the encodings that are not boxes
+        let parallelograms: HashMap<i32, Parallelogram> = base_ids
             .iter()
-            .filter(|(_, id)| **id != ninc_nonzero_pattern_id)
-            .unique_by(|(_, id)| **id)
-            .for_each(|(_, id)| {
+            .filter(|_| shape_encoding != UzpShapeEncoding::VertexRec)
+            .filter_map(|id| metapattern_to_parallelogram(*id, &self.meta_patterns).map(|parallelogram| (*id, parallelogram)))
+            .collect();
 
-            let (u,w,c) = metapattern_to_hyperrectangle_uwc(*id, &self.meta_patterns);
+        base_ids.iter().for_each(|id| {
+
+            let parallelogram = parallelograms.get(id);
+            let (u,w,c) = match parallelogram {
+                Some(parallelogram) => parallelogram.uwc.clone(),
+                None => metapattern_to_hyperrectangle_uwc(*id, &self.meta_patterns),
+            };
 
             // Write shape id
-            file.write_i16::<LittleEndian>( *reorder.get(id).unwrap() as i16 ).unwrap();
-            // Write type of encoding. 0 = vertex_rec, 1 = vertex_gen, 2 = ineqs . FIXME only writes vertex_rec
-            file.write_i16::<LittleEndian>(0i16).unwrap();
-            // Write dimension of i_p. Hardcodec for vertex_rec
+            layout.write_id(&mut file, *reorder.get(id).unwrap() as i32).unwrap();
+            // Write type of encoding. 0 = vertex_rec, 1 = vertex_gen, 2 = ineqs
+            file.write_i16::<LittleEndian>(shape_encoding.code()).unwrap();
+            // Write dimension of i_p
             // INFO This can also be done by accessing self.metapatterns and checking ORDER field
             file.write_i16::<LittleEndian>(u[0].len() as i16).unwrap();
             // println!("    - Dimension of i_p = {}", u[0].len());
 
-            // Get convex_hull FIXED for n-dimensional hyperrectangles
-            let ch: Vec<Vec<i32>> = convex_hull_hyperrectangle_nd(&u, &w, false);
+            // Get convex_hull FIXED for n-dimensional hyperrectangles. Parallelograms are bounded by their corners
+            let (min_point, max_point): (Vec<i32>, Vec<i32>) = match parallelogram {
+                Some(parallelogram) => (0..2)
+                    .map(|axis| parallelogram.corners.iter().map(|corner| corner[axis]).minmax().into_option().unwrap())
+                    .unzip(),
+                None => {
+                    let ch: Vec<Vec<i32>> = convex_hull_hyperrectangle_nd(&u, &w, false);
+                    (ch[0].clone(), ch[ch.len()-1].clone())
+                },
+            };
+
+            // Only vertex_rec shapes have strides, so the lattice of the rest is always kept as is
+            let strides: Vec<i32> = c
+                .iter()
+                .tuples()
+                .map(|(i, j)| if normalize_lattice && shape_encoding == UzpShapeEncoding::VertexRec { lattice_gcd(*i, *j) } else { 1 })
+                .collect();
+            let c: Vec<i32> = c.iter().enumerate().map(|(idx, cc)| cc / strides[idx/2]).collect();
 
             // Write minimal point
-            for i in 0..ch[0].len() {
-                file.write_i32::<LittleEndian>(ch[0][i]).unwrap();
-                // println!("    - Minimal point from ch[0] = {}", ch[0][i]);
+            for i in 0..min_point.len() {
+                file.write_i32::<LittleEndian>(min_point[i] * strides[i]).unwrap();
             }
 
-            // Write lenghts along axis
-            for i in 0..ch[0].len() {
-                // taking shortcut as minimal points are always [0,0,...,0] (N times)
-                file.write_i32::<LittleEndian>(ch[ch.len()-1][i]).unwrap();
-                // println!("    - Lenghts along axis from ch[ch.len()-1][i] {:?}", ch[ch.len()-1][i]);
+            // Write maximal point
+            for i in 0..max_point.len() {
+                file.write_i32::<LittleEndian>(max_point[i] * strides[i]).unwrap();
             }
 
-            // "Hardcoded stride at this time"
-            for _ in 0..u[0].len() {
-                file.write_i32::<LittleEndian>(1i32).unwrap();
+            match shape_encoding {
+                UzpShapeEncoding::VertexRec => {
+                    for stride in &strides {
+                        file.write_i32::<LittleEndian>(*stride).unwrap();
+                    }
+                },
+                UzpShapeEncoding::VertexGen => {
+                    // Corners of the hyperrectangle. Axes of length 1 would repeat them
+                    let corners: Vec<Vec<i32>> = match parallelogram {
+                        Some(parallelogram) => parallelogram.corners.clone(),
+                        None => min_point
+                            .iter()
+                            .zip(max_point.iter())
+                            .map(|(min, max)| if max > min { vec![*min, *max] } else { vec![*min] })
+                            .multi_cartesian_product()
+                            .collect(),
+                    };
+
+                    file.write_i32::<LittleEndian>(corners.len() as i32).unwrap();
+                    corners.iter().flatten().for_each(|coord| file.write_i32::<LittleEndian>(*coord).unwrap());
+                },
+                UzpShapeEncoding::Ineqs => {
+                    // The Uwc description already is U·p + w >= 0, for boxes and parallelograms alike
+                    file.write_i32::<LittleEndian>(u.len() as i32).unwrap();
+                    u.iter().flatten().for_each(|coef| file.write_i32::<LittleEndian>(*coef).unwrap());
+                    w.iter().for_each(|w_val| file.write_i32::<LittleEndian>(*w_val).unwrap());
+                },
             }
 
             // Write lattice
@@ -268,10 +526,35 @@ impl UZPGen {
             }
         });
 
+        // Hierarchical shapes repeat their sub-pattern shape n times along (i,j)
+        hier_ids.iter().for_each(|id| {
+            let ((n,i,j), _, subpat) = self.meta_patterns.get(id).unwrap();
+            let stride = if normalize_lattice { lattice_gcd(*i, *j) } else { 1 };
+            let (i, j) = (i / stride, j / stride);
+
+            layout.write_id(&mut file, *reorder.get(id).unwrap() as i32).unwrap();
+            layout.write_id(&mut file, *reorder.get(&subpat.unwrap()).unwrap() as i32).unwrap();
+            // Dimension of the repetition
+            file.write_i16::<LittleEndian>(1i16).unwrap();
+            // Minimal point, maximal point and stride
+            file.write_i32::<LittleEndian>(0i32).unwrap();
+            file.write_i32::<LittleEndian>((*n - 1) * stride).unwrap();
+            file.write_i32::<LittleEndian>(stride).unwrap();
+            // Lattice
+            if !transpose_output {
+                file.write_i32::<LittleEndian>(i).unwrap();
+                file.write_i32::<LittleEndian>(j).unwrap();
+            } else {
+                file.write_i32::<LittleEndian>(j).unwrap();
+                file.write_i32::<LittleEndian>(i).unwrap();
+            }
+        });
+
         // Write total number of origins
-        file.write_i32::<LittleEndian>(piece_cutoff as i32).unwrap();
+        section_starts[2] = file.stream_position().unwrap();
+        layout.write_count(&mut file, piece_cutoff).unwrap();
 
-        let mut data_offset: i32 = 0;
+        let mut data_offset: usize = 0;
         let mut mpp_iter = self.meta_pattern_pieces.iter();
         for _ in 0..piece_cutoff {
             let ((row,col),id) = mpp_iter.next().unwrap();
@@ -279,21 +562,21 @@ impl UZPGen {
             let (u,w,_) = metapattern_to_hyperrectangle_uwc(*id, &self.meta_patterns);
 
             // Write shape id
-            file.write_i16::<LittleEndian>( *reorder.get(id).unwrap() as i16 ).unwrap();
+            layout.write_id(&mut file, *reorder.get(id).unwrap() as i32).unwrap();
 
             // Get convex_hull
             let ch: Vec<Vec<i32>> = convex_hull_hyperrectangle_nd(&u, &w, true);
 
             // Write coordinates of AST's starting point
             if !transpose_output {
-                file.write_i32::<LittleEndian>(*row as i32).unwrap(); // row
-                file.write_i32::<LittleEndian>(*col as i32).unwrap(); // col
+                layout.write_count(&mut file, *row).unwrap(); // row
+                layout.write_count(&mut file, *col).unwrap(); // col
             } else {
-                file.write_i32::<LittleEndian>(*col as i32).unwrap(); // col
-                file.write_i32::<LittleEndian>(*row as i32).unwrap(); // row
+                layout.write_count(&mut file, *col).unwrap(); // col
+                layout.write_count(&mut file, *row).unwrap(); // row
             }
-            file.write_i32::<LittleEndian>(data_offset).unwrap();                 // data offset
-            data_offset += ch.len() as i32;   // Offset in elements. no judgment about data type
+            layout.write_count(&mut file, data_offset).unwrap();                 // data offset
+            data_offset += ch.len();   // Offset in elements. no judgment about data type
         }
 
         // Codes here:
@@ -310,12 +593,17 @@ impl UZPGen {
                                    else { 2u8 }
                                };
 
-        eprintln!("Writing uninc_format = {} to offset 0x{:X}...\n", uninc_format, file.seek(SeekFrom::Current(0)).unwrap());
-        file.write_u8(uninc_format).unwrap();
+        section_starts[3] = file.stream_position().unwrap();
+        eprintln!("Writing uninc_format = {} to offset 0x{:X}...\n", uninc_format, section_starts[3]);
+        // Legacy files have no prefix, so the symmetry goes on the high nibble. General matrices keep the plain format code
+        file.write_u8(if layout.is_legacy() { uninc_format | (symmetry_to_uzp_code(symmetry) << 4) } else { uninc_format }).unwrap();
 
         // Set iterator
         let mut mpp_iter = self.meta_pattern_pieces.iter().skip(piece_cutoff);
 
+        // Uninc values are written in the same order as their positions, which changes when transposing into CSR
+        let mut uninc_positions: Vec<(usize, usize)> = Vec::with_capacity(self.meta_pattern_pieces.len() - piece_cutoff);
+
         match uninc_format {
             0 => {  // DEBUG -- eprintln!("Writing CSR");
                     let mut local_coo_mat: TriMat<u8> = TriMat::new((self.nrows, self.ncols));
@@ -339,22 +627,27 @@ impl UZPGen {
 
                     // Write rowptr/indptr
                     local_csr_mat.proper_indptr().iter().for_each(|iptr_val| {
-                        file.write_i32::<LittleEndian>(*iptr_val as i32).unwrap();
+                        layout.write_count(&mut file, *iptr_val).unwrap();
                     });
                     // Write colptr/indices
                     local_csr_mat.indices().iter().for_each(|ind_val| {
-                        file.write_i32::<LittleEndian>(*ind_val as i32).unwrap();
+                        layout.write_count(&mut file, *ind_val).unwrap();
+                    });
+
+                    local_csr_mat.iter().for_each(|(_, (row, col))| {
+                        uninc_positions.push(if !transpose_output { (row, col) } else { (col, row) });
                     });
                  },
             2 => {  // DEBUG -- eprintln!("Writing COO");
 
-                    let mut rowvec: Vec<i32> = vec![];
-                    let mut colvec: Vec<i32> = vec![];
+                    let mut rowvec: Vec<usize> = vec![];
+                    let mut colvec: Vec<usize> = vec![];
 
                     for _ in piece_cutoff..self.meta_pattern_pieces.len() {
                         let (row, col) = mpp_iter.next().unwrap().0;
-                        rowvec.push(*row as i32);
-                        colvec.push(*col as i32);
+                        rowvec.push(*row);
+                        colvec.push(*col);
+                        uninc_positions.push((*row, *col));
                     }
 
                     if transpose_output {
@@ -364,12 +657,12 @@ impl UZPGen {
                     // DEBUG -- eprint!("Writing Rowptr: ");
                     for row in rowvec {
                         // DEBUG -- eprint!("{} ", row);
-                        file.write_i32::<LittleEndian>(row).unwrap(); // Write rowptr
+                        layout.write_count(&mut file, row).unwrap(); // Write rowptr
                     }
                     // DEBUG -- eprint!("\nWriting Colptr: ");
                     for col in colvec {
                         // DEBUG -- eprint!("{} ", col);
-                        file.write_i32::<LittleEndian>(col).unwrap(); // Write colptr
+                        layout.write_count(&mut file, col).unwrap(); // Write colptr
                     }
                     // DEBUG -- eprintln!();
                  },
@@ -378,179 +671,76 @@ impl UZPGen {
 
         // Save current position for later
         let curr_pos = file.seek(SeekFrom::Current(0)).unwrap();
+        section_starts[4] = curr_pos;
 
         // And rewrite pointer to start of data
-        file.seek(SeekFrom::Start(26)).unwrap();
-        file.write_i32::<LittleEndian>(curr_pos as i32).unwrap();
+        file.seek(SeekFrom::Start(data_ptr_pos)).unwrap();
+        if let Err(e) = layout.write_data_ptr(&mut file, curr_pos) {
+            eprintln!("{} Unable to write UZP file {}. ERROR: {}", "[ERROR]".red().bold(), output_file_path.bright_blue(), e.to_string().bold().red());
+            std::process::exit(1);
+        }
         file.seek(SeekFrom::Start(curr_pos)).unwrap();
 
 
         // f.write( struct.pack( len(self.mask)*"d", *mat.data[self.reorder] ) )
-        self.meta_pattern_pieces.iter().for_each(|((row,col),id)| {
-            for val in recursive_traverse(&(*row,*col), *id, &self.meta_patterns, &f64_value_matrix){
+        self.meta_pattern_pieces.iter().take(piece_cutoff).for_each(|((row,col),id)| {
+            if let Some(parallelogram) = parallelograms.get(id) {
+                for (i, j) in &parallelogram.offsets {
+                    let value = f64_value_matrix.get((*row as i64 + *i as i64) as usize, (*col as i64 + *j as i64) as usize).unwrap();
+                    file.write_f64::<LittleEndian>(*value).unwrap();
+                }
+                return;
+            }
+            for val in recursive_traverse(&(*row,*col), *id, &self.meta_patterns, f64_value_matrix){
                 file.write_f64::<LittleEndian>(val).unwrap();
             }
         });
-    }
-}
-
-pub fn convert_uzp (input_uzp_file_path: &str, output_mtx_file_path: &str, csr: bool, print_ast_list: bool) {
-    let mut file = File::open(input_uzp_file_path).expect(format!("Unable to open uzp file {}", input_uzp_file_path).as_str());
-
-    // Read header
-    let nnz = file.read_i32::<LittleEndian>().unwrap();
-    let inc_nnz = file.read_i32::<LittleEndian>().unwrap();
-    let nrows = file.read_i32::<LittleEndian>().unwrap();
-    let ncols = file.read_i32::<LittleEndian>().unwrap();
-
-    let dims = file.read_i16::<LittleEndian>().unwrap();
-    if dims != 2 {
-        panic!("Only 2D matrices are supported at the moment");
-    }
-
-    let num_shapes = file.read_i32::<LittleEndian>().unwrap();
-    // skip num_hier_shapes
-    file.seek(SeekFrom::Current(32/8)).unwrap();
-    // let num_hier_shapes = file.read_i32::<LittleEndian>().unwrap();
-
-    // Pointer to start of data
-    let data_ptr = file.read_i32::<LittleEndian>().unwrap();
-
-    let max_dims = file.read_i16::<LittleEndian>().unwrap();
-    // Skip max_dims data
-    file.seek(SeekFrom::Current((32/8)*(max_dims as i64))).unwrap();
-
-    // Create sprs triplet matrix for insertion
-    // let mut triplet_matrix: TriMat<f64> = TriMat::new((nrows as usize, ncols as usize));
-    // initialize three vecs with capacity nnz
-    let mut rowvec: Vec<usize> = Vec::with_capacity(nnz as usize);
-    let mut colvec: Vec<usize> = Vec::with_capacity(nnz as usize);
-    let mut datavec: Vec<f64> = Vec::with_capacity(nnz as usize);
-
-    //                       shape_id, (dim_of_ip, lengths_along_axis, c)
-    let mut shapes_map: HashMap<i16, (i16, Vec<i32>, Vec<i32>)> = HashMap::with_capacity(num_shapes as usize);
-
-    for _ in 0..(num_shapes as usize) {
-        // Read shapes
-        // shapes_vec[shape_id].0 =
-
-        let l_shape_id = file.read_i16::<LittleEndian>().unwrap();
-
-        let type_of_encoding = file.read_i16::<LittleEndian>().unwrap();
-        if type_of_encoding != 0 {
-            panic!("Only vertex_rec encoding is supported at the moment");
-        }
-
-        // shapes_vec[shape_id].1
-
-        let l_dim_of_ip = file.read_i16::<LittleEndian>().unwrap();
-
-        // for i in 0..dim_of_ip {
-        //     // let min_point = file.read_i32::<LittleEndian>().unwrap();
-        //     // let len_along_axis = file.read_i32::<LittleEndian>().unwrap();
-        //     // let stride = file.read_i32::<LittleEndian>().unwrap();
-        // }
-        // Skip min_point
-        file.seek(SeekFrom::Current((32/8)*(l_dim_of_ip as i64))).unwrap();
+        uninc_positions.iter().for_each(|(row, col)| {
+            file.write_f64::<LittleEndian>(*f64_value_matrix.get(*row, *col).unwrap()).unwrap();
+        });
 
-        // Read len_along_axis
-        let mut l_len_along_axis: Vec<i32> = Vec::with_capacity(l_dim_of_ip as usize);
-        for _ in 0..l_dim_of_ip {
-            l_len_along_axis.push(file.read_i32::<LittleEndian>().unwrap());
+        let end = file.stream_position().unwrap();
+        if let Err(e) = layout.write_section_table(&mut file, section_starts, end) {
+            eprintln!("{} Unable to write UZP file {}. ERROR: {}", "[ERROR]".red().bold(), output_file_path.bright_blue(), e.to_string().bold().red());
+            std::process::exit(1);
         }
+    }
+}
 
-        // Skip stride
-        file.seek(SeekFrom::Current((32/8)*(l_dim_of_ip as i64))).unwrap();
-
-        // read 2*dim_of_ip c values into shapes_vec[shape_id].2
-        let mut l_c: Vec<i32> = Vec::with_capacity(2*l_dim_of_ip as usize);
-        for _ in 0..2*l_dim_of_ip {
-            l_c.push(file.read_i32::<LittleEndian>().unwrap());
-        }
+impl UZPGen {
+    /// Splits the metapatterns of `origin_ids` and their sub-patterns into base shapes (1D metapatterns) and
+    /// hierarchical shapes. Every sub-pattern comes before the metapatterns built on it, so it is written first.
+    fn hierarchical_shape_ids(&self, origin_ids: &[i32]) -> (Vec<i32>, Vec<i32>) {
+        let mut shape_ids: LinkedHashSet<i32> = LinkedHashSet::new();
+        origin_ids.iter().for_each(|id| {
+            // Sub-pattern chain up to the first metapattern already added, whose sub-patterns are added too
+            let mut chain: Vec<i32> = vec![];
+            let mut curr_id = Some(*id);
+            while let Some(id) = curr_id.filter(|id| !shape_ids.contains(id)) {
+                chain.push(id);
+                curr_id = self.meta_patterns.get(&id).unwrap().2;
+            }
+            shape_ids.extend(chain.into_iter().rev());
+        });
 
-        shapes_map.insert(l_shape_id,(l_dim_of_ip, l_len_along_axis, l_c));
+        shape_ids.into_iter().partition(|id| self.meta_patterns.get(id).unwrap().2.is_none())
     }
+}
 
-    // Read total number of origins
-    let num_origins = file.read_i32::<LittleEndian>().unwrap();
-    // let mut data_offset: i32 = 0;
+pub fn convert_uzp (input_uzp_file_path: &str, output_mtx_file_path: &str, csr: bool, print_ast_list: bool, keep_symmetry: bool) {
+    let uzp_file = read_uzp_file(input_uzp_file_path);
 
     if print_ast_list {
         eprintln!("{} Printing AST List:", "[INFO]".cyan().bold());
         println!("Row\tCol\tN\tI\tJ");
+        uzp_file.origins().for_each(|origin| {
+            let (len, (row_start, col_start), (row_step, col_step)) = uzp_file.shape(origin.shape_id).axis_walk(0);
+            println!("{}\t{}\t{}\t{}\t{}", origin.row + row_start, origin.col + col_start, len, row_step, col_step);
+        });
     }
 
-    for _ in 0..num_origins {
-        let shape_id = file.read_i16::<LittleEndian>().unwrap();
-        let base_row = file.read_i32::<LittleEndian>().unwrap();
-        let base_col = file.read_i32::<LittleEndian>().unwrap();
-        // skip reading data_offset
-        file.seek(SeekFrom::Current(32/8)).unwrap();
-
-        // NO NEED TO JUMP TO DATA OFFSET, AS WE READ IT ALL TOGETHER AT THE END. WE JUST POPULATE ROW AND COL VECTORS
-        // Read data offset as the num of elements. We can calculate this, but this is easier
-        // data_offset = file.read_i32::<LittleEndian>().unwrap() - data_offset;
-        // let curr_pos = file.seek(SeekFrom::Current(0)).unwrap();
-        // file.seek(SeekFrom::Start(data_ptr as u64 + data_offset as u64)).unwrap();
-
-        // traverse row and col from l_row and l_col, and push index into rowvec and colvec
-        let (l_dim_of_ip, l_len_along_axis, l_c) = shapes_map.get(&shape_id).unwrap();
-
-        recursive_populate_row_col_vec(*l_dim_of_ip, l_len_along_axis, l_c, &mut rowvec, &mut colvec, base_row, base_col);
-
-        if print_ast_list {
-            println!("{}\t{}\t{}\t{}\t{}", base_row, base_col, l_len_along_axis[0]+1, l_c[0], l_c[1]);
-        }
-
-        // Return to previous position
-        // file.seek(SeekFrom::Start(curr_pos)).unwrap();
-    }
-
-    // Read uninc_format
-    let uninc_format = file.read_u8().unwrap();
-    match uninc_format {
-        0 => {  //eprintln!("Reading CSR");
-                let mut last_row_cnt = file.read_i32::<LittleEndian>().unwrap();
-
-                // Read rowptr
-                for curr_row in 0..nrows {
-                    let row_cnt = file.read_i32::<LittleEndian>().unwrap();
-                    // Insert row_cnt - last_row_cnt, curr_row values into rowvec
-                    rowvec.extend(vec![curr_row as usize; (row_cnt - last_row_cnt) as usize]);
-                    last_row_cnt = row_cnt;
-                }
-                // Read colidx
-                for _ in 0..nnz-inc_nnz {
-                    colvec.push(file.read_i32::<LittleEndian>().unwrap() as usize);
-                }
-             },
-        2 => {  // eprintln!("Reading COO");
-                // Read rowptr
-                for _ in 0..nnz-inc_nnz {
-                    rowvec.push(file.read_i32::<LittleEndian>().unwrap() as usize);
-                }
-                // Read colptr
-                for _ in 0..nnz-inc_nnz {
-                    colvec.push(file.read_i32::<LittleEndian>().unwrap() as usize);
-                }
-             },
-        _ => { panic!("{} Read uninc_format variable value was {} and is unsupported at the moment. Report this issue.", "[ERROR]".bold().red(), uninc_format) }
-    }
-
-    // seek to data_ptr
-    file.seek(SeekFrom::Start(data_ptr as u64)).unwrap();
-
-    // Read data
-    for _ in 0..nnz {
-        datavec.push(file.read_f64::<LittleEndian>().unwrap());
-    }
-
-    // println!("DEBUG -- rowvec = {:?}", rowvec);
-    // println!("DEBUG -- colvec = {:?}", colvec);
-    // println!("DEBUG -- datavec = {:?}", datavec);
-    // println!("DEBUG -- lengths (row,col,data) = {:?}, nnz = {:?}", (rowvec.len(), colvec.len(), datavec.len()), nnz);
-
-    let coo_mat = TriMat::from_triplets((nrows as usize,ncols as usize), rowvec, colvec, datavec);
+    // Only one triangle is stored for symmetric matrices
+    let coo_mat = uzp_file.to_trimat(true);
 
     let csx_matrix: CsMat<f64>;
     if csr {
@@ -560,167 +750,16 @@ pub fn convert_uzp (input_uzp_file_path: &str, output_mtx_file_path: &str, csr:
     }
 
     // Write matrix to file
-    sprs::io::write_matrix_market(output_mtx_file_path, &csx_matrix).unwrap();
+    write_matrix_market_with_symmetry(output_mtx_file_path, &csx_matrix, if keep_symmetry { uzp_file.symmetry } else { MmSymmetry::General });
 }
 
-pub fn convert_uzp_for_timing (input_uzp_file_path: &str, output_mtx_file_path: &str, csr: bool) {
-    let mut file = File::open(input_uzp_file_path).expect(format!("Unable to open uzp file {}", input_uzp_file_path).as_str());
-
-    // Read header
-    let nnz = file.read_i32::<LittleEndian>().unwrap();
-    let inc_nnz = file.read_i32::<LittleEndian>().unwrap();
-    let nrows = file.read_i32::<LittleEndian>().unwrap();
-    let ncols = file.read_i32::<LittleEndian>().unwrap();
-
-    let dims = file.read_i16::<LittleEndian>().unwrap();
-    if dims != 2 {
-        panic!("Only 2D matrices are supported at the moment");
-    }
-
-    let num_shapes = file.read_i32::<LittleEndian>().unwrap();
-    // skip num_hier_shapes
-    file.seek(SeekFrom::Current(32/8)).unwrap();
-    // let num_hier_shapes = file.read_i32::<LittleEndian>().unwrap();
-
-    // Pointer to start of data
-    let data_ptr = file.read_i32::<LittleEndian>().unwrap();
-
-    let max_dims = file.read_i16::<LittleEndian>().unwrap();
-    // Skip max_dims data
-    file.seek(SeekFrom::Current((32/8)*(max_dims as i64))).unwrap();
-
-    // Create sprs triplet matrix for insertion
-    // let mut triplet_matrix: TriMat<f64> = TriMat::new((nrows as usize, ncols as usize));
-    // initialize three vecs with capacity nnz
-    let mut rowvec: Vec<usize> = Vec::with_capacity(nnz as usize);
-    let mut colvec: Vec<usize> = Vec::with_capacity(nnz as usize);
-    let mut datavec: Vec<f64> = Vec::with_capacity(nnz as usize);
-
-    //                       shape_id, (dim_of_ip, lengths_along_axis, c)
-    let mut shapes_map: HashMap<i16, (i16, Vec<i32>, Vec<i32>)> = HashMap::with_capacity(num_shapes as usize);
-
-    for _ in 0..(num_shapes as usize) {
-        // Read shapes
-        // shapes_vec[shape_id].0 =
-
-        let l_shape_id = file.read_i16::<LittleEndian>().unwrap();
-
-        let type_of_encoding = file.read_i16::<LittleEndian>().unwrap();
-        if type_of_encoding != 0 {
-            panic!("Only vertex_rec encoding is supported at the moment");
-        }
-
-        // shapes_vec[shape_id].1
-
-        let l_dim_of_ip = file.read_i16::<LittleEndian>().unwrap();
-
-        // for i in 0..dim_of_ip {
-        //     // let min_point = file.read_i32::<LittleEndian>().unwrap();
-        //     // let len_along_axis = file.read_i32::<LittleEndian>().unwrap();
-        //     // let stride = file.read_i32::<LittleEndian>().unwrap();
-        // }
-        // Skip min_point
-        file.seek(SeekFrom::Current((32/8)*(l_dim_of_ip as i64))).unwrap();
-
-        // Read len_along_axis
-        let mut l_len_along_axis: Vec<i32> = Vec::with_capacity(l_dim_of_ip as usize);
-        for _ in 0..l_dim_of_ip {
-            l_len_along_axis.push(file.read_i32::<LittleEndian>().unwrap());
-        }
-
-        // Skip stride
-        file.seek(SeekFrom::Current((32/8)*(l_dim_of_ip as i64))).unwrap();
-
-        // read 2*dim_of_ip c values into shapes_vec[shape_id].2
-        let mut l_c: Vec<i32> = Vec::with_capacity(2*l_dim_of_ip as usize);
-        for _ in 0..2*l_dim_of_ip {
-            l_c.push(file.read_i32::<LittleEndian>().unwrap());
-        }
-
-        shapes_map.insert(l_shape_id,(l_dim_of_ip, l_len_along_axis, l_c));
-    }
-
-    // Read total number of origins
-    let num_origins = file.read_i32::<LittleEndian>().unwrap();
-    // let mut data_offset: i32 = 0;
-
-    //                          shape_id, base_row, base_col
-    let mut origin_shapes: Vec<(i16,i32,i32)> = Vec::with_capacity(num_origins as usize);
-    for _ in 0..num_origins {
-        let shape_id = file.read_i16::<LittleEndian>().unwrap();
-        let base_row = file.read_i32::<LittleEndian>().unwrap();
-        let base_col = file.read_i32::<LittleEndian>().unwrap();
-        // skip reading data_offset
-        file.seek(SeekFrom::Current(32/8)).unwrap();
-
-        // NO NEED TO JUMP TO DATA OFFSET, AS WE READ IT ALL TOGETHER AT THE END. WE JUST POPULATE ROW AND COL VECTORS
-        // Read data offset as the num of elements. We can calculate this, but this is easier
-        // data_offset = file.read_i32::<LittleEndian>().unwrap() - data_offset;
-        // let curr_pos = file.seek(SeekFrom::Current(0)).unwrap();
-        // file.seek(SeekFrom::Start(data_ptr as u64 + data_offset as u64)).unwrap();
-
-        origin_shapes.push((shape_id, base_row, base_col));
-
-        // Return to previous position
-        // file.seek(SeekFrom::Start(curr_pos)).unwrap();
-    }
-
-    // Load ninc coords in memory
-    let mut ninc_rowvec: Vec<usize> = Vec::with_capacity((nnz - inc_nnz) as usize);
-    let mut ninc_colvec: Vec<usize> = Vec::with_capacity((nnz - inc_nnz) as usize);
-
-    // Read uninc_format
-    let uninc_format = file.read_u8().unwrap();
-    match uninc_format {
-        0 => {  //eprintln!("Reading CSR");
-                let mut last_row_cnt = file.read_i32::<LittleEndian>().unwrap();
-
-                // Read rowptr
-                for curr_row in 0..nrows {
-                    let row_cnt = file.read_i32::<LittleEndian>().unwrap();
-                    // Insert row_cnt - last_row_cnt, curr_row values into rowvec
-                    ninc_rowvec.extend(vec![curr_row as usize; (row_cnt - last_row_cnt) as usize]);
-                    last_row_cnt = row_cnt;
-                }
-                // Read colidx
-                for _ in 0..nnz-inc_nnz {
-                    ninc_colvec.push(file.read_i32::<LittleEndian>().unwrap() as usize);
-                }
-             },
-        2 => {  // eprintln!("Reading COO");
-                // Read rowptr
-                for _ in 0..nnz-inc_nnz {
-                    ninc_rowvec.push(file.read_i32::<LittleEndian>().unwrap() as usize);
-                }
-                // Read colptr
-                for _ in 0..nnz-inc_nnz {
-                    ninc_colvec.push(file.read_i32::<LittleEndian>().unwrap() as usize);
-                }
-             },
-        _ => { panic!("{} Read uninc_format variable value was {} and is unsupported at the moment. Report this issue.", "[ERROR]".bold().red(), uninc_format) }
-    }
-
-    // seek to data_ptr
-    file.seek(SeekFrom::Start(data_ptr as u64)).unwrap();
-
-    // Read data
-    for _ in 0..nnz {
-        datavec.push(file.read_f64::<LittleEndian>().unwrap());
-    }
+pub fn convert_uzp_for_timing (input_uzp_file_path: &str, output_mtx_file_path: &str, csr: bool, keep_symmetry: bool) {
+    let uzp_file = read_uzp_file(input_uzp_file_path);
 
     let now = Instant::now();
     /*********************************** NOW PROCESS THE DATA IN MEMORY INTO A CSx MATRIX ***********************************/
-    origin_shapes.iter().for_each(|(shape_id, base_row, base_col)| {
-        // traverse row and col from l_row and l_col, and push index into rowvec and colvec
-        let (l_dim_of_ip, l_len_along_axis, l_c) = shapes_map.get(shape_id).unwrap();
-
-        recursive_populate_row_col_vec(*l_dim_of_ip, l_len_along_axis, l_c, &mut rowvec, &mut colvec, *base_row, *base_col);
-    });
-    // Add ninc coords to the end
-    rowvec.extend(ninc_rowvec);
-    colvec.extend(ninc_colvec);
-
-    let coo_mat = TriMat::from_triplets((nrows as usize,ncols as usize), rowvec, colvec, datavec);
+    // Only one triangle is stored for symmetric matrices
+    let coo_mat = uzp_file.to_trimat(true);
 
     let csx_matrix: CsMat<f64>;
     if csr {
@@ -734,28 +773,31 @@ pub fn convert_uzp_for_timing (input_uzp_file_path: &str, output_mtx_file_path:
     std::io::stdout().flush().unwrap();
 
     // Write matrix to file
-    sprs::io::write_matrix_market(output_mtx_file_path, &csx_matrix).unwrap();
+    write_matrix_market_with_symmetry(output_mtx_file_path, &csx_matrix, if keep_symmetry { uzp_file.symmetry } else { MmSymmetry::General });
 }
 
-#[inline(always)]
-#[allow(dead_code)]
-fn recursive_populate_row_col_vec(l_dim_of_ip: i16, l_len_along_axis: &[i32], l_c: &[i32], rowvec: &mut Vec<usize>, colvec: &mut Vec<usize>, base_row: i32, base_col: i32) {
-    if l_dim_of_ip < 2 /* l_dim_of_ip == 1 basically */ {
-        for ii in 0..=l_len_along_axis[0] {
-            rowvec.push((base_row + (l_c[0] * ii)) as usize);
-            colvec.push((base_col + (l_c[1] * ii)) as usize);
-        }
-    } else {
-        for ii in 0..=l_len_along_axis[0] {
-            recursive_populate_row_col_vec(
-                l_dim_of_ip-1, &l_len_along_axis[1..], &l_c[2..], rowvec, colvec,
-                base_row + (l_c[0] * ii),
-                base_col + (l_c[1] * ii)
-            )
+/// Parses a UZP file, exiting with an error message if it cannot be read.
+pub fn read_uzp_file(input_uzp_file_path: &str) -> UzpFile {
+    match UzpFile::from_file(input_uzp_file_path) {
+        Ok(uzp_file) => uzp_file,
+        Err(e) => {
+            eprintln!("{} Unable to read UZP file {}. ERROR: {}", "[ERROR]".red().bold(), input_uzp_file_path.bright_blue(), e.to_string().bold().red());
+            std::process::exit(1);
         }
     }
 }
 
+#[inline(always)]
+fn write_matrix_market_with_symmetry(output_mtx_file_path: &str, csx_matrix: &CsMat<f64>, symmetry: MmSymmetry) {
+    match symmetry {
+        MmSymmetry::General => sprs::io::write_matrix_market(output_mtx_file_path, csx_matrix).unwrap(),
+        MmSymmetry::Symmetric => sprs::io::write_matrix_market_sym(output_mtx_file_path, csx_matrix, sprs::io::SymmetryMode::Symmetric).unwrap(),
+        MmSymmetry::SkewSymmetric => sprs::io::write_matrix_market_sym(output_mtx_file_path, csx_matrix, sprs::io::SymmetryMode::SkewSymmetric).unwrap(),
+        // Values are real, so hermitian matrices are just symmetric ones
+        MmSymmetry::Hermitian => sprs::io::write_matrix_market_sym(output_mtx_file_path, csx_matrix, sprs::io::SymmetryMode::Symmetric).unwrap(),
+    }
+}
+
 #[inline(always)]
 #[allow(dead_code)]
 fn recursive_traverse(metapattern_piece: &MetaPatternPiece, metapattern_id: i32, meta_patterns: &LinkedHashMap<i32, MetaPattern>, f64_value_matrix: &CsMat<f64>) -> Vec<f64> {
@@ -791,7 +833,7 @@ fn recursive_traverse(metapattern_piece: &MetaPatternPiece, metapattern_id: i32,
 fn format_eqs(u: &Vec<Vec<i32>>, w: &Vec<i32>) -> String {
     let mut str_list: Vec<String> = vec![];
 
-    let idx_values = vec!["i", "j", "k", "l"];
+    let idx_values = index_variable_names(u.first().map_or(0, |row| row.len()));
 
     for i in 0..u.len() {
         str_list.push("   ===   ".to_string());
@@ -806,7 +848,7 @@ fn format_eqs(u: &Vec<Vec<i32>>, w: &Vec<i32>) -> String {
             }, variable={
                 match u[i][j] {
                     0 => "",
-                    _ => idx_values[j]
+                    _ => idx_values[j].as_str()
                 }
             }, weight={
                 match w[i] {
@@ -820,3 +862,177 @@ fn format_eqs(u: &Vec<Vec<i32>>, w: &Vec<i32>) -> String {
 
     str_list.join("")
 }
+
+/// 2D metapattern whose lattice is sheared but unimodular, written by vertex_gen and ineqs as the parallelogram its
+/// (row, col) offsets fill, with an identity lattice
+struct Parallelogram {
+    /// Facets, as U·p + w >= 0, and identity lattice
+    uwc: Uwc,
+    corners: Vec<Vec<i32>>,
+    /// (row, col) offsets of the points in lexicographic order, which is the data order of the reader
+    offsets: Vec<(i32, i32)>,
+}
+
+/// Parallelogram of a 2D metapattern repeating (n0,i0,j0) a sub-pattern (n1,i1,j1), if its sides span every integer point
+/// (|det| = 1) and are not both axis-aligned, in which case the box of its lattice already is the same shape. Readers walk
+/// its rows, so it must not span more rows than it has points
+fn metapattern_to_parallelogram(metapattern_id: i32, meta_patterns: &LinkedHashMap<i32, MetaPattern>) -> Option<Parallelogram> {
+    let ((n0, i0, j0), order, subpat) = meta_patterns.get(&metapattern_id).unwrap();
+    if *order != 2 {
+        return None;
+    }
+    let ((n1, i1, j1), _, _) = meta_patterns.get(&subpat.unwrap()).unwrap();
+    let det = i0 * j1 - j0 * i1;
+    if *n0 < 2 || *n1 < 2 || det.abs() != 1 || (*j0 == 0 && *i1 == 0) || (*i0 == 0 && *j1 == 0) {
+        return None;
+    }
+    if (n0 - 1) as i64 * i0.abs() as i64 + (n1 - 1) as i64 * i1.abs() as i64 >= *n0 as i64 * *n1 as i64 {
+        return None;
+    }
+
+    // p = k0·(i0,j0) + k1·(i1,j1) inverts to k0 = det·(j1·r - i1·c) and k1 = det·(i0·c - j0·r), as 1/det = det
+    let u = vec![vec![det * j1, -det * i1], vec![-det * j1, det * i1], vec![-det * j0, det * i0], vec![det * j0, -det * i0]];
+    let w = vec![0, n0 - 1, 0, n1 - 1];
+    let corners = c![vec![k0 * i0 + k1 * i1, k0 * j0 + k1 * j1], for k0 in [0, n0 - 1], for k1 in [0, n1 - 1]];
+    let offsets = (0..*n0).cartesian_product(0..*n1).map(|(k0, k1)| (k0 * i0 + k1 * i1, k0 * j0 + k1 * j1)).sorted().collect();
+
+    Some(Parallelogram { uwc: (u, w, vec![1, 0, 0, 1]), corners, offsets })
+}
+
+/// Largest stride evenly dividing the (i, j) lattice of an axis. At least 1, so axes with a null lattice keep their stride
+#[inline(always)]
+fn lattice_gcd(i: i32, j: i32) -> i32 {
+    let (mut a, mut b) = (i.abs(), j.abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.max(1)
+}
+
+/// Names of the index variables of a shape: i, j, k, l up to four dimensions, i0, i1... beyond that
+fn index_variable_names(dims: usize) -> Vec<String> {
+    if dims <= 4 {
+        ["i", "j", "k", "l"][..dims].iter().map(|name| name.to_string()).collect()
+    } else {
+        (0..dims).map(|dim| format!("i{}", dim)).collect()
+    }
+}
+
+/// ISL affine expression `base + coef_0*var_0 + coef_1*var_1...`, skipping zero terms. An empty base starts with the first term
+fn isl_affine_expr(base: &str, terms: impl Iterator<Item = (i32, String)>) -> String {
+    let mut expr = String::from(base);
+    terms.filter(|(coef, _)| *coef != 0).for_each(|(coef, var)| {
+        let sign = if coef < 0 { "-" } else { "+" };
+        let term = if coef.abs() == 1 { var } else { format!("{}*{}", coef.abs(), var) };
+        if expr.is_empty() {
+            expr = if coef < 0 { format!("-{}", term) } else { term };
+        } else {
+            expr.push_str(&format!(" {} {}", sign, term));
+        }
+    });
+    if expr.is_empty() { "0".to_string() } else { expr }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempFile;
+    use crate::uzpfile::UzpShape;
+    use crate::uzpverify::verify_uzp;
+
+    // 8x8 matrix: two sheared 2D pieces, one of them reaching columns left of its start, a 2x2 box and a single nonzero
+    const META_PATTERNS: [(i32, MetaPattern); 7] = [
+        (0, ((4,0,1), 1, None)), (1, ((3,1,1), 2, Some(0))),
+        (2, ((2,1,-1), 1, None)), (3, ((3,1,0), 2, Some(2))),
+        (4, ((2,0,1), 1, None)), (5, ((2,1,0), 2, Some(4))),
+        (-1, ((1,0,0), 1, None)),
+    ];
+    const PIECES: [(MetaPatternPiece, i32); 4] = [((0, 0), 1), ((3, 6), 3), ((6, 0), 5), ((7, 7), -1)];
+
+    fn uzpgen() -> UZPGen {
+        UZPGen { nrows: 8, ncols: 8, nnz: 23, inc_nnz: 22, meta_patterns: META_PATTERNS.into_iter().collect(), meta_pattern_pieces: PIECES.into_iter().collect() }
+    }
+
+    /// Positions covered by the piece of `id` starting at `start`, sorted
+    fn piece_points(uzpgen: &UZPGen, (row, col): MetaPatternPiece, id: i32) -> Vec<(usize, usize)> {
+        let (u, w, c) = metapattern_to_hyperrectangle_uwc(id, &uzpgen.meta_patterns);
+        convex_hull_hyperrectangle_nd(&u, &w, true)
+            .iter()
+            .map(|point| point.iter().enumerate().fold((row, col), |(i, j), (axis, k)| {
+                ((i as i32 + k * c[2*axis]) as usize, (j as i32 + k * c[2*axis+1]) as usize)
+            }))
+            .sorted()
+            .collect()
+    }
+
+    fn value_matrix() -> CsMat<f64> {
+        let uzpgen = uzpgen();
+        let mut triplets: TriMat<f64> = TriMat::new((8, 8));
+        uzpgen.meta_pattern_pieces.iter().flat_map(|(start, id)| piece_points(&uzpgen, *start, *id)).for_each(|(row, col)| {
+            triplets.add_triplet(row, col, 0.5 + triplets.nnz() as f64);
+        });
+        triplets.to_csr()
+    }
+
+    #[test]
+    fn parallelogram_round_trip() {
+        let matrix = value_matrix();
+        assert_eq!(matrix.nnz(), 23);
+
+        for shape_encoding in [UzpShapeEncoding::VertexGen, UzpShapeEncoding::Ineqs] {
+            for transpose_output in [false, true] {
+                let name = format!("{}_{}", shape_encoding.name(), transpose_output);
+                let options = UzpWriteOptions { shape_encoding, transpose_output, ..Default::default() };
+                let uzp_path = TempFile::new(&format!("uzpgen_{}.uzp", name), &[]);
+                uzpgen().write_uzp_with_values(&matrix, MmSymmetry::General, &uzp_path.path, &options);
+
+                let uzp_file = UzpFile::from_file(&uzp_path.path).unwrap();
+                let expected = if transpose_output { matrix.transpose_view().to_csr() } else { matrix.clone() };
+                assert_eq!(uzp_file.shapes().filter(|shape| shape.box_walks().is_none()).count(), 2, "{}", name);
+                assert!(verify_uzp(&uzp_file, &expected, 0.0).is_ok(), "{}", name);
+
+                let mtx_path = TempFile::new(&format!("uzpgen_{}.mtx", name), &[]);
+                convert_uzp(&uzp_path.path, &mtx_path.path, true, false, false);
+                assert_eq!(crate::utils::read_matrix_csr::<f64>(&mtx_path.path, false, InputFormat::MatrixMarket, false).0, expected, "{}", name);
+
+                // Pieces are read back starting at the same nonzeros and covering the same ones. The sides of
+                // vertex_gen parallelograms may come back in the other order, which describes the same shape
+                let read_back = UZPGen::from_uzp_file(&uzp_file).unwrap();
+                let transpose = |(row, col): MetaPatternPiece| if transpose_output { (col, row) } else { (row, col) };
+                let pieces: Vec<(MetaPatternPiece, Vec<(usize, usize)>)> = read_back.meta_pattern_pieces
+                    .iter()
+                    .map(|(start, id)| (*start, piece_points(&read_back, *start, *id)))
+                    .sorted()
+                    .collect();
+                let expected_pieces: Vec<(MetaPatternPiece, Vec<(usize, usize)>)> = PIECES
+                    .iter()
+                    .map(|(start, id)| (transpose(*start), piece_points(&uzpgen(), *start, *id).into_iter().map(transpose).sorted().collect()))
+                    .sorted()
+                    .collect();
+                assert_eq!(pieces, expected_pieces, "{}", name);
+                assert!(read_back.meta_patterns.values().any(|(_, order, _)| *order == 2), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn triangles_are_not_metapatterns() {
+        // 0 <= col <= row <= 3
+        let triangle = UzpShape {
+            id: 0,
+            encoding: UzpShapeEncoding::Ineqs,
+            dim: 2,
+            min_point: vec![0, 0],
+            max_point: vec![3, 3],
+            strides: vec![1, 1],
+            vertices: vec![],
+            u: vec![vec![0, 1], vec![1, -1], vec![-1, 0]],
+            w: vec![0, 0, 3],
+            lattice: vec![1, 0, 0, 1],
+            sub_shape: None,
+        };
+        assert_eq!(triangle.points().len(), 10);
+        assert!(triangle.box_walks().is_none());
+        assert!(triangle.parallelogram_walks().is_none());
+    }
+}
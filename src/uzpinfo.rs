@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::uzpfile::{UzpFile, UzpHeader, UzpSection, UzpShapeEncoding, UzpUninc};
+use crate::mmio::MmSymmetry;
+
+/* UZP INFO
+ *
+ * What a UZP file holds, without decoding its nonzeros:
+ *
+ *   header      Version, layout, symmetry, matrix size and counts, and the section table of version 2 files
+ *   shapes      Number of shapes of every dimensionality, and origins and points of every shape
+ *   coverage    Share of the stored nonzeros included in shapes (inc_nnz/nnz), and how the rest are stored
+ *   storage     Size of the file against CSR and COO encodings of the same stored nonzeros, with 32-bit indices
+ *               when the matrix fits them (64-bit otherwise) and f64 values. Index bytes leave the values out
+ */
+
+#[derive(Clone, Debug)]
+pub struct UzpShapeInfo {
+    pub id: i32,
+    pub dim: usize,
+    pub encoding: UzpShapeEncoding,
+    pub hierarchical: bool,
+    pub points: usize,
+    pub origins: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UzpDimInfo {
+    pub shapes: usize,
+    pub hierarchical: usize,
+    pub origins: usize,
+    /// Values taken by the origins of these shapes
+    pub points: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct StorageSize {
+    pub bytes: u64,
+    pub index_bytes: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct UzpInfo {
+    pub header: UzpHeader,
+    pub symmetry: MmSymmetry,
+    pub num_origins: usize,
+    pub uninc_format: &'static str,
+    pub uninc_nnz: usize,
+    pub shapes: Vec<UzpShapeInfo>,
+    pub dims: BTreeMap<usize, UzpDimInfo>,
+    pub uzp_size: StorageSize,
+    pub csr_size: StorageSize,
+    pub coo_size: StorageSize,
+}
+
+impl UzpInfo {
+    pub fn coverage(&self) -> f64 {
+        if self.header.nnz == 0 { 0.0 } else { self.header.inc_nnz as f64 / self.header.nnz as f64 }
+    }
+}
+
+/// Gathers the statistics of a UZP file of `file_bytes` bytes
+pub fn uzp_info(uzp_file: &UzpFile, file_bytes: u64) -> UzpInfo {
+    let header = uzp_file.header;
+
+    let mut shape_origins: HashMap<i32, usize> = HashMap::new();
+    uzp_file.origins().for_each(|origin| *shape_origins.entry(origin.shape_id).or_insert(0) += 1);
+
+    let shapes: Vec<UzpShapeInfo> = uzp_file
+        .shapes()
+        .map(|shape| UzpShapeInfo {
+            id: shape.id,
+            dim: shape.dim,
+            encoding: shape.encoding,
+            hierarchical: shape.sub_shape.is_some(),
+            points: uzp_file.shape_points(shape.id).len(),
+            origins: shape_origins.get(&shape.id).copied().unwrap_or(0),
+        })
+        .collect();
+
+    let mut dims: BTreeMap<usize, UzpDimInfo> = BTreeMap::new();
+    shapes.iter().for_each(|shape| {
+        let dim_info = dims.entry(shape.dim).or_default();
+        dim_info.shapes += 1;
+        dim_info.hierarchical += shape.hierarchical as usize;
+        dim_info.origins += shape.origins;
+        dim_info.points += shape.origins * shape.points;
+    });
+
+    let uninc_format = match uzp_file.uninc {
+        UzpUninc::Csr { .. } => "csr",
+        UzpUninc::Coo { .. } => "coo",
+    };
+
+    // Same index width as narrow UZP files, unless the matrix does not fit it
+    let nnz = header.nnz as u64;
+    let index_width: u64 = if header.nnz.max(header.nrows + 1).max(header.ncols) <= i32::MAX as usize { 4 } else { 8 };
+    let csr_index_bytes = index_width * (header.nrows as u64 + 1 + nnz);
+    let coo_index_bytes = index_width * 2 * nnz;
+
+    UzpInfo {
+        header,
+        symmetry: uzp_file.symmetry,
+        num_origins: uzp_file.origins().count(),
+        uninc_format,
+        uninc_nnz: uzp_file.uninc.len(),
+        shapes,
+        dims,
+        uzp_size: StorageSize { bytes: file_bytes, index_bytes: file_bytes.saturating_sub(8 * nnz) },
+        csr_size: StorageSize { bytes: csr_index_bytes + 8 * nnz, index_bytes: csr_index_bytes },
+        coo_size: StorageSize { bytes: coo_index_bytes + 8 * nnz, index_bytes: coo_index_bytes },
+    }
+}
+
+/// Prints the statistics as tab separated tables, one per kind
+pub fn print_info(info: &UzpInfo) {
+    let header = &info.header;
+
+    println!("Field\tValue");
+    println!("Version\t{}", if header.layout.is_legacy() { "legacy".to_string() } else { header.layout.version.to_string() });
+    println!("Layout\t{}", if header.layout.wide { "wide" } else { "narrow" });
+    println!("Symmetry\t{}", info.symmetry.name());
+    println!("Rows\t{}", header.nrows);
+    println!("Cols\t{}", header.ncols);
+    println!("Nnz\t{}", header.nnz);
+    println!("Inc_nnz\t{}", header.inc_nnz);
+    println!("Coverage\t{:.2}%", 100.0 * info.coverage());
+    println!("Shapes\t{}", header.num_shapes);
+    println!("Hierarchical shapes\t{}", header.num_hier_shapes);
+    println!("Origins\t{}", info.num_origins);
+    println!("Uninc format\t{}", info.uninc_format);
+    println!("Uninc nnz\t{}", info.uninc_nnz);
+    println!("Data pointer\t{}", header.data_ptr);
+
+    if let Some(sections) = header.sections {
+        println!();
+        println!("Section\tOffset\tLength\tCRC32");
+        UzpSection::ALL.iter().zip(sections.iter()).for_each(|(section, entry)| {
+            println!("{}\t{}\t{}\t{:08x}", section, entry.offset, entry.length, entry.crc32);
+        });
+    }
+
+    println!();
+    println!("Dim\tShapes\tHierarchical\tOrigins\tPoints");
+    info.dims.iter().for_each(|(dim, dim_info)| {
+        println!("{}\t{}\t{}\t{}\t{}", dim, dim_info.shapes, dim_info.hierarchical, dim_info.origins, dim_info.points);
+    });
+
+    println!();
+    println!("Shape\tDim\tEncoding\tHierarchical\tPoints\tOrigins");
+    info.shapes.iter().for_each(|shape| {
+        println!("{}\t{}\t{}\t{}\t{}\t{}", shape.id, shape.dim, shape.encoding.name(), shape.hierarchical, shape.points, shape.origins);
+    });
+
+    println!();
+    println!("Format\tBytes\tIndex bytes\tUZP/Format");
+    [("UZP", info.uzp_size), ("CSR", info.csr_size), ("COO", info.coo_size)].iter().for_each(|(name, size)| {
+        println!("{}\t{}\t{}\t{:.3}", name, size.bytes, size.index_bytes, info.uzp_size.bytes as f64 / size.bytes.max(1) as f64);
+    });
+}
+
+/// Prints the statistics as a JSON object
+pub fn print_info_json(info: &UzpInfo, input_uzp_file_path: &str) {
+    let header = &info.header;
+
+    let sections = match header.sections {
+        None => "null".to_string(),
+        Some(sections) => format!(
+            "[{}]",
+            UzpSection::ALL
+                .iter()
+                .zip(sections.iter())
+                .map(|(section, entry)| format!("{{\"name\": \"{}\", \"offset\": {}, \"length\": {}, \"crc32\": \"{:08x}\"}}", section, entry.offset, entry.length, entry.crc32))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    };
+    let dims = info
+        .dims
+        .iter()
+        .map(|(dim, dim_info)| format!(
+            "{{\"dim\": {}, \"shapes\": {}, \"hierarchical\": {}, \"origins\": {}, \"points\": {}}}",
+            dim, dim_info.shapes, dim_info.hierarchical, dim_info.origins, dim_info.points
+        ))
+        .collect::<Vec<String>>();
+    let shapes = info
+        .shapes
+        .iter()
+        .map(|shape| format!(
+            "{{\"id\": {}, \"dim\": {}, \"encoding\": \"{}\", \"hierarchical\": {}, \"points\": {}, \"origins\": {}}}",
+            shape.id, shape.dim, shape.encoding.name(), shape.hierarchical, shape.points, shape.origins
+        ))
+        .collect::<Vec<String>>();
+    let size = |size: &StorageSize| format!("{{\"bytes\": {}, \"index_bytes\": {}}}", size.bytes, size.index_bytes);
+
+    println!("{{");
+    println!("  \"file\": \"{}\",", json_escape(input_uzp_file_path));
+    println!("  \"version\": {},", header.layout.version);
+    println!("  \"wide\": {},", header.layout.wide);
+    println!("  \"symmetry\": \"{}\",", info.symmetry.name());
+    println!("  \"nrows\": {},", header.nrows);
+    println!("  \"ncols\": {},", header.ncols);
+    println!("  \"nnz\": {},", header.nnz);
+    println!("  \"inc_nnz\": {},", header.inc_nnz);
+    println!("  \"coverage\": {},", info.coverage());
+    println!("  \"num_shapes\": {},", header.num_shapes);
+    println!("  \"num_hier_shapes\": {},", header.num_hier_shapes);
+    println!("  \"num_origins\": {},", info.num_origins);
+    println!("  \"uninc\": {{\"format\": \"{}\", \"nnz\": {}}},", info.uninc_format, info.uninc_nnz);
+    println!("  \"data_ptr\": {},", header.data_ptr);
+    println!("  \"sections\": {},", sections);
+    println!("  \"dims\": [{}],", dims.join(", "));
+    println!("  \"shapes\": [{}],", shapes.join(", "));
+    println!("  \"storage\": {{\"uzp\": {}, \"csr\": {}, \"coo\": {}}}", size(&info.uzp_size), size(&info.csr_size), size(&info.coo_size));
+    println!("}}");
+}
+
+fn json_escape(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            c if (c as u32) < 0x20 => format!("\\u{:04x}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
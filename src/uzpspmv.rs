@@ -0,0 +1,107 @@
+use sprs::CsMat;
+
+use crate::mmio::MmSymmetry;
+use crate::uzpfile::{UzpFile, UzpShape, UzpShapeEncoding, UzpUninc};
+
+/* UZP SPMV
+ *
+ * y = A·x computed straight from the sections of a UZP file, without expanding it:
+ *
+ *   origins    Every origin walks its shape as a loop nest, first axis outermost, reading the data
+ *              section from the origin data offset onwards. Every axis is a strided loop from the min point.
+ *              Non rectangular shapes walk the list of their points instead
+ *   uninc      Walked as the CSR or COO structure it is stored in, reading the data section from inc_nnz
+ *
+ * Symmetric files only store one triangle, so every off-diagonal nonzero also adds its mirrored
+ * contribution (negated for skew-symmetric matrices).
+ */
+
+/// Computes y += A·x from the UZP file. `x` must have ncols elements and `y` nrows elements.
+pub fn uzp_spmv(uzp_file: &UzpFile, x: &[f64], y: &mut [f64]) {
+    assert!(x.len() == uzp_file.header.ncols && y.len() == uzp_file.header.nrows, "Vector sizes do not match the UZP matrix");
+
+    match uzp_file.symmetry {
+        MmSymmetry::General => uzp_spmv_stored::<false>(uzp_file, 1.0, x, y),
+        MmSymmetry::SkewSymmetric => uzp_spmv_stored::<true>(uzp_file, -1.0, x, y),
+        MmSymmetry::Symmetric | MmSymmetry::Hermitian => uzp_spmv_stored::<true>(uzp_file, 1.0, x, y),
+    }
+}
+
+/// Fixed input vector of the spmv subcommand and of generated C drivers, so results can be compared between runs
+pub fn input_vector(ncols: usize) -> Vec<f64> {
+    (0..ncols).map(|idx| 1.0 + (idx % 13) as f64 / 13.0).collect()
+}
+
+/// Computes y += A·x with the CSR matrix, to check and time `uzp_spmv` against.
+pub fn csr_spmv(csr_matrix: &CsMat<f64>, x: &[f64], y: &mut [f64]) {
+    sprs::prod::mul_acc_mat_vec_csr(csr_matrix.view(), x, y);
+}
+
+// Specialized on MIRROR so general matrices do not pay for the mirrored contributions
+fn uzp_spmv_stored<const MIRROR: bool>(uzp_file: &UzpFile, mirror_sign: f64, x: &[f64], y: &mut [f64]) {
+    uzp_file.origin_shapes().for_each(|(origin, shape)| {
+        let data = &uzp_file.data[origin.data_offset as usize..];
+        if shape.encoding == UzpShapeEncoding::VertexRec {
+            spmv_shape::<MIRROR>(shape, 0, (origin.row, origin.col), data, mirror_sign, x, y);
+        } else {
+            // Non rectangular shapes are not a loop nest, so their points are walked instead
+            uzp_file.shape_points(shape.id).iter().zip(data.iter()).for_each(|((row, col), val)| {
+                spmv_point::<MIRROR>((origin.row + row) as usize, (origin.col + col) as usize, *val, mirror_sign, x, y);
+            });
+        }
+    });
+
+    let uninc_data = &uzp_file.data[uzp_file.header.inc_nnz..];
+    match &uzp_file.uninc {
+        UzpUninc::Csr { indptr, indices } => {
+            indptr.windows(2).enumerate().for_each(|(row, ptrs)| {
+                let (start, end) = (ptrs[0] as usize, ptrs[1] as usize);
+                if !MIRROR {
+                    y[row] += indices[start..end].iter().zip(uninc_data[start..end].iter()).map(|(col, val)| val * x[*col as usize]).sum::<f64>();
+                } else {
+                    for k in start..end {
+                        spmv_point::<MIRROR>(row, indices[k] as usize, uninc_data[k], mirror_sign, x, y);
+                    }
+                }
+            });
+        },
+        UzpUninc::Coo { rows, cols } => {
+            for k in 0..rows.len() {
+                spmv_point::<MIRROR>(rows[k] as usize, cols[k] as usize, uninc_data[k], mirror_sign, x, y);
+            }
+        },
+    }
+}
+
+/// Walks the points of a shape from `axis` on, starting at `base` and consuming its values from `data`.
+/// Returns the number of values consumed.
+#[inline(always)]
+fn spmv_shape<const MIRROR: bool>(shape: &UzpShape, axis: usize, base: (i64, i64), data: &[f64], mirror_sign: f64, x: &[f64], y: &mut [f64]) -> usize {
+    let (len, (row_start, col_start), (row_step, col_step)) = shape.axis_walk(axis);
+    let (mut row, mut col) = (base.0 + row_start, base.1 + col_start);
+
+    if axis + 1 == shape.dim {
+        for val in &data[..len] {
+            spmv_point::<MIRROR>(row as usize, col as usize, *val, mirror_sign, x, y);
+            row += row_step;
+            col += col_step;
+        }
+        return len;
+    }
+
+    let mut consumed = 0;
+    for _ in 0..len {
+        consumed += spmv_shape::<MIRROR>(shape, axis + 1, (row, col), &data[consumed..], mirror_sign, x, y);
+        row += row_step;
+        col += col_step;
+    }
+    consumed
+}
+
+#[inline(always)]
+fn spmv_point<const MIRROR: bool>(row: usize, col: usize, val: f64, mirror_sign: f64, x: &[f64], y: &mut [f64]) {
+    y[row] += val * x[col];
+    if MIRROR && row != col {
+        y[col] += mirror_sign * val * x[row];
+    }
+}
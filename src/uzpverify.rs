@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use colored::Colorize;
+use sprs::CsMat;
+
+use crate::uzpfile::UzpFile;
+
+/* UZP VERIFICATION
+ *
+ * A UZP file is decoded (mirroring the stored triangle of symmetric files) and compared entry by entry
+ * against the matrix it was generated from. Discrepancies are sorted row-major:
+ *
+ *   missing     Nonzeros of the source matrix not present in the UZP file
+ *   extra       Nonzeros of the UZP file not present in the source matrix
+ *   duplicated  Positions decoded more than once (overlapping shapes, uninc nonzeros inside a shape...)
+ *   mismatched  Positions on both sides whose values differ by more than the tolerance
+ */
+
+//                       Row    Col    Value
+pub type Entry = (usize, usize, f64);
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// (nrows, ncols) of the UZP file and of the source matrix, when they differ
+    pub shape_mismatch: Option<((usize, usize), (usize, usize))>,
+    pub compared: usize,
+    pub missing: Vec<Entry>,
+    pub extra: Vec<Entry>,
+    /// Position and number of times it was decoded
+    pub duplicated: Vec<(usize, usize, usize)>,
+    //                          Row    Col    UZP  Source
+    pub mismatched: Vec<(usize, usize, f64, f64)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.shape_mismatch.is_none() && self.missing.is_empty() && self.extra.is_empty() && self.duplicated.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Compares the decoded UZP file against the source matrix. Values match when they differ by at most `tolerance`.
+pub fn verify_uzp(uzp_file: &UzpFile, source: &CsMat<f64>, tolerance: f64) -> VerifyReport {
+    let mut report = VerifyReport::default();
+
+    let uzp_shape = (uzp_file.header.nrows, uzp_file.header.ncols);
+    if uzp_shape != source.shape() {
+        report.shape_mismatch = Some((uzp_shape, source.shape()));
+    }
+
+    // First decoded value of every position and how many times it was decoded
+    let decoded = uzp_file.to_trimat(true);
+    let mut uzp_entries: HashMap<(usize, usize), (f64, usize)> = HashMap::with_capacity(decoded.nnz());
+    decoded.triplet_iter().for_each(|(val, (row, col))| {
+        uzp_entries.entry((row, col)).and_modify(|(_, count)| *count += 1).or_insert((*val, 1));
+    });
+
+    report.duplicated = uzp_entries.iter().filter(|(_, (_, count))| *count > 1).map(|(&(row, col), &(_, count))| (row, col, count)).collect();
+
+    // Every source nonzero, matched or not, is taken out so the remaining ones are extra
+    source.iter().for_each(|(val, (row, col))| {
+        report.compared += 1;
+        match uzp_entries.remove(&(row, col)) {
+            None => report.missing.push((row, col, *val)),
+            Some((uzp_val, _)) => {
+                if !values_match(uzp_val, *val, tolerance) {
+                    report.mismatched.push((row, col, uzp_val, *val));
+                }
+            },
+        }
+    });
+
+    report.extra = uzp_entries.into_iter().map(|((row, col), (val, _))| (row, col, val)).collect();
+
+    report.missing.sort_by_key(|&(row, col, _)| (row, col));
+    report.extra.sort_by_key(|&(row, col, _)| (row, col));
+    report.duplicated.sort_by_key(|&(row, col, _)| (row, col));
+    report.mismatched.sort_by_key(|&(row, col, _, _)| (row, col));
+
+    report
+}
+
+/// Prints the summary and up to `max_reported` entries of every kind of discrepancy. Coordinates are 1-based,
+/// as in MatrixMarket files.
+pub fn print_report(report: &VerifyReport, max_reported: usize) {
+    if let Some(((uzp_rows, uzp_cols), (src_rows, src_cols))) = report.shape_mismatch {
+        eprintln!("{} Matrix shape differs: UZP file is {}x{}, source matrix is {}x{}", "[ERROR]".red().bold(), uzp_rows, uzp_cols, src_rows, src_cols);
+    }
+
+    print_entries("missing", "Nonzeros of the source matrix not found in the UZP file", &report.missing, max_reported, |&(row, col, val)| format!("({}, {}) = {:e}", row+1, col+1, val));
+    print_entries("extra", "Nonzeros of the UZP file not found in the source matrix", &report.extra, max_reported, |&(row, col, val)| format!("({}, {}) = {:e}", row+1, col+1, val));
+    print_entries("duplicated", "Positions decoded more than once", &report.duplicated, max_reported, |&(row, col, count)| format!("({}, {}) decoded {} times", row+1, col+1, count));
+    print_entries("mismatched", "Values out of tolerance", &report.mismatched, max_reported, |&(row, col, uzp_val, src_val)| format!("({}, {}): UZP = {:e}, source = {:e}", row+1, col+1, uzp_val, src_val));
+
+    println!("Compared\tMissing\tExtra\tDuplicated\tMismatched");
+    println!("{}\t{}\t{}\t{}\t{}", report.compared, report.missing.len(), report.extra.len(), report.duplicated.len(), report.mismatched.len());
+}
+
+#[inline(always)]
+fn values_match(uzp_val: f64, src_val: f64, tolerance: f64) -> bool {
+    // NaN never compares equal, but the same bits were written
+    uzp_val == src_val || (uzp_val - src_val).abs() <= tolerance || uzp_val.to_bits() == src_val.to_bits()
+}
+
+fn print_entries<T>(kind: &str, description: &str, entries: &[T], max_reported: usize, format_entry: impl Fn(&T) -> String) {
+    if entries.is_empty() {
+        return;
+    }
+
+    eprintln!("{} {} {} entries. {}:", "[ERROR]".red().bold(), entries.len(), kind, description);
+    entries.iter().take(max_reported).for_each(|entry| eprintln!("    {}", format_entry(entry)));
+    if entries.len() > max_reported {
+        eprintln!("    ... and {} more", entries.len() - max_reported);
+    }
+}
@@ -31,7 +31,8 @@
 # the path to the patterns file, `matrix.mtx` is the path to the matrix file and
 # `ast_file.txt` is the path to the AST file to be generated.
 #
-# The script is then executed using the following syntax:
+# The script needs numpy, matplotlib, PyPDF2, charset-normalizer and tqdm, and is
+# then executed using the following syntax:
 #
 # python3 plot_ast_2d.py ast_file.txt [-o output.pdf]
 #